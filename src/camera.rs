@@ -119,6 +119,15 @@ impl Camera {
         self.orientation = cgmath::Rotation::look_at(*dir, *up);
     }
 
+    /// Turn to face `target`.
+    pub fn point_at(&mut self, target: Vec3) {
+        let dir = target - self.position();
+        if dir.magnitude2() > 0.0 {
+            let up = self.up;
+            self.look_at(&dir.normalize(), &up);
+        }
+    }
+
     pub fn rotate_to_mouse(&mut self,
                            (xnew, ynew): (f32, f32),
                            cursor_pos: (f32, f32),
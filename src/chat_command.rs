@@ -0,0 +1,419 @@
+use color;
+use chat_history::{ChannelId, ChatHistory, DEFAULT_CHANNEL};
+
+use std::collections::BTreeMap;
+
+/// What a command gets to work with when it runs.
+pub struct CommandContext<'a> {
+    pub commands: &'a CommandRegistry,
+    pub chat_history: &'a mut ChatHistory,
+
+    // The channel the chat input currently sends to, commands may switch it.
+    pub channel_id: &'a mut ChannelId,
+    pub player_name: &'a str,
+}
+
+/// A command receives everything typed after its name, with surrounding whitespace trimmed. The
+/// error string is shown to the user in the system channel.
+pub type CommandFn = fn(&mut CommandContext, &str) -> Result<(), String>;
+
+#[derive(Clone, Debug)]
+pub struct Command {
+    pub name: String,
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub run: CommandFn,
+}
+
+#[derive(Debug)]
+pub struct CommandRegistry {
+    commands: BTreeMap<String, Command>,
+
+    // alias -> name of the command it stands for.
+    aliases: BTreeMap<String, String>,
+}
+
+/// Split a line of chat input into a command name and its arguments. Returns None when the line
+/// isn't a command.
+pub fn parse_command(input: &str) -> Option<(&str, &str)> {
+    let input = input.trim();
+    if !input.starts_with('/') || input.len() == 1 {
+        return None;
+    }
+    let input = &input[1..];
+    match input.find(char::is_whitespace) {
+        Some(pos) => Some((&input[..pos], input[pos..].trim())),
+        None => Some((input, "")),
+    }
+}
+
+fn split_first_arg(args: &str) -> (&str, &str) {
+    match args.find(char::is_whitespace) {
+        Some(pos) => (&args[..pos], args[pos..].trim()),
+        None => (args, ""),
+    }
+}
+
+impl CommandRegistry {
+    pub fn new() -> CommandRegistry {
+        CommandRegistry {
+            commands: BTreeMap::new(),
+            aliases: BTreeMap::new(),
+        }
+    }
+
+    /// A registry holding the built-in chat commands.
+    pub fn with_defaults() -> CommandRegistry {
+        let mut registry = CommandRegistry::new();
+        registry.register("help", "/help", "List the available commands.", help);
+        registry.register("whisper",
+                          "/w <name> <text>",
+                          "Send a private message to another player.",
+                          whisper);
+        registry.register("reply",
+                          "/r <text>",
+                          "Whisper whoever whispered you last.",
+                          reply);
+        registry.register("say", "/s <text>", "Talk in the General channel.", say);
+        registry.register("group", "/p <text>", "Talk to your group.", group);
+        registry.register("guild", "/g <text>", "Talk to your guild.", guild);
+        registry.register("join",
+                          "/join <channel>",
+                          "Join a channel, creating it if needed.",
+                          join);
+        registry.register("leave", "/leave", "Leave the current channel.", leave);
+        registry.register("rename",
+                          "/rename <name>",
+                          "Rename the current channel.",
+                          rename);
+        registry.register("clear", "/clear", "Clear the chat window.", clear);
+        registry.register("restore",
+                          "/restore",
+                          "Bring back everything that was cleared or pruned.",
+                          restore);
+        registry.register("prune",
+                          "/prune <n>|off",
+                          "Only show the last n messages.",
+                          prune);
+        registry.register("ignore",
+                          "/ignore [name]",
+                          "Hide messages from a player, or list who you ignore.",
+                          ignore);
+        registry.register("unignore",
+                          "/unignore <name>",
+                          "Stop ignoring a player.",
+                          unignore);
+        registry.register("mute",
+                          "/mute [channel]",
+                          "Hide messages in a channel, the current one by default.",
+                          mute);
+        registry.register("unmute",
+                          "/unmute [channel]",
+                          "Show messages in a muted channel again.",
+                          unmute);
+
+        registry.alias("w", "whisper");
+        registry.alias("r", "reply");
+        registry.alias("s", "say");
+        registry.alias("p", "group");
+        registry.alias("party", "group");
+        registry.alias("g", "guild");
+        registry
+    }
+
+    /// Register a command, replacing any command already registered under that name.
+    pub fn register(&mut self,
+                    name: &str,
+                    usage: &'static str,
+                    help: &'static str,
+                    run: CommandFn) {
+        let command = Command {
+            name: name.to_owned(),
+            usage: usage,
+            help: help,
+            run: run,
+        };
+        self.commands.insert(name.to_owned(), command);
+    }
+
+    pub fn alias(&mut self, alias: &str, name: &str) {
+        self.aliases.insert(alias.to_owned(), name.to_owned());
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<&Command> {
+        let name = name.to_lowercase();
+        let name = self.aliases.get(&name).unwrap_or(&name);
+        self.commands.get(name)
+    }
+
+    /// Every name a command can be invoked by, aliases included.
+    pub fn names(&self) -> Vec<&str> {
+        let commands = self.commands.keys().map(|x| x.as_str());
+        let aliases = self.aliases.keys().map(|x| x.as_str());
+        commands.chain(aliases).collect()
+    }
+
+    pub fn iter(&self) -> ::std::collections::btree_map::Values<String, Command> {
+        self.commands.values()
+    }
+
+    /// Run `input` if it is a command, reporting failures in the system channel.
+    ///
+    /// Returns false if `input` isn't a command, in which case it should be sent as a message.
+    pub fn execute(&self,
+                   chat_history: &mut ChatHistory,
+                   channel_id: &mut ChannelId,
+                   player_name: &str,
+                   input: &str)
+                   -> bool {
+        let (name, args) = match parse_command(input) {
+            Some(command) => command,
+            None => return false,
+        };
+        let result = match self.lookup(name) {
+            Some(command) => {
+                let mut context = CommandContext {
+                    commands: self,
+                    chat_history: &mut *chat_history,
+                    channel_id: &mut *channel_id,
+                    player_name: player_name,
+                };
+                (command.run)(&mut context, args)
+            }
+            None => {
+                Err(format!("Unknown command '/{}', type /help for a list of commands.", name))
+            }
+        };
+        if let Err(e) = result {
+            chat_history.send_system_message(&e);
+        }
+        true
+    }
+}
+
+fn usage(context: &CommandContext, name: &str) -> String {
+    match context.commands.lookup(name) {
+        Some(command) => format!("Usage: {}", command.usage),
+        None => String::from("Invalid arguments."),
+    }
+}
+
+fn help(context: &mut CommandContext, _: &str) -> Result<(), String> {
+    let lines: Vec<String> = context.commands
+        .iter()
+        .map(|command| format!("{} - {}", command.usage, command.help))
+        .collect();
+    for line in lines {
+        context.chat_history.send_system_message(&line);
+    }
+    Ok(())
+}
+
+fn whisper(context: &mut CommandContext, args: &str) -> Result<(), String> {
+    let (name, text) = split_first_arg(args);
+    if name.is_empty() || text.is_empty() {
+        return Err(usage(context, "whisper"));
+    }
+    send_whisper(context, name, text)
+}
+
+fn reply(context: &mut CommandContext, args: &str) -> Result<(), String> {
+    if args.is_empty() {
+        return Err(usage(context, "reply"));
+    }
+    let name = context.chat_history
+        .last_whisperer()
+        .map(|x| x.to_owned())
+        .ok_or_else(|| String::from("Nobody has whispered you yet."))?;
+    send_whisper(context, &name, args)
+}
+
+fn send_whisper(context: &mut CommandContext, name: &str, text: &str) -> Result<(), String> {
+    let id = context.chat_history
+        .find_channel("Whisper")
+        .ok_or_else(|| String::from("There is no Whisper channel."))?;
+    context.chat_history.send_whisper(id, context.player_name, name, text);
+    Ok(())
+}
+
+// Send `text` to the channel called `channel`, or switch to that channel if there's no text.
+fn talk_in(context: &mut CommandContext, channel: &str, text: &str) -> Result<(), String> {
+    let id = context.chat_history
+        .find_channel(channel)
+        .ok_or_else(|| format!("There is no {} channel.", channel))?;
+    if text.is_empty() {
+        *context.channel_id = id;
+    } else {
+        context.chat_history.send_player_message(id, context.player_name, text);
+    }
+    Ok(())
+}
+
+fn say(context: &mut CommandContext, args: &str) -> Result<(), String> {
+    talk_in(context, "General", args)
+}
+
+fn group(context: &mut CommandContext, args: &str) -> Result<(), String> {
+    talk_in(context, "Group", args)
+}
+
+fn guild(context: &mut CommandContext, args: &str) -> Result<(), String> {
+    talk_in(context, "Guild", args)
+}
+
+fn join(context: &mut CommandContext, args: &str) -> Result<(), String> {
+    let (name, _) = split_first_arg(args);
+    if name.is_empty() {
+        return Err(usage(context, "join"));
+    }
+    let id = match context.chat_history.find_channel(name) {
+        Some(id) => id,
+        None => context.chat_history.create_channel(name, color::WHITE),
+    };
+    *context.channel_id = id;
+    context.chat_history.send_system_message(&format!("Joined channel {}.", name));
+    Ok(())
+}
+
+fn leave(context: &mut CommandContext, _: &str) -> Result<(), String> {
+    let id = *context.channel_id;
+    let name = context.chat_history
+        .lookup_channel(id)
+        .map(|x| x.name.clone())
+        .unwrap_or_default();
+    if !context.chat_history.remove_channel(id) {
+        return Err(format!("You can't leave the {} channel.", name));
+    }
+    *context.channel_id = DEFAULT_CHANNEL;
+    context.chat_history.send_system_message(&format!("Left channel {}.", name));
+    Ok(())
+}
+
+fn rename(context: &mut CommandContext, args: &str) -> Result<(), String> {
+    if args.is_empty() {
+        return Err(usage(context, "rename"));
+    }
+    if !context.chat_history.rename_channel(*context.channel_id, args) {
+        return Err(String::from("The current channel can't be renamed."));
+    }
+    Ok(())
+}
+
+fn clear(context: &mut CommandContext, _: &str) -> Result<(), String> {
+    context.chat_history.clear();
+    Ok(())
+}
+
+fn restore(context: &mut CommandContext, _: &str) -> Result<(), String> {
+    context.chat_history.restore();
+    Ok(())
+}
+
+fn prune(context: &mut CommandContext, args: &str) -> Result<(), String> {
+    let chat_history = &mut context.chat_history;
+    if args == "off" {
+        let length = chat_history.get_prune().length;
+        chat_history.set_prune(false, length);
+        chat_history.restore();
+        return Ok(());
+    }
+    let length = match args.parse::<i32>() {
+        Ok(length) if length >= 0 => length,
+        _ => return Err(format!("Usage: /prune <n>|off, '{}' isn't a valid length.", args)),
+    };
+    // Same as confirming the Max Length dialog.
+    chat_history.set_prune(true, length);
+    chat_history.restore();
+    chat_history.prune();
+    Ok(())
+}
+
+fn ignore(context: &mut CommandContext, args: &str) -> Result<(), String> {
+    let (name, _) = split_first_arg(args);
+    if name.is_empty() {
+        let ignored: Vec<String> = context.chat_history.filter().ignored().cloned().collect();
+        let notice = if ignored.is_empty() {
+            String::from("You aren't ignoring anyone.")
+        } else {
+            format!("Ignoring: {}", ignored.join(", "))
+        };
+        context.chat_history.send_system_message(&notice);
+        return Ok(());
+    }
+    if name.to_lowercase() == context.player_name.to_lowercase() {
+        return Err(String::from("You can't ignore yourself."));
+    }
+    if !context.chat_history.ignore_sender(name) {
+        return Err(format!("You are already ignoring {}.", name));
+    }
+    context.chat_history.send_system_message(&format!("Now ignoring {}.", name));
+    Ok(())
+}
+
+fn unignore(context: &mut CommandContext, args: &str) -> Result<(), String> {
+    let (name, _) = split_first_arg(args);
+    if name.is_empty() {
+        return Err(usage(context, "unignore"));
+    }
+    if !context.chat_history.unignore_sender(name) {
+        return Err(format!("You aren't ignoring {}.", name));
+    }
+    context.chat_history.send_system_message(&format!("No longer ignoring {}.", name));
+    Ok(())
+}
+
+// The channel named in `args`, or the current channel if no name was given.
+fn channel_arg(context: &CommandContext, args: &str) -> Result<ChannelId, String> {
+    if args.is_empty() {
+        return Ok(*context.channel_id);
+    }
+    context.chat_history
+        .find_channel(args)
+        .ok_or_else(|| format!("There is no {} channel.", args))
+}
+
+fn set_muted(context: &mut CommandContext, args: &str, muted: bool) -> Result<(), String> {
+    let id = channel_arg(context, args)?;
+    let name = context.chat_history
+        .lookup_channel(id)
+        .map(|x| x.name.clone())
+        .unwrap_or_default();
+    if Some(id) == context.chat_history.system_channel() {
+        return Err(format!("The {} channel can't be muted.", name));
+    }
+    context.chat_history.set_channel_muted(id, muted);
+    let notice = if muted {
+        format!("Muted channel {}.", name)
+    } else {
+        format!("Unmuted channel {}.", name)
+    };
+    context.chat_history.send_system_message(&notice);
+    Ok(())
+}
+
+fn mute(context: &mut CommandContext, args: &str) -> Result<(), String> {
+    set_muted(context, args, true)
+}
+
+fn unmute(context: &mut CommandContext, args: &str) -> Result<(), String> {
+    set_muted(context, args, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_split_into_name_and_arguments() {
+        assert_eq!(parse_command("/w Bob  hello there "), Some(("w", "Bob  hello there")));
+        assert_eq!(parse_command("  /help"), Some(("help", "")));
+        assert_eq!(parse_command("/"), None);
+        assert_eq!(parse_command("hello /w"), None);
+    }
+
+    #[test]
+    fn first_argument_is_split_off() {
+        assert_eq!(split_first_arg("Bob  hello there"), ("Bob", "hello there"));
+        assert_eq!(split_first_arg("Bob"), ("Bob", ""));
+    }
+}
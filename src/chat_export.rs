@@ -0,0 +1,345 @@
+use chat_history::{ChannelId, ChatHistory, ChatMessage, Timestamp, now};
+use serde_json;
+
+use std::error::Error;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+/// Which messages to export.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExportScope {
+    /// What a chat tab subscribed to these channels shows.
+    Visible(Vec<ChannelId>),
+    /// Everything still in memory, history backup included.
+    Full,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ExportFormat {
+    Text,
+    JsonLines,
+    Html,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            ExportFormat::Text => "txt",
+            ExportFormat::JsonLines => "jsonl",
+            ExportFormat::Html => "html",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct JsonMessage<'a> {
+    timestamp: Timestamp,
+    channel_id: usize,
+    channel: &'a str,
+    kind: &'static str,
+    sender: Option<&'a str>,
+    recipient: Option<&'a str>,
+    text: &'a str,
+}
+
+/// Format a timestamp as `YYYY-MM-DD HH:MM:SS` in UTC.
+pub fn format_timestamp(timestamp: Timestamp) -> String {
+    let secs = timestamp / 1000;
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+    let (hour, minute, second) = (secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60);
+
+    // Convert days since the epoch to a civil date (Howard Hinnant's days_from_civil, inverted).
+    let z = days as i64 + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 -
+                       day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second)
+}
+
+fn messages<'a>(history: &'a ChatHistory, scope: &ExportScope) -> Vec<&'a ChatMessage> {
+    match *scope {
+        ExportScope::Visible(ref ids) => {
+            let mut messages: Vec<&ChatMessage> =
+                history.iter_history().filter(|x| ids.contains(&x.channel_id)).collect();
+            let whispers = history.iter_whispers()
+                .filter(|x| ids.contains(&x.channel_id) && !history.is_hidden(x));
+            messages.extend(whispers);
+            // Whispers are kept apart from the channels, merge them back in by time.
+            messages.sort_by_key(|x| x.timestamp);
+            messages
+        }
+        ExportScope::Full => {
+            let mut messages: Vec<&ChatMessage> = history.iter_all().collect();
+            messages.extend(history.iter_whispers());
+            messages.sort_by_key(|x| x.timestamp);
+            messages
+        }
+    }
+}
+
+fn channel_name(history: &ChatHistory, id: ChannelId) -> &str {
+    history.lookup_any_channel(id).map(|x| x.name.as_str()).unwrap_or("?")
+}
+
+// The message as a single line of plain text, without the timestamp or channel.
+fn message_line(msg: &ChatMessage) -> String {
+    let text = &msg.text;
+    match (msg.sender.as_ref(), msg.recipient.as_ref()) {
+        (_, Some(recipient)) => format!("To {}: {}", recipient, text),
+        (Some(sender), None) => format!("{}: {}", sender, text),
+        (None, None) => text.to_string(),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn css_color(color: [f32; 4]) -> String {
+    let clamp = |c: f32| c.max(0.0).min(1.0);
+    let channel = |c: f32| (clamp(c) * 255.0).round() as u8;
+    format!("rgba({}, {}, {}, {:.3})",
+            channel(color[0]),
+            channel(color[1]),
+            channel(color[2]),
+            clamp(color[3]))
+}
+
+pub fn export_text<W: Write>(history: &ChatHistory,
+                             scope: &ExportScope,
+                             out: &mut W)
+                             -> Result<(), Box<Error>> {
+    for msg in messages(history, scope) {
+        write!(out,
+               "[{}] [{}] {}\n",
+               format_timestamp(msg.timestamp),
+               channel_name(history, msg.channel_id),
+               message_line(msg))?;
+    }
+    Ok(())
+}
+
+/// Write one JSON object per message, one message per line.
+pub fn export_json_lines<W: Write>(history: &ChatHistory,
+                                   scope: &ExportScope,
+                                   out: &mut W)
+                                   -> Result<(), Box<Error>> {
+    for msg in messages(history, scope) {
+        let json = JsonMessage {
+            timestamp: msg.timestamp,
+            channel_id: msg.channel_id.index(),
+            channel: channel_name(history, msg.channel_id),
+            kind: msg.kind.as_str(),
+            sender: msg.sender.as_ref().map(|x| x.as_str()),
+            recipient: msg.recipient.as_ref().map(|x| x.as_str()),
+            text: msg.text.as_str(),
+        };
+        serde_json::to_writer(&mut *out, &json)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Write a standalone HTML page, every message keeping the text color of its channel.
+pub fn export_html<W: Write>(history: &ChatHistory,
+                             scope: &ExportScope,
+                             out: &mut W)
+                             -> Result<(), Box<Error>> {
+    out.write_all(b"<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>Chat transcript</title>
+<style>
+body { background: #111; font-family: monospace; font-size: 14px; }
+.msg { white-space: pre-wrap; margin: 2px 0; }
+.time { color: #777; }
+</style>
+</head>
+<body>
+")?;
+    for msg in messages(history, scope) {
+        let color = history.lookup_any_channel(msg.channel_id)
+            .map(|x| x.text_color)
+            .unwrap_or([1.0, 1.0, 1.0, 1.0]);
+        write!(out,
+               "<div class=\"msg\" style=\"color: {}\"><span class=\"time\">[{}]</span> [{}] \
+                {}</div>\n",
+               css_color(color),
+               format_timestamp(msg.timestamp),
+               escape_html(channel_name(history, msg.channel_id)),
+               escape_html(&message_line(msg)))?;
+    }
+    out.write_all(b"</body>\n</html>\n")?;
+    Ok(())
+}
+
+pub fn export<W: Write>(history: &ChatHistory,
+                        scope: &ExportScope,
+                        format: ExportFormat,
+                        out: &mut W)
+                        -> Result<(), Box<Error>> {
+    match format {
+        ExportFormat::Text => export_text(history, scope, out),
+        ExportFormat::JsonLines => export_json_lines(history, scope, out),
+        ExportFormat::Html => export_html(history, scope, out),
+    }
+}
+
+/// Export into a new, timestamped file in `dir`, returning the path written to. Existing files
+/// are never overwritten, a later export within the same second gets a number added.
+pub fn export_to_dir<P: AsRef<Path>>(history: &ChatHistory,
+                                     scope: &ExportScope,
+                                     format: ExportFormat,
+                                     dir: P)
+                                     -> Result<PathBuf, Box<Error>> {
+    fs::create_dir_all(&dir)?;
+    let stamp = format_timestamp(now()).replace(' ', "_").replace(':', "-");
+    let mut attempt = 1;
+    let (path, file) = loop {
+        let name = if attempt == 1 {
+            format!("chat-{}.{}", stamp, format.extension())
+        } else {
+            format!("chat-{}-{}.{}", stamp, attempt, format.extension())
+        };
+        let path = dir.as_ref().join(name);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => break (path, file),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(e) => return Err(e.into()),
+        }
+    };
+    let mut out = BufWriter::new(file);
+    export(history, scope, format, &mut out)?;
+    out.flush()?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_history::MessageKind;
+    use serde_json::Value;
+    use std::env;
+
+    const GENERAL: usize = 1;
+
+    fn history() -> ChatHistory {
+        let mut history = ChatHistory::new();
+        history.add_channel(ChannelId::new(0), "System", [1.0, 1.0, 1.0, 1.0]);
+        history.add_channel(ChannelId::new(GENERAL), "<General>", [1.0, 0.5, 0.0, 1.0]);
+        let old = ChatMessage::new("old", ChannelId::new(GENERAL)).with_timestamp(0);
+        history.send_message(old);
+        history.clear();
+        let msg = ChatMessage::new("a < b & \"c\"", ChannelId::new(GENERAL))
+            .with_kind(MessageKind::Player)
+            .with_sender("Bob")
+            .with_timestamp(951_782_400_000);
+        history.send_message(msg);
+        let whisper = ChatMessage::new("psst", ChannelId::new(GENERAL))
+            .with_kind(MessageKind::Whisper)
+            .with_sender("Adventurer")
+            .with_recipient("Carol")
+            .with_timestamp(951_782_401_000);
+        history.send_message(whisper);
+        history
+    }
+
+    fn exported(history: &ChatHistory, scope: &ExportScope, format: ExportFormat) -> String {
+        let mut out = vec![];
+        export(history, scope, format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn timestamps_are_utc_dates() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(1_677_674_096_999), "2023-03-01 12:34:56");
+        assert_eq!(format_timestamp(951_782_400_000), "2000-02-29 00:00:00");
+        assert_eq!(format_timestamp(1_709_251_199_000), "2024-02-29 23:59:59");
+        // 2100 isn't a leap year, February ends on the 28th.
+        assert_eq!(format_timestamp(4_107_542_400_000 - 1000), "2100-02-28 23:59:59");
+        assert_eq!(format_timestamp(4_107_542_400_000), "2100-03-01 00:00:00");
+    }
+
+    #[test]
+    fn text_has_one_line_per_message() {
+        let history = history();
+        let visible = ExportScope::Visible(vec![ChannelId::new(GENERAL)]);
+        assert_eq!(exported(&history, &visible, ExportFormat::Text),
+                   "[2000-02-29 00:00:00] [<General>] Bob: a < b & \"c\"\n\
+                    [2000-02-29 00:00:01] [<General>] To Carol: psst\n");
+        let full = exported(&history, &ExportScope::Full, ExportFormat::Text);
+        assert!(full.starts_with("[1970-01-01 00:00:00] [<General>] old\n"));
+        assert_eq!(full.lines().count(), 3);
+        assert_eq!(exported(&history, &ExportScope::Visible(vec![]), ExportFormat::Text), "");
+    }
+
+    #[test]
+    fn json_lines_are_objects() {
+        let history = history();
+        let visible = ExportScope::Visible(vec![ChannelId::new(GENERAL)]);
+        let json = exported(&history, &visible, ExportFormat::JsonLines);
+        let lines: Vec<Value> = json.lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["text"], "a < b & \"c\"");
+        assert_eq!(lines[0]["channel"], "<General>");
+        assert_eq!(lines[0]["channel_id"], GENERAL);
+        assert_eq!(lines[0]["kind"], "Player");
+        assert_eq!(lines[0]["sender"], "Bob");
+        assert!(lines[0]["recipient"].is_null());
+        assert_eq!(lines[1]["recipient"], "Carol");
+    }
+
+    #[test]
+    fn html_is_escaped_and_colored() {
+        let history = history();
+        let visible = ExportScope::Visible(vec![ChannelId::new(GENERAL)]);
+        let html = exported(&history, &visible, ExportFormat::Html);
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.ends_with("</body>\n</html>\n"));
+        assert!(html.contains("<div class=\"msg\" style=\"color: rgba(255, 128, 0, 1.000)\">\
+                               <span class=\"time\">[2000-02-29 00:00:00]</span> \
+                               [&lt;General&gt;] Bob: a &lt; b &amp; &quot;c&quot;</div>\n"));
+    }
+
+    #[test]
+    fn exports_never_overwrite() {
+        let dir = env::temp_dir().join("softland-chat-export");
+        let _ = fs::remove_dir_all(&dir);
+        let history = history();
+        let first = export_to_dir(&history, &ExportScope::Full, ExportFormat::Text, &dir).unwrap();
+        let second = export_to_dir(&history, &ExportScope::Full, ExportFormat::Text, &dir).unwrap();
+        assert!(first != second);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+use chat_history::{ChannelId, ChatMessage};
+use regex;
+use regex::{Regex, RegexBuilder};
+use toml;
+
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// The chat filter file as stored on disk. It ships with the game and is never written, the
+/// ignore list and muted channels are kept with the chat settings.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ChatFilterFile {
+    // Words that hide any message containing them, matched as whole words ignoring case.
+    #[serde(default)]
+    pub words: Vec<String>,
+}
+
+/// Decides which messages are kept out of the recent history: messages from ignored senders,
+/// messages in muted channels and messages containing a filtered word.
+#[derive(Debug)]
+pub struct ChatFilter {
+    words: Vec<String>,
+    words_regex: Option<Regex>,
+
+    // Lowercased sender names.
+    ignored: BTreeSet<String>,
+    muted: BTreeSet<ChannelId>,
+}
+
+fn build_words_regex(words: &[String]) -> Result<Option<Regex>, regex::Error> {
+    let words: Vec<String> = words.iter()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| regex::escape(x))
+        .collect();
+    if words.is_empty() {
+        return Ok(None);
+    }
+    let pattern = format!(r"\b(?:{})\b", words.join("|"));
+    RegexBuilder::new(&pattern).case_insensitive(true).build().map(Some)
+}
+
+impl ChatFilter {
+    pub fn new() -> ChatFilter {
+        ChatFilter {
+            words: vec![],
+            words_regex: None,
+            ignored: BTreeSet::new(),
+            muted: BTreeSet::new(),
+        }
+    }
+
+    /// Load the word list from `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ChatFilter, Box<Error>> {
+        let contents = {
+            let mut file = File::open(&path)?;
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            contents
+        };
+        let file: ChatFilterFile = toml::from_str(&contents)?;
+        let mut filter = ChatFilter::new();
+        filter.set_words(file.words)?;
+        Ok(filter)
+    }
+
+    pub fn words(&self) -> &[String] {
+        &self.words
+    }
+
+    pub fn set_words(&mut self, words: Vec<String>) -> Result<(), Box<Error>> {
+        self.words_regex = build_words_regex(&words)?;
+        self.words = words;
+        Ok(())
+    }
+
+    /// Whether `text` contains a filtered word.
+    pub fn contains_filtered_word(&self, text: &str) -> bool {
+        self.words_regex.as_ref().map_or(false, |x| x.is_match(text))
+    }
+
+    pub fn ignored(&self) -> ::std::collections::btree_set::Iter<String> {
+        self.ignored.iter()
+    }
+
+    pub fn is_ignored(&self, sender: &str) -> bool {
+        self.ignored.contains(&sender.to_lowercase())
+    }
+
+    /// Returns false if `sender` was already ignored.
+    pub fn ignore(&mut self, sender: &str) -> bool {
+        self.ignored.insert(sender.to_lowercase())
+    }
+
+    /// Returns false if `sender` wasn't ignored.
+    pub fn unignore(&mut self, sender: &str) -> bool {
+        self.ignored.remove(&sender.to_lowercase())
+    }
+
+    /// The muted channels, in id order.
+    pub fn muted(&self) -> ::std::collections::btree_set::Iter<ChannelId> {
+        self.muted.iter()
+    }
+
+    pub fn is_muted(&self, id: ChannelId) -> bool {
+        self.muted.contains(&id)
+    }
+
+    pub fn set_muted(&mut self, id: ChannelId, muted: bool) {
+        if muted {
+            self.muted.insert(id);
+        } else {
+            self.muted.remove(&id);
+        }
+    }
+
+    /// Whether `msg` should be kept out of the recent history.
+    pub fn hides(&self, msg: &ChatMessage) -> bool {
+        let ignored = msg.sender.as_ref().map_or(false, |x| self.is_ignored(x));
+        msg.filtered || ignored || self.is_muted(msg.channel_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(words: &[&str]) -> ChatFilter {
+        let mut filter = ChatFilter::new();
+        filter.set_words(words.iter().map(|x| x.to_string()).collect()).unwrap();
+        filter
+    }
+
+    #[test]
+    fn words_match_ignoring_case() {
+        let filter = filter(&["gold", "cheap items"]);
+        assert!(filter.contains_filtered_word("buy GOLD now"));
+        assert!(filter.contains_filtered_word("Gold!"));
+        assert!(filter.contains_filtered_word("selling Cheap Items here"));
+        assert!(!filter.contains_filtered_word("selling cheap swords"));
+    }
+
+    #[test]
+    fn only_whole_words_match() {
+        let filter = filter(&["ass"]);
+        assert!(filter.contains_filtered_word("what an ass."));
+        assert!(!filter.contains_filtered_word("a classic pass"));
+        assert!(!filter.contains_filtered_word("assassin"));
+    }
+
+    #[test]
+    fn words_are_not_patterns() {
+        let filter = filter(&["a.c", " "]);
+        assert!(filter.contains_filtered_word("a.c"));
+        assert!(!filter.contains_filtered_word("abc"));
+        assert!(!filter.contains_filtered_word("two words"));
+        assert!(!ChatFilter::new().contains_filtered_word("anything"));
+    }
+
+    #[test]
+    fn ignored_senders_and_muted_channels_hide() {
+        let mut filter = ChatFilter::new();
+        filter.ignore("Bob");
+        filter.set_muted(ChannelId::new(2), true);
+        let from = |sender: &str, channel: usize| {
+            ChatMessage::new("hi", ChannelId::new(channel)).with_sender(sender)
+        };
+        assert!(filter.hides(&from("bob", 1)));
+        assert!(filter.hides(&from("Carol", 2)));
+        assert!(!filter.hides(&from("Carol", 1)));
+        assert!(filter.unignore("BOB"));
+        assert!(!filter.hides(&from("Bob", 1)));
+    }
+}
@@ -0,0 +1,120 @@
+use imgui_sys;
+
+use std::error::Error;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::ptr;
+
+/// Where the fonts chat can be drawn in are kept, every `.ttf` file in it is loaded.
+pub const FONTS_PATH: &str = "assets/fonts";
+
+/// The sizes every font is loaded at, in pixels. The font atlas is built once before the first
+/// frame, so only these sizes can be picked.
+pub const FONT_SIZES: &[u32] = &[12, 14, 16, 18, 20, 24];
+
+/// A font picked for a channel or a chat window, by file name without the extension.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ChatFont {
+    pub name: String,
+    pub size: u32,
+}
+
+/// Where a loaded font sits in imgui's font atlas.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FontIndex(usize);
+
+/// The fonts loaded into imgui's font atlas, besides the default one.
+#[derive(Debug)]
+pub struct Fonts {
+    fonts: Vec<(ChatFont, FontIndex)>,
+}
+
+impl Fonts {
+    pub fn new() -> Fonts {
+        Fonts { fonts: vec![] }
+    }
+
+    /// Load every font in `dir` at each of `FONT_SIZES`. This has to happen before the renderer
+    /// builds the font texture. A missing directory just means there are no fonts to pick from.
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Fonts, Box<Error>> {
+        let mut paths = vec![];
+        match fs::read_dir(dir) {
+            Ok(entries) => {
+                for entry in entries {
+                    let path = entry?.path();
+                    let ttf = path.extension()
+                        .map_or(false, |x| x.to_string_lossy().to_lowercase() == "ttf");
+                    if ttf && path.is_file() {
+                        paths.push(path);
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Fonts::new()),
+            Err(e) => return Err(e.into()),
+        }
+        paths.sort();
+
+        let mut fonts = Fonts::new();
+        let atlas = unsafe { (*imgui_sys::igGetIO()).fonts };
+        // Whatever is added first becomes the default font, keep imgui's own one in that spot.
+        if unsafe { (*atlas).fonts.size } == 0 {
+            unsafe { imgui_sys::ImFontAtlas_AddFontDefault(atlas, ptr::null()) };
+        }
+        for path in paths {
+            let name = match path.file_stem() {
+                Some(name) => name.to_string_lossy().into_owned(),
+                None => continue,
+            };
+            let filename = CString::new(path.to_string_lossy().into_owned())?;
+            for &size in FONT_SIZES {
+                let index = unsafe { (*atlas).fonts.size } as usize;
+                let font = unsafe {
+                    imgui_sys::ImFontAtlas_AddFontFromFileTTF(atlas,
+                                                              filename.as_ptr(),
+                                                              size as f32,
+                                                              ptr::null(),
+                                                              ptr::null())
+                };
+                if font.is_null() {
+                    return Err(format!("can't load font '{}'", path.display()).into());
+                }
+                let font = ChatFont {
+                    name: name.clone(),
+                    size: size,
+                };
+                fonts.fonts.push((font, FontIndex(index)));
+            }
+        }
+        Ok(fonts)
+    }
+
+    /// The names of the loaded fonts, in alphabetical order.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.fonts.iter().map(|x| x.0.name.as_str()).collect();
+        names.dedup();
+        names
+    }
+
+    pub fn find(&self, font: &ChatFont) -> Option<FontIndex> {
+        self.fonts.iter().find(|x| x.0 == *font).map(|x| x.1)
+    }
+}
+
+/// Draw whatever `f` draws in `font`, or in the current font if there is none.
+pub fn with_font<F: FnOnce() -> T, T>(font: Option<FontIndex>, f: F) -> T {
+    let font = font.and_then(|FontIndex(index)| unsafe {
+        let fonts = (*(*imgui_sys::igGetIO()).fonts).fonts.as_slice();
+        fonts.get(index).cloned()
+    });
+    match font {
+        Some(font) => {
+            unsafe { imgui_sys::igPushFont(font) };
+            let result = f();
+            unsafe { imgui_sys::igPopFont() };
+            result
+        }
+        None => f(),
+    }
+}
@@ -1,59 +1,238 @@
-#[derive(Debug, Copy, Clone, PartialEq)]
+use chat_filter::ChatFilter;
+use chat_font::ChatFont;
+use chat_listener::{ChannelChange, ChatListener, Listeners, Verdict};
+use chat_notify::{HighlightRules, Unread};
+use chat_rate::RateLimiter;
+use chat_log::{ChannelRecord, ChatLog, ChatLogFile, CHAT_LOG_VERSION, MessageRecord, PruneRecord};
+use chat_search::{SearchMatch, SearchQuery};
+use chat_store::{ChannelIter, ChatStore, DEFAULT_CAPACITY, MessageSeq, StoreIter};
+use chat_text::ChatText;
+use chat_whisper::{Conversation, Conversations};
+use combat::CombatEvent;
+
+use std::collections::{BTreeSet, HashMap};
+use std::collections::vec_deque;
+use std::error::Error;
+use std::iter::Peekable;
+use std::mem;
+use std::path::Path;
+use std::slice;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Wall-clock time in milliseconds since the unix epoch.
+pub type Timestamp = u64;
+
+pub fn now() -> Timestamp {
+    let elapsed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64
+}
+
+/// Identifies a channel for as long as the chat history exists. Ids are handed out by
+/// `ChatHistory` and are never reused, even after the channel is removed, so a message can't end
+/// up in a different channel than the one it was sent to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct ChannelId(usize);
 
+/// The channel every history starts with. It can't be removed, and messages sent to a channel that
+/// no longer exists are delivered here instead.
+pub const DEFAULT_CHANNEL: ChannelId = ChannelId(0);
+
 impl ChannelId {
     pub fn new(id: usize) -> ChannelId {
         ChannelId(id)
     }
+
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+/// Where a message is kept, either in the store or in a whisper conversation.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MessageRef {
+    Store(MessageSeq),
+    // The conversation's index in `ChatHistory::conversations`, and the whisper's sequence number
+    // within the conversation.
+    Whisper(usize, MessageSeq),
 }
 
 #[derive(Debug)]
 pub struct Channel {
     pub id: ChannelId,
-    pub name: String,
+    pub name: ChatText,
     pub text_color: [f32; 4],
+
+    // The font the channel's messages are drawn in, None uses the chat window's font.
+    pub font: Option<ChatFont>,
 }
 
 impl Channel {
     pub fn new(id: ChannelId, name: &str, text_color: [f32; 4]) -> Channel {
         Channel {
             id: id,
-            name: name.to_owned(),
+            name: ChatText::new(name),
             text_color: text_color,
+            font: None,
+        }
+    }
+
+    fn to_record(&self, removed: bool) -> ChannelRecord {
+        ChannelRecord {
+            id: self.id.0,
+            name: self.name.to_string(),
+            text_color: self.text_color,
+            removed: removed,
+            font: self.font.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MessageKind {
+    Player,
+    System,
+    Combat,
+    Whisper,
+}
+
+impl MessageKind {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            MessageKind::Player => "player",
+            MessageKind::System => "system",
+            MessageKind::Combat => "combat",
+            MessageKind::Whisper => "whisper",
+        }
+    }
+
+    pub fn from_str(kind: &str) -> Option<MessageKind> {
+        match kind {
+            "player" => Some(MessageKind::Player),
+            "system" => Some(MessageKind::System),
+            "combat" => Some(MessageKind::Combat),
+            "whisper" => Some(MessageKind::Whisper),
+            _ => None,
         }
     }
 }
 
 #[derive(Debug)]
 pub struct ChatMessage {
-    pub data: Vec<u8>,
+    pub text: ChatText,
     pub channel_id: ChannelId,
+    pub kind: MessageKind,
+
+    // Who sent the message, system and combat messages usually don't have a sender.
+    pub sender: Option<String>,
+    // Who a whisper was sent to, if it was sent by us.
+    pub recipient: Option<String>,
+    pub timestamp: Timestamp,
+
+    // The event a combat message reports, its text is the event rendered for the combat log.
+    pub combat: Option<CombatEvent>,
+
+    // Whether the text contains a word from the chat filter.
+    pub filtered: bool,
 }
 
 impl ChatMessage {
-    pub fn new<B: Into<Vec<u8>>>(bytes: B, channel_id: ChannelId) -> ChatMessage {
+    /// Create a system message stamped with the current time.
+    pub fn new<T: Into<ChatText>>(text: T, channel_id: ChannelId) -> ChatMessage {
         ChatMessage {
-            data: bytes.into(),
+            text: text.into(),
             channel_id: channel_id,
+            kind: MessageKind::System,
+            sender: None,
+            recipient: None,
+            timestamp: now(),
+            combat: None,
+            filtered: false,
         }
     }
 
-    pub fn to_owned(&self) -> Vec<u8> {
-        self.data.to_owned()
+    pub fn with_kind(mut self, kind: MessageKind) -> ChatMessage {
+        self.kind = kind;
+        self
     }
-}
 
-impl Iterator for ChatMessage {
-    type Item = u8;
-    fn next(&mut self) -> Option<u8> {
-        match self.data.iter().next() {
-            Some(b) => Some(*b),
-            None => None,
+    pub fn with_sender(mut self, sender: &str) -> ChatMessage {
+        self.sender = Some(ChatText::new(sender).into_string());
+        self
+    }
+
+    pub fn with_recipient(mut self, recipient: &str) -> ChatMessage {
+        self.recipient = Some(ChatText::new(recipient).into_string());
+        self
+    }
+
+    pub fn with_timestamp(mut self, timestamp: Timestamp) -> ChatMessage {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// A combat message reporting `event`.
+    pub fn from_combat_event(event: CombatEvent, channel_id: ChannelId) -> ChatMessage {
+        ChatMessage::new(event.render(), channel_id)
+            .with_kind(MessageKind::Combat)
+            .with_timestamp(event.timestamp)
+            .with_combat(event)
+    }
+
+    pub fn with_combat(mut self, event: CombatEvent) -> ChatMessage {
+        self.combat = Some(event);
+        self
+    }
+
+    /// Who the player whispered, or who whispered the player. None for anything but whispers.
+    pub fn whisper_partner(&self) -> Option<&str> {
+        if self.kind != MessageKind::Whisper {
+            return None;
+        }
+        self.recipient.as_ref().or(self.sender.as_ref()).map(|x| x.as_str())
+    }
+
+    // Recover the event from a combat message that only has its text, such as one read back from
+    // the chat log.
+    fn parse_combat(mut self) -> ChatMessage {
+        if self.kind == MessageKind::Combat && self.combat.is_none() {
+            self.combat = CombatEvent::parse(&self.text, self.timestamp);
         }
+        self
+    }
+
+    fn to_record(&self, backup: bool) -> MessageRecord {
+        MessageRecord {
+            channel: self.channel_id.0,
+            text: self.text.to_string(),
+            backup: backup,
+            kind: Some(self.kind.as_str().to_owned()),
+            sender: self.sender.clone(),
+            recipient: self.recipient.clone(),
+            timestamp: self.timestamp,
+        }
+    }
+
+    fn from_record(record: MessageRecord) -> ChatMessage {
+        let kind = record.kind
+            .and_then(|kind| MessageKind::from_str(&kind))
+            .unwrap_or(MessageKind::System);
+        // The log is read back from disk, so the text and names are checked like any other input.
+        let sanitize = |text: String| ChatText::from(text).into_string();
+        let msg = ChatMessage {
+            text: ChatText::from(record.text),
+            channel_id: ChannelId::new(record.channel),
+            kind: kind,
+            sender: record.sender.map(&sanitize),
+            recipient: record.recipient.map(&sanitize),
+            timestamp: record.timestamp,
+            combat: None,
+            filtered: false,
+        };
+        msg.parse_combat()
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ChatPrune {
     pub length: i32,
     pub enabled: bool,
@@ -61,34 +240,86 @@ pub struct ChatPrune {
 
 #[derive(Debug)]
 pub struct ChatHistory {
-    history: Vec<ChatMessage>,
-    history_backup: Vec<ChatMessage>,
+    // Every message we still hold, both the recent history and the backup. Messages from
+    // `visible_from` on are the recent history, anything older (or from a removed channel) is the
+    // backup. Clearing, restoring and pruning just move that boundary.
+    store: ChatStore,
+    visible_from: MessageSeq,
+
+    // Channels in the order they are displayed.
     channels: Vec<Channel>,
+    // Kept around so messages from removed channels can still be shown with their channel's
+    // name and color in the backup.
+    removed_channels: Vec<Channel>,
+    next_channel_id: usize,
     prune: ChatPrune,
+
+    // Where system notices (command feedback, errors, ...) are sent. Falls back to the first
+    // channel when unset.
+    system_channel: Option<ChannelId>,
+
+    // When present, every channel and message added to the history is also appended here.
+    log: Option<ChatLog>,
+
+    // Combat events added since the last call to `take_combat_events`.
+    combat_events: Vec<CombatEvent>,
+
+    // Messages the filter hides are kept in the backup, unless `reveal_hidden` is set.
+    filter: ChatFilter,
+    reveal_hidden: bool,
+
+    // Per channel, what arrived since the channel was last viewed.
+    unread: HashMap<ChannelId, Unread>,
+    highlight_rules: HighlightRules,
+
+    // Flood protection for `send_message`. System notices and combat events bypass it.
+    rate_limiter: RateLimiter,
+
+    // Whispers don't go into `store`, they are kept per conversation.
+    conversations: Conversations,
+
+    listeners: Listeners,
 }
 
 impl ChatHistory {
     pub fn new<'a>() -> ChatHistory {
         ChatHistory {
-            history: vec![],
-            history_backup: vec![],
+            store: ChatStore::with_capacity(DEFAULT_CAPACITY),
+            visible_from: 0,
             channels: vec![],
+            removed_channels: vec![],
+            next_channel_id: 0,
             prune: ChatPrune {
                 length: 0,
                 enabled: false,
             },
+            system_channel: None,
+            log: None,
+            combat_events: vec![],
+            filter: ChatFilter::new(),
+            reveal_hidden: false,
+            unread: HashMap::new(),
+            highlight_rules: HighlightRules::new(),
+            rate_limiter: RateLimiter::new(),
+            conversations: Conversations::new(),
+            listeners: Listeners::new(),
         }
     }
 
     pub fn from_existing<'a>(channels: &[((String), [f32; 4])],
-                             history: &'a [(&'a str, ChannelId)],
+                             history: &'a [(Option<&'a str>, &'a str, MessageKind, ChannelId)],
                              prune: ChatPrune)
                              -> ChatHistory {
         let mut chat_history = ChatHistory::new();
         chat_history.prune = prune;
-        chat_history.history = history.iter()
-            .map(|&(msg, chan_id)| ChatMessage::new((*msg).to_string().into_bytes(), chan_id))
-            .collect();
+        for &(sender, msg, kind, chan_id) in history {
+            let msg = ChatMessage::new(msg, chan_id).with_kind(kind);
+            let msg = match sender {
+                Some(sender) => msg.with_sender(sender),
+                None => msg,
+            };
+            chat_history.push_message(msg.parse_combat());
+        }
 
         for (idx, channels) in channels.iter().enumerate() {
             let &(ref name, color) = channels;
@@ -98,9 +329,115 @@ impl ChatHistory {
         chat_history
     }
 
-    pub fn channel_names(&self) -> Vec<(String, [f32; 4])> {
-        let copy_channel_name = |c: &Channel| (c.name.clone(), c.text_color);
-        self.channels.iter().map(copy_channel_name).collect()
+    /// Rebuild a chat history from a log written by `save` and/or appended to by an open log.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ChatHistory, Box<Error>> {
+        let log = ChatLog::read(path)?;
+
+        let mut chat_history = ChatHistory::new();
+        for record in log.channel {
+            let id = ChannelId::new(record.id);
+            if record.removed {
+                chat_history.remove_channel(id);
+                if chat_history.lookup_removed_channel(id).is_none() {
+                    let mut channel = Channel::new(id, &record.name, record.text_color);
+                    channel.font = record.font;
+                    chat_history.removed_channels.push(channel);
+                }
+                chat_history.next_channel_id = max!(chat_history.next_channel_id, record.id + 1);
+                continue;
+            }
+            // A later definition of the same channel replaces the earlier one. The font isn't an
+            // argument to `add_channel`, so it's set here for new channels too.
+            chat_history.add_channel(id, &record.name, record.text_color);
+            if let Some(channel) = chat_history.lookup_channel_mut(id) {
+                channel.name = ChatText::from(record.name);
+                channel.text_color = record.text_color;
+                channel.font = record.font;
+            }
+        }
+        for record in log.message {
+            let backup = record.backup;
+            let seq = chat_history.push_message(ChatMessage::from_record(record));
+            if let (true, Some(seq)) = (backup, seq) {
+                // Everything up to the last message that was in the backup stays in the backup.
+                chat_history.visible_from = seq + 1;
+            }
+        }
+        if let Some(prune) = log.prune {
+            chat_history.set_prune(prune.enabled, prune.length);
+            if prune.enabled {
+                chat_history.prune();
+            }
+        }
+        Ok(chat_history)
+    }
+
+    /// Write a snapshot of the channels, prune settings and both histories to `path`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<Error>> {
+        let channel = {
+            let removed = self.removed_channels.iter().map(|x| x.to_record(true));
+            let active = self.channels.iter().map(|x| x.to_record(false));
+            removed.chain(active).collect()
+        };
+        let message = {
+            let store = self.store
+                .iter_from(self.store.first_seq())
+                .map(|(seq, msg)| msg.to_record(seq < self.visible_from));
+            let whispers = self.iter_whispers().map(|msg| msg.to_record(false));
+            store.chain(whispers).collect()
+        };
+        let log = ChatLogFile {
+            version: CHAT_LOG_VERSION,
+            prune: Some(PruneRecord {
+                length: self.prune.length,
+                enabled: self.prune.enabled,
+            }),
+            channel: channel,
+            message: message,
+        };
+        ChatLog::write(path, &log)
+    }
+
+    /// Append every channel and message added from now on to the log at `path`.
+    pub fn open_log<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Box<Error>> {
+        self.log = Some(ChatLog::open(path)?);
+        Ok(())
+    }
+
+    /// Compact the open log (if any) into a fresh snapshot of the current history.
+    pub fn sync_log(&mut self) -> Result<(), Box<Error>> {
+        let path = match self.log.take() {
+            Some(log) => log.path().to_path_buf(),
+            None => return Ok(()),
+        };
+        self.save(&path)?;
+        self.open_log(&path)
+    }
+
+    fn log_message(&mut self, msg: &ChatMessage) {
+        if let Some(ref mut log) = self.log {
+            if let Err(e) = log.append_message(msg.to_record(false)) {
+                println!("error appending to chat log: {}", e);
+            }
+        }
+    }
+
+    fn log_channel(&mut self, id: ChannelId) {
+        let record = match (self.lookup_channel(id), self.lookup_removed_channel(id)) {
+            (Some(channel), _) => channel.to_record(false),
+            (None, Some(channel)) => channel.to_record(true),
+            (None, None) => return,
+        };
+        if let Some(ref mut log) = self.log {
+            if let Err(e) = log.append_channel(record) {
+                println!("error appending to chat log: {}", e);
+            }
+        }
+    }
+
+    /// The channels in display order.
+    pub fn channels(&self) -> &[Channel] {
+        &self.channels
     }
 
     pub fn lookup_channel_mut(&mut self, id: ChannelId) -> Option<&mut Channel> {
@@ -110,50 +447,202 @@ impl ChatHistory {
     pub fn lookup_channel(&self, id: ChannelId) -> Option<&Channel> {
         self.channels.iter().filter(|x| x.id == id).next()
     }
+
+    /// Find a channel by name, ignoring case.
+    pub fn find_channel(&self, name: &str) -> Option<ChannelId> {
+        let name = name.to_lowercase();
+        self.channels.iter().find(|x| x.name.to_lowercase() == name).map(|x| x.id)
+    }
+
+    pub fn lookup_removed_channel(&self, id: ChannelId) -> Option<&Channel> {
+        self.removed_channels.iter().filter(|x| x.id == id).next()
+    }
+
+    /// Look a channel up whether or not it has been removed, for displaying old messages.
+    pub fn lookup_any_channel(&self, id: ChannelId) -> Option<&Channel> {
+        self.lookup_channel(id).or_else(|| self.lookup_removed_channel(id))
+    }
+
+    pub fn system_channel(&self) -> Option<ChannelId> {
+        self.system_channel.or_else(|| self.channels.first().map(|x| x.id))
+    }
+
+    pub fn set_system_channel(&mut self, id: ChannelId) {
+        self.system_channel = Some(id);
+    }
     pub fn add_channel(&mut self, id: ChannelId, name: &str, text_color: [f32; 4]) -> bool {
         let channel_already_present = self.channel_present(id);
-        if !channel_already_present {
-            // We don't add the channel if it's already present.
+        let channel_removed = self.lookup_removed_channel(id).is_some();
+        if !channel_already_present && !channel_removed {
+            // We don't add the channel if it's already present, or re-use the id of a channel
+            // that was removed.
             self.channels.push(Channel::new(id, name, text_color));
+            self.next_channel_id = max!(self.next_channel_id, id.0 + 1);
+            self.log_channel(id);
+            self.notify_channel(id, ChannelChange::Added);
         }
         channel_already_present
     }
 
+    /// Create a channel at runtime, appending it to the end of the channel list.
+    pub fn create_channel(&mut self, name: &str, text_color: [f32; 4]) -> ChannelId {
+        let id = ChannelId::new(self.next_channel_id);
+        self.add_channel(id, name, text_color);
+        id
+    }
+
+    /// Remove a channel. The default channel can't be removed.
+    ///
+    /// The channel's messages become part of the history backup, where they stay tagged with the
+    /// removed channel's id. Anything sent to the channel afterwards goes to the default channel.
+    pub fn remove_channel(&mut self, id: ChannelId) -> bool {
+        if id == DEFAULT_CHANNEL {
+            return false;
+        }
+        let pos = match self.channels.iter().position(|x| x.id == id) {
+            Some(pos) => pos,
+            None => return false,
+        };
+        let channel = self.channels.remove(pos);
+        self.removed_channels.push(channel);
+
+        if self.system_channel == Some(id) {
+            self.system_channel = None;
+        }
+        self.unread.remove(&id);
+        self.log_channel(id);
+        self.notify_channel(id, ChannelChange::Removed);
+        true
+    }
+
+    /// Move a channel to `index` in the display order, clamping to the end of the list.
+    pub fn move_channel(&mut self, id: ChannelId, index: usize) -> bool {
+        let pos = match self.channels.iter().position(|x| x.id == id) {
+            Some(pos) => pos,
+            None => return false,
+        };
+        let channel = self.channels.remove(pos);
+        let index = min!(index, self.channels.len());
+        self.channels.insert(index, channel);
+        self.notify_channel(id, ChannelChange::Moved);
+        true
+    }
+
+    pub fn channel_index(&self, id: ChannelId) -> Option<usize> {
+        self.channels.iter().position(|x| x.id == id)
+    }
+
     pub fn clear(&mut self) {
         // Move everything from history into history_backup
-        self.history_backup.append(&mut self.history);
+        self.visible_from = self.store.end_seq();
     }
 
     pub fn restore(&mut self) {
-        // 1) flush everything currently in recent history to the end of the history backup
-        self.clear();
+        // Move everything from backup to recent history
+        self.visible_from = self.store.first_seq();
+    }
+
+    fn is_visible(&self, seq: MessageSeq, msg: &ChatMessage) -> bool {
+        seq >= self.visible_from && self.channel_present(msg.channel_id) && !self.is_hidden(msg)
+    }
+
+    /// Whether the chat filter keeps `msg` out of the recent history.
+    pub fn is_hidden(&self, msg: &ChatMessage) -> bool {
+        !self.reveal_hidden && self.filter.hides(msg)
+    }
+
+    pub fn filter(&self) -> &ChatFilter {
+        &self.filter
+    }
+
+    /// Replace the chat filter, re-checking every message and whisper against its word list.
+    pub fn set_filter(&mut self, filter: ChatFilter) {
+        self.filter = filter;
+        let filter = &self.filter;
+        let store = &mut self.store;
+        for seq in store.first_seq()..store.end_seq() {
+            if let Some(msg) = store.get_mut(seq) {
+                msg.filtered = msg.sender.is_some() && filter.contains_filtered_word(&msg.text);
+            }
+        }
+        for conversation in self.conversations.iter_mut() {
+            for msg in conversation.iter_mut() {
+                msg.filtered = msg.sender.is_some() && filter.contains_filtered_word(&msg.text);
+            }
+        }
+    }
 
-        // 2) Move everything from backup to recent history
-        self.history.append(&mut self.history_backup);
+    /// Hide every message from `sender`, returns false if they were already ignored.
+    pub fn ignore_sender(&mut self, sender: &str) -> bool {
+        self.filter.ignore(sender)
+    }
+
+    pub fn unignore_sender(&mut self, sender: &str) -> bool {
+        self.filter.unignore(sender)
+    }
+
+    pub fn is_channel_muted(&self, id: ChannelId) -> bool {
+        self.filter.is_muted(id)
+    }
+
+    pub fn set_channel_muted(&mut self, id: ChannelId, muted: bool) {
+        self.filter.set_muted(id, muted);
+    }
+
+    pub fn reveal_hidden(&self) -> bool {
+        self.reveal_hidden
+    }
+
+    /// Show the messages the filter hides in the recent history.
+    pub fn set_reveal_hidden(&mut self, reveal_hidden: bool) {
+        self.reveal_hidden = reveal_hidden;
     }
 
     fn channel_present(&self, id: ChannelId) -> bool {
         self.channels.iter().any(|ref x| x.id == id)
     }
 
+    pub fn set_channel_font(&mut self, id: ChannelId, font: Option<ChatFont>) {
+        if let Some(channel) = self.lookup_channel_mut(id) {
+            channel.font = font;
+        }
+        self.log_channel(id);
+    }
+
     pub fn rename_channel(&mut self, id: ChannelId, name: &str) -> bool {
-        self.lookup_channel_mut(id)
+        let renamed = self.lookup_channel_mut(id)
             .and_then(|f| {
-                f.name = String::from(name);
+                f.name = ChatText::new(name);
                 Some(f)
             })
-            .is_some()
+            .is_some();
+        if renamed {
+            self.log_channel(id);
+            self.notify_channel(id, ChannelChange::Renamed);
+        }
+        renamed
     }
 
     pub fn prune(&mut self) {
-        let length = self.prune.length as usize;
-        let history_length = self.history.len();
-        if length < history_length {
-            let extend_length = history_length - length;
-            self.history_backup.extend(self.history.drain(..extend_length));
+        let length = max!(self.prune.length, 0) as MessageSeq;
+        let end = self.store.end_seq();
+        if end - self.visible_from > length {
+            let pruned = end - length - self.visible_from;
+            self.visible_from = end - length;
+            self.listeners.prune(pruned as usize);
         }
     }
 
+    /// Maximum number of messages kept in memory, recent history and backup combined. The oldest
+    /// messages are dropped once it is reached.
+    pub fn capacity(&self) -> usize {
+        self.store.capacity()
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.store.set_capacity(capacity);
+    }
+
     pub fn get_prune(&self) -> &ChatPrune {
         &self.prune
     }
@@ -163,46 +652,627 @@ impl ChatHistory {
         self.prune.length = length;
     }
 
-    pub fn send_message_u8(&mut self, id: ChannelId, msg: &[u8]) {
-        let msg = ChatMessage::new(msg.to_owned(), id);
-        self.history.push(msg);
+    /// Send a message, unless it breaks its channel's rate limit. A rejected message of our own is
+    /// answered with a system notice saying why, anybody else's is dropped silently.
+    pub fn send_message(&mut self, msg: ChatMessage) {
+        if let Err(rejection) = self.rate_limiter.check(&msg) {
+            if !self.highlight_rules.is_own(&msg) {
+                return;
+            }
+            let notice = {
+                let channel = self.lookup_channel(msg.channel_id).map_or("", |x| x.name.as_str());
+                format!("Your message to {} wasn't sent, {}.", channel, rejection)
+            };
+            self.send_system_message(&notice);
+            return;
+        }
+        self.deliver(msg);
+    }
+
+    // Add a message to the history without checking the rate limit, unless a listener vetoes it.
+    fn deliver(&mut self, mut msg: ChatMessage) {
+        let mut replies = vec![];
+        if self.listeners.message(&mut msg, &mut replies) == Verdict::Keep {
+            self.add_message(msg);
+        }
+        for reply in replies {
+            self.add_message(reply);
+        }
+    }
+
+    fn add_message(&mut self, mut msg: ChatMessage) {
+        if !self.channel_present(msg.channel_id) {
+            msg.channel_id = DEFAULT_CHANNEL;
+        }
+        self.log_message(&msg);
+        let partner = msg.whisper_partner().map(|x| x.to_owned());
+        match (self.push_message(msg), partner) {
+            (Some(seq), _) => self.count_unread(seq),
+            (None, Some(partner)) => self.count_whisper(&partner),
+            (None, None) => {}
+        }
         if self.prune.enabled {
             self.prune();
         }
     }
 
+    // Add a message to the store, or to its conversation if it is a whisper. Returns where the
+    // store put it.
+    fn push_message(&mut self, mut msg: ChatMessage) -> Option<MessageSeq> {
+        if msg.sender.is_some() {
+            msg.filtered = self.filter.contains_filtered_word(&msg.text);
+        }
+        if let Some(ref event) = msg.combat {
+            self.combat_events.push(event.clone());
+        }
+        let partner = msg.whisper_partner().map(|x| x.to_owned());
+        match partner {
+            Some(partner) => {
+                self.conversations.push(&partner, msg);
+                None
+            }
+            None => Some(self.store.push(msg)),
+        }
+    }
+
+    pub fn send_message_u8(&mut self, id: ChannelId, msg: &[u8]) {
+        self.send_message(ChatMessage::new(msg, id))
+    }
+
     pub fn send_message_str(&mut self, id: ChannelId, msg: &str) {
         self.send_message_u8(id, msg.as_bytes())
     }
 
+    pub fn send_player_message(&mut self, id: ChannelId, sender: &str, msg: &str) {
+        let msg = ChatMessage::new(msg, id)
+            .with_kind(MessageKind::Player)
+            .with_sender(sender);
+        self.send_message(msg)
+    }
+
+    pub fn send_whisper(&mut self, id: ChannelId, sender: &str, recipient: &str, msg: &str) {
+        let msg = ChatMessage::new(msg, id)
+            .with_kind(MessageKind::Whisper)
+            .with_sender(sender)
+            .with_recipient(recipient);
+        self.send_message(msg)
+    }
+
+    pub fn send_system_message(&mut self, msg: &str) {
+        if let Some(id) = self.system_channel() {
+            self.deliver(ChatMessage::new(msg, id));
+        }
+    }
+
+    fn count_unread(&mut self, seq: MessageSeq) {
+        let (channel_id, timestamp, highlighted) = match self.store.get(seq) {
+            Some(msg) if !self.is_hidden(msg) && !self.highlight_rules.is_own(msg) => {
+                (msg.channel_id, msg.timestamp, self.highlight_rules.highlights(msg))
+            }
+            _ => return,
+        };
+        let unread = self.unread.entry(channel_id).or_insert_with(Unread::default);
+        unread.count += 1;
+        if highlighted {
+            unread.highlights += 1;
+            unread.last_highlight = timestamp;
+        }
+    }
+
+    // Open the conversation a whisper just went into, and count it as unread unless the player
+    // sent it.
+    fn count_whisper(&mut self, partner: &str) {
+        let (incoming, timestamp, highlighted) = {
+            let msg = match self.conversations.find(partner).and_then(|x| x.last()) {
+                Some(msg) if !self.is_hidden(msg) => msg,
+                _ => return,
+            };
+            let incoming = msg.recipient.is_none() && !self.highlight_rules.is_own(msg);
+            (incoming, msg.timestamp, self.highlight_rules.highlights(msg))
+        };
+        if incoming {
+            self.conversations.set_last_whisperer(partner);
+        }
+        if let Some(conversation) = self.conversations.find_mut(partner) {
+            conversation.open = true;
+            if incoming {
+                conversation.unread.count += 1;
+                if highlighted {
+                    conversation.unread.highlights += 1;
+                    conversation.unread.last_highlight = timestamp;
+                }
+            }
+        }
+    }
+
+    /// Whisper conversations, in the order they were started.
+    pub fn conversations(&self) -> slice::Iter<Conversation> {
+        self.conversations.iter()
+    }
+
+    pub fn conversation(&self, partner: &str) -> Option<&Conversation> {
+        self.conversations.find(partner)
+    }
+
+    /// Close a conversation's tab, it opens again with the next whisper.
+    pub fn close_conversation(&mut self, partner: &str) {
+        if let Some(conversation) = self.conversations.find_mut(partner) {
+            conversation.open = false;
+            conversation.unread = Unread::default();
+        }
+    }
+
+    pub fn mark_conversation_read(&mut self, partner: &str) {
+        if let Some(conversation) = self.conversations.find_mut(partner) {
+            conversation.unread = Unread::default();
+        }
+    }
+
+    /// Whoever whispered the player last.
+    pub fn last_whisperer(&self) -> Option<&str> {
+        self.conversations.last_whisperer()
+    }
+
+    /// Iterate every whisper of every conversation, hidden ones included. Each conversation is
+    /// in order, but they aren't merged by time.
+    pub fn iter_whispers<'a>(&'a self) -> ChatWhisperIterator<'a> {
+        ChatWhisperIterator {
+            conversations: self.conversations.iter(),
+            inner: None,
+        }
+    }
+
+    /// Iterate the whispers exchanged with `partner`, oldest first.
+    pub fn iter_conversation<'a>(&'a self, partner: &str) -> ChatConversationIterator<'a> {
+        ChatConversationIterator {
+            history: self,
+            inner: self.conversations.find(partner).map(|x| x.iter()),
+        }
+    }
+
+    /// What channel `id` received since it was last marked read.
+    pub fn unread(&self, id: ChannelId) -> Unread {
+        self.unread.get(&id).cloned().unwrap_or_default()
+    }
+
+    pub fn mark_read(&mut self, id: ChannelId) {
+        self.unread.remove(&id);
+    }
+
+    pub fn mark_all_read(&mut self) {
+        self.unread.clear();
+    }
+
+    /// Have `listener` called whenever a message is sent, the history is pruned or a channel
+    /// changes.
+    pub fn add_listener(&mut self, listener: Box<ChatListener>) {
+        self.listeners.add(listener);
+    }
+
+    fn notify_channel(&mut self, id: ChannelId, change: ChannelChange) {
+        let channel = self.channels.iter().chain(&self.removed_channels).find(|x| x.id == id);
+        if let Some(channel) = channel {
+            self.listeners.channel(channel, change);
+        }
+    }
+
+    pub fn set_rate_limiter(&mut self, rate_limiter: RateLimiter) {
+        self.rate_limiter = rate_limiter;
+    }
+
+    pub fn highlight_rules(&self) -> &HighlightRules {
+        &self.highlight_rules
+    }
+
+    pub fn set_highlight_rules(&mut self, rules: HighlightRules) {
+        self.highlight_rules = rules;
+    }
+
+    /// Report a combat event in channel `id`.
+    pub fn send_combat_event(&mut self, id: ChannelId, event: CombatEvent) {
+        self.deliver(ChatMessage::from_combat_event(event, id))
+    }
+
+    /// Take the combat events of every combat message added since the last call, oldest first.
+    pub fn take_combat_events(&mut self) -> Vec<CombatEvent> {
+        mem::replace(&mut self.combat_events, vec![])
+    }
+
+    /// Iterate the recent history, oldest first.
     pub fn iter_history<'a>(&'a self) -> ChatHistoryIterator<'a> {
-        ChatHistoryIterator::new(&self.history)
+        ChatHistoryIterator {
+            history: self,
+            inner: self.store.iter_from(self.visible_from),
+            visible: Some(true),
+        }
     }
 
+    /// Iterate the history backup, oldest first.
     pub fn iter_backup<'a>(&'a self) -> ChatHistoryIterator<'a> {
-        ChatHistoryIterator::new(&self.history_backup)
+        ChatHistoryIterator {
+            history: self,
+            inner: self.store.iter_from(self.store.first_seq()),
+            visible: Some(false),
+        }
+    }
+
+    /// Iterate both the history backup and the recent history, oldest first.
+    pub fn iter_all<'a>(&'a self) -> ChatHistoryIterator<'a> {
+        ChatHistoryIterator {
+            history: self,
+            inner: self.store.iter_from(self.store.first_seq()),
+            visible: None,
+        }
+    }
+
+    /// Iterate the recent history of several channels as rows, oldest first. Only the channels'
+    /// own messages are visited, including the ones the filter hides, so a message's position
+    /// matches the sum of `channel_len` over the channels. Muted channels are left out, unless
+    /// hidden messages are revealed.
+    pub fn iter_channel_rows<'a>(&'a self, ids: &[ChannelId]) -> ChatChannelRowIterator<'a> {
+        let inner = ids.iter()
+            .filter(|&&id| self.reveal_hidden || !self.filter.is_muted(id))
+            .map(|&id| self.store.iter_channel_from(id, self.visible_from).peekable())
+            .collect();
+        ChatChannelRowIterator { inner: inner }
+    }
+
+    /// Find every message matching `query` in both the history backup and the recent history,
+    /// whispers included, oldest first.
+    pub fn search(&self, query: &SearchQuery) -> Vec<SearchMatch> {
+        let mut found: Vec<(Timestamp, SearchMatch)> = self.store
+            .iter_from(self.store.first_seq())
+            .filter_map(|(seq, msg)| {
+                query.matches(MessageRef::Store(seq), msg, !self.is_visible(seq, msg))
+                    .map(|x| (msg.timestamp, x))
+            })
+            .collect();
+        for (idx, conversation) in self.conversations.iter().enumerate() {
+            for (n, msg) in conversation.iter().enumerate() {
+                let at = MessageRef::Whisper(idx, conversation.first_seq() + n as MessageSeq);
+                if let Some(x) = query.matches(at, msg, self.is_hidden(msg)) {
+                    found.push((msg.timestamp, x));
+                }
+            }
+        }
+        found.sort_by_key(|&(timestamp, _)| timestamp);
+        found.into_iter().map(|(_, x)| x).collect()
+    }
+
+    /// The message kept at `at`, if it's still there.
+    pub fn message(&self, at: MessageRef) -> Option<&ChatMessage> {
+        match at {
+            MessageRef::Store(seq) => self.store.get(seq),
+            MessageRef::Whisper(idx, seq) => self.conversations.get(idx).and_then(|x| x.get(seq)),
+        }
+    }
+
+    /// Changes whenever a message is added, or the history is cleared, restored or pruned. Search
+    /// results only need redoing when it does.
+    pub fn revision(&self) -> (MessageSeq, MessageSeq, u64) {
+        (self.store.end_seq(), self.visible_from, self.conversations.pushed())
+    }
+
+    /// The name of everyone who sent a message we still hold, sorted and without duplicates.
+    pub fn known_senders(&self) -> Vec<String> {
+        let partners = self.conversations.iter().map(|x| x.partner.as_str());
+        let senders: BTreeSet<&str> = self.store
+            .iter_from(self.store.first_seq())
+            .filter_map(|(_, msg)| msg.sender.as_ref().map(|x| x.as_str()))
+            .chain(partners)
+            .collect();
+        senders.into_iter().map(|x| x.to_owned()).collect()
+    }
+
+    /// Number of rows in the recent history of a channel, see `iter_channel_rows`.
+    pub fn channel_len(&self, id: ChannelId) -> usize {
+        if !self.reveal_hidden && self.filter.is_muted(id) {
+            return 0;
+        }
+        self.store.channel_len_from(id, self.visible_from)
     }
 }
 
 pub struct ChatHistoryIterator<'a> {
-    data: &'a Vec<ChatMessage>,
-    pos: usize,
+    history: &'a ChatHistory,
+    inner: StoreIter<'a>,
+
+    // Whether to yield the recent history or the backup, None yields both.
+    visible: Option<bool>,
 }
 
-impl<'a> ChatHistoryIterator<'a> {
-    pub fn new(data: &'a Vec<ChatMessage>) -> ChatHistoryIterator<'a> {
-        ChatHistoryIterator {
-            data: data,
-            pos: 0,
+impl<'a> Iterator for ChatHistoryIterator<'a> {
+    type Item = &'a ChatMessage;
+    fn next(&mut self) -> Option<&'a ChatMessage> {
+        while let Some((seq, msg)) = self.inner.next() {
+            let wanted = self.visible.map_or(true, |visible| {
+                self.history.is_visible(seq, msg) == visible
+            });
+            if wanted {
+                return Some(msg);
+            }
         }
+        None
     }
 }
 
-impl<'a> Iterator for ChatHistoryIterator<'a> {
+pub struct ChatChannelRowIterator<'a> {
+    // One for each channel, merged by sequence number.
+    inner: Vec<Peekable<ChannelIter<'a>>>,
+}
+
+impl<'a> Iterator for ChatChannelRowIterator<'a> {
     type Item = &'a ChatMessage;
     fn next(&mut self) -> Option<&'a ChatMessage> {
-        let pos = self.pos;
-        self.pos += 1;
-        self.data.iter().nth(pos)
+        let mut oldest: Option<(usize, MessageSeq)> = None;
+        for (idx, iter) in self.inner.iter_mut().enumerate() {
+            if let Some(&(seq, _)) = iter.peek() {
+                if oldest.map_or(true, |(_, oldest_seq)| seq < oldest_seq) {
+                    oldest = Some((idx, seq));
+                }
+            }
+        }
+        match oldest {
+            Some((idx, _)) => self.inner[idx].next().map(|(_, msg)| msg),
+            None => None,
+        }
+    }
+}
+
+pub struct ChatConversationIterator<'a> {
+    history: &'a ChatHistory,
+    // None when there is no such conversation.
+    inner: Option<vec_deque::Iter<'a, ChatMessage>>,
+}
+
+impl<'a> Iterator for ChatConversationIterator<'a> {
+    type Item = &'a ChatMessage;
+    fn next(&mut self) -> Option<&'a ChatMessage> {
+        let inner = match self.inner {
+            Some(ref mut inner) => inner,
+            None => return None,
+        };
+        while let Some(msg) = inner.next() {
+            if !self.history.is_hidden(msg) {
+                return Some(msg);
+            }
+        }
+        None
+    }
+}
+
+pub struct ChatWhisperIterator<'a> {
+    conversations: slice::Iter<'a, Conversation>,
+    // The conversation being visited, None before the first.
+    inner: Option<vec_deque::Iter<'a, ChatMessage>>,
+}
+
+impl<'a> Iterator for ChatWhisperIterator<'a> {
+    type Item = &'a ChatMessage;
+    fn next(&mut self) -> Option<&'a ChatMessage> {
+        loop {
+            if let Some(ref mut inner) = self.inner {
+                if let Some(msg) = inner.next() {
+                    return Some(msg);
+                }
+            }
+            match self.conversations.next() {
+                Some(conversation) => self.inner = Some(conversation.iter()),
+                None => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::path::PathBuf;
+
+    const GENERAL: ChannelId = ChannelId(1);
+
+    fn history() -> ChatHistory {
+        let mut history = ChatHistory::new();
+        history.add_channel(DEFAULT_CHANNEL, "System", [1.0, 1.0, 1.0, 1.0]);
+        history.add_channel(GENERAL, "General", [1.0, 1.0, 1.0, 1.0]);
+        history.set_highlight_rules(HighlightRules {
+            own_name: Some("Adventurer".to_owned()),
+            keywords: vec![],
+            whispers: true,
+        });
+        history
+    }
+
+    fn message(sender: &str, text: &str, timestamp: Timestamp) -> ChatMessage {
+        ChatMessage::new(text, GENERAL)
+            .with_kind(MessageKind::Player)
+            .with_sender(sender)
+            .with_timestamp(timestamp)
+    }
+
+    // A path in the temp directory with nothing there yet.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("softland-chat-history-{}.toml", name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn texts<'a, I: Iterator<Item = &'a ChatMessage>>(iter: I) -> Vec<String> {
+        iter.map(|x| x.text.to_string()).collect()
+    }
+
+    #[test]
+    fn unread_counts_messages_and_highlights() {
+        let mut history = history();
+        history.send_message(message("Bob", "hello", 10));
+        history.send_message(message("Carol", "hi adventurer", 20));
+        history.send_message(message("Dave", "anyone there?", 30));
+        assert_eq!(history.unread(GENERAL),
+                   Unread {
+                       count: 3,
+                       highlights: 1,
+                       last_highlight: 20,
+                   });
+        assert!(history.unread(DEFAULT_CHANNEL).is_empty());
+
+        history.mark_read(GENERAL);
+        assert!(history.unread(GENERAL).is_empty());
+    }
+
+    #[test]
+    fn own_and_hidden_messages_are_not_unread() {
+        let mut history = history();
+        history.ignore_sender("Bob");
+        history.send_message(message("adventurer", "hello", 10));
+        history.send_message(message("Bob", "hi adventurer", 20));
+        assert!(history.unread(GENERAL).is_empty());
+    }
+
+    #[test]
+    fn incoming_whispers_are_unread_in_their_conversation() {
+        let mut history = history();
+        let incoming = message("Bob", "psst", 10).with_kind(MessageKind::Whisper);
+        let outgoing = message("Adventurer", "what?", 20)
+            .with_kind(MessageKind::Whisper)
+            .with_recipient("Bob");
+        history.send_message(incoming);
+        history.send_message(outgoing);
+
+        assert!(history.unread(GENERAL).is_empty());
+        let conversation = history.conversation("bob").unwrap();
+        assert!(conversation.open);
+        assert_eq!(conversation.unread.count, 1);
+        assert_eq!(conversation.unread.highlights, 1);
+        assert_eq!(history.last_whisperer(), Some("Bob"));
+        assert_eq!(texts(history.iter_conversation("Bob")), vec!["psst", "what?"]);
+    }
+
+    #[test]
+    fn only_our_own_rejected_messages_are_reported() {
+        let mut history = history();
+        for idx in 0..10 {
+            history.send_message(message("Bob", &format!("spam {}", idx), idx));
+        }
+        assert_eq!(history.iter_channel_rows(&[GENERAL]).count(), 5);
+        assert!(history.iter_channel_rows(&[DEFAULT_CHANNEL]).next().is_none());
+
+        for idx in 0..6 {
+            history.send_message(message("Adventurer", &format!("hi {}", idx), 0));
+        }
+        assert_eq!(texts(history.iter_channel_rows(&[DEFAULT_CHANNEL])),
+                   vec!["Your message to General wasn't sent, you're sending messages too fast, \
+                         wait 10.0 seconds."]);
+    }
+
+    #[test]
+    fn channel_rows_merge_in_order() {
+        let system = |mut msg: ChatMessage| {
+            msg.channel_id = DEFAULT_CHANNEL;
+            msg
+        };
+        let mut history = history();
+        history.send_message(message("Bob", "a", 0));
+        history.send_message(system(message("Bob", "b", 1)));
+        history.send_message(message("Carol", "c", 2));
+        history.send_message(system(message("Carol", "d", 3)));
+        history.ignore_sender("Carol");
+
+        // Hidden messages are still rows, muted channels aren't.
+        let rows = texts(history.iter_channel_rows(&[DEFAULT_CHANNEL, GENERAL]));
+        assert_eq!(rows, vec!["a", "b", "c", "d"]);
+        assert_eq!(history.channel_len(GENERAL) + history.channel_len(DEFAULT_CHANNEL), 4);
+        history.set_channel_muted(DEFAULT_CHANNEL, true);
+        assert_eq!(texts(history.iter_channel_rows(&[DEFAULT_CHANNEL, GENERAL])), vec!["a", "c"]);
+        assert_eq!(history.channel_len(DEFAULT_CHANNEL), 0);
+        history.set_channel_muted(DEFAULT_CHANNEL, false);
+
+        history.clear();
+        history.send_message(message("Bob", "e", 4));
+        assert_eq!(texts(history.iter_channel_rows(&[DEFAULT_CHANNEL, GENERAL])), vec!["e"]);
+        assert_eq!(history.channel_len(GENERAL), 1);
+        assert_eq!(history.channel_len(DEFAULT_CHANNEL), 0);
+    }
+
+    #[test]
+    fn clear_restore_and_prune_move_the_backup_boundary() {
+        let mut history = history();
+        for (idx, text) in ["a", "b", "c"].iter().enumerate() {
+            history.send_message(message("Bob", text, idx as Timestamp));
+        }
+        history.clear();
+        history.send_message(message("Bob", "d", 3));
+        assert_eq!(texts(history.iter_history()), vec!["d"]);
+        assert_eq!(texts(history.iter_backup()), vec!["a", "b", "c"]);
+
+        history.restore();
+        assert_eq!(texts(history.iter_history()), vec!["a", "b", "c", "d"]);
+        assert!(history.iter_backup().next().is_none());
+
+        history.set_prune(true, 2);
+        history.prune();
+        assert_eq!(texts(history.iter_history()), vec!["c", "d"]);
+        assert_eq!(texts(history.iter_backup()), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn saved_history_loads_back() {
+        let path = temp_path("save");
+        let mut history = history();
+        history.send_message(message("Bob", "old", 10));
+        history.clear();
+        history.send_message(message("Bob", "new", 20));
+        history.send_message(message("Bob", "psst", 30).with_kind(MessageKind::Whisper));
+        history.set_prune(true, 10);
+        history.rename_channel(GENERAL, "Trade");
+        history.save(&path).unwrap();
+
+        let loaded = ChatHistory::load(&path).unwrap();
+        let names: Vec<String> = loaded.channels().iter().map(|x| x.name.to_string()).collect();
+        assert_eq!(names, vec!["System", "Trade"]);
+        assert_eq!(texts(loaded.iter_backup()), vec!["old"]);
+        assert_eq!(texts(loaded.iter_history()), vec!["new"]);
+        assert_eq!(texts(loaded.iter_conversation("Bob")), vec!["psst"]);
+        let msg = loaded.iter_history().next().unwrap();
+        assert_eq!((msg.kind, msg.sender.as_ref().map(|x| x.as_str()), msg.timestamp),
+                   (MessageKind::Player, Some("Bob"), 20));
+        assert_eq!((loaded.get_prune().enabled, loaded.get_prune().length), (true, 10));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_log_appends_what_is_sent() {
+        let path = temp_path("open-log");
+        let mut history = ChatHistory::new();
+        history.open_log(&path).unwrap();
+        history.add_channel(DEFAULT_CHANNEL, "System", [1.0, 1.0, 1.0, 1.0]);
+        history.add_channel(GENERAL, "General", [1.0, 1.0, 1.0, 1.0]);
+        history.send_message(message("Bob", "hello", 10));
+
+        let loaded = ChatHistory::load(&path).unwrap();
+        assert_eq!(loaded.channels().len(), 2);
+        assert_eq!(texts(loaded.iter_history()), vec!["hello"]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn old_messages_load_as_system_messages() {
+        let path = temp_path("version-1");
+        File::create(&path)
+            .unwrap()
+            .write_all(b"version = 1\n\n[[channel]]\nid = 0\nname = \"System\"\n\
+                         text_color = [1.0, 1.0, 1.0, 1.0]\n\n\
+                         [[message]]\nchannel = 0\ntext = \"hello\"\n")
+            .unwrap();
+
+        let loaded = ChatHistory::load(&path).unwrap();
+        let msg = loaded.iter_history().next().unwrap();
+        assert_eq!(msg.text.as_str(), "hello");
+        assert_eq!(msg.kind, MessageKind::System);
+        assert!(msg.sender.is_none());
+        fs::remove_file(&path).unwrap();
     }
 }
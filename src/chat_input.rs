@@ -0,0 +1,190 @@
+use std::collections::VecDeque;
+
+/// How many lines of chat input are remembered for recall.
+pub const DEFAULT_INPUT_HISTORY_LENGTH: usize = 100;
+
+/// Lines typed into the chat input this session, recalled with the Up and Down keys.
+#[derive(Debug)]
+pub struct InputHistory {
+    entries: VecDeque<String>,
+    capacity: usize,
+
+    // The entry being shown while browsing, None when editing a fresh line.
+    position: Option<usize>,
+
+    // What was typed before browsing started, brought back when browsing past the newest entry.
+    draft: String,
+}
+
+impl InputHistory {
+    pub fn with_capacity(capacity: usize) -> InputHistory {
+        InputHistory {
+            entries: VecDeque::new(),
+            capacity: max!(capacity, 1),
+            position: None,
+            draft: String::new(),
+        }
+    }
+
+    /// Remember a line that was just entered, and stop browsing.
+    pub fn push(&mut self, line: &str) {
+        self.reset();
+        if line.is_empty() || self.entries.back().map_or(false, |x| x == line) {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(line.to_owned());
+    }
+
+    /// Step back to an older entry. `current` is what the input holds right now, it is kept so
+    /// browsing forward again can restore it.
+    pub fn previous(&mut self, current: &str) -> Option<&str> {
+        let position = match self.position {
+            None if self.entries.is_empty() => return None,
+            None => {
+                self.draft = current.to_owned();
+                self.entries.len() - 1
+            }
+            Some(0) => return None,
+            Some(position) => position - 1,
+        };
+        self.position = Some(position);
+        Some(&self.entries[position])
+    }
+
+    /// Step forward to a newer entry, ending with the line that was being typed before browsing.
+    pub fn next(&mut self) -> Option<&str> {
+        match self.position {
+            None => None,
+            Some(position) if position + 1 < self.entries.len() => {
+                self.position = Some(position + 1);
+                Some(&self.entries[position + 1])
+            }
+            Some(_) => {
+                self.position = None;
+                Some(&self.draft)
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.position = None;
+        self.draft.clear();
+    }
+}
+
+/// The result of completing the word in front of the cursor.
+#[derive(Debug)]
+pub struct Completion {
+    // Byte offset where the completed word starts in the input.
+    pub start: usize,
+    // What replaces the word.
+    pub text: String,
+    // Every candidate the word matched, sorted.
+    pub candidates: Vec<String>,
+}
+
+// Longest prefix shared by all `words`, ignoring case. Keeps the case of the first word.
+fn common_prefix(words: &[String]) -> &str {
+    let first = match words.first() {
+        Some(first) => first,
+        None => return "",
+    };
+    let mut len = first.len();
+    for word in &words[1..] {
+        let shared: usize = first.chars()
+            .zip(word.chars())
+            .take_while(|&(a, b)| a.to_lowercase().eq(b.to_lowercase()))
+            .map(|(a, _)| a.len_utf8())
+            .sum();
+        len = min!(len, shared);
+    }
+    &first[..len]
+}
+
+/// Complete the word ending at byte offset `cursor` in `input`.
+///
+/// A leading word starting with '/' is completed from `commands` (given without the slash),
+/// anything else from `words`. Returns None when nothing matches.
+pub fn complete(input: &str,
+                cursor: usize,
+                commands: &[&str],
+                words: &[String])
+                -> Option<Completion> {
+    let cursor = min!(cursor, input.len());
+    let before = &input[..cursor];
+    let start = before.char_indices()
+        .rev()
+        .find(|&(_, c)| c.is_whitespace())
+        .map_or(0, |(pos, c)| pos + c.len_utf8());
+    let word = &before[start..];
+    if word.is_empty() {
+        return None;
+    }
+
+    let word_lower = word.to_lowercase();
+    let mut candidates: Vec<String> = if start == 0 && word.starts_with('/') {
+        commands.iter().map(|x| format!("/{}", x)).collect()
+    } else {
+        words.to_vec()
+    };
+    candidates.retain(|x| x.to_lowercase().starts_with(&word_lower));
+    candidates.sort();
+    candidates.dedup();
+
+    let text = match candidates.len() {
+        0 => return None,
+        1 => format!("{} ", candidates[0]),
+        _ => {
+            // Only complete as far as the candidates agree, never shorten what was typed.
+            let prefix = common_prefix(&candidates);
+            if prefix.len() > word.len() {
+                prefix.to_owned()
+            } else {
+                word.to_owned()
+            }
+        }
+    };
+    Some(Completion {
+        start: start,
+        text: text,
+        candidates: candidates,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(words: &[&str]) -> Vec<String> {
+        words.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn single_candidate_completes_with_a_space() {
+        let completion = complete("hi bo", 5, &[], &words(&["Bob", "Carol"])).unwrap();
+        assert_eq!(completion.start, 3);
+        assert_eq!(completion.text, "Bob ");
+        assert_eq!(completion.candidates, vec!["Bob"]);
+    }
+
+    #[test]
+    fn several_candidates_complete_their_common_prefix() {
+        let completion = complete("/w", 2, &["whisper", "who"], &[]).unwrap();
+        assert_eq!(completion.text, "/wh");
+        assert_eq!(completion.candidates, vec!["/whisper", "/who"]);
+
+        // Nothing more is shared, so what was typed is kept.
+        let completion = complete("/wh", 3, &["whisper", "who"], &[]).unwrap();
+        assert_eq!(completion.text, "/wh");
+    }
+
+    #[test]
+    fn commands_only_complete_the_first_word() {
+        let commands = ["whisper"];
+        assert!(complete("hi /w", 5, &commands, &[]).is_none());
+        assert!(complete("hi ", 3, &commands, &words(&["Bob"])).is_none());
+    }
+}
@@ -0,0 +1,372 @@
+use chat_font::ChatFont;
+use chat_history::{now, Channel, ChannelId, Timestamp, DEFAULT_CHANNEL};
+use settings::{ChatTabRecord, ChatWindowRecord};
+
+/// A tab of a chat window, showing the messages of the channels it subscribes to.
+#[derive(Debug, Clone)]
+pub struct ChatTab {
+    pub name: String,
+    pub channels: Vec<ChannelId>,
+
+    // Subscribe to every channel, including ones created later. `channels` is ignored while set.
+    pub all_channels: bool,
+
+    // Where the chat input sends to while the tab is selected.
+    pub send_channel: ChannelId,
+}
+
+impl ChatTab {
+    pub fn new(name: &str, channels: &[ChannelId]) -> ChatTab {
+        ChatTab {
+            name: name.to_owned(),
+            channels: channels.to_vec(),
+            all_channels: false,
+            send_channel: channels.first().cloned().unwrap_or(DEFAULT_CHANNEL),
+        }
+    }
+
+    /// A tab subscribed to every channel.
+    pub fn all(name: &str) -> ChatTab {
+        ChatTab { all_channels: true, ..ChatTab::new(name, &[DEFAULT_CHANNEL]) }
+    }
+
+    pub fn is_subscribed(&self, id: ChannelId) -> bool {
+        self.all_channels || self.channels.contains(&id)
+    }
+
+    /// Subscribe or unsubscribe a single channel. Unsubscribing from a tab subscribed to every
+    /// channel turns it into a tab subscribed to every other channel in `channels`.
+    pub fn set_subscribed(&mut self, id: ChannelId, subscribed: bool, channels: &[Channel]) {
+        if self.all_channels {
+            if subscribed {
+                return;
+            }
+            self.all_channels = false;
+            self.channels = channels.iter().map(|x| x.id).collect();
+        }
+        if subscribed && !self.channels.contains(&id) {
+            self.channels.push(id);
+        } else if !subscribed {
+            self.channels.retain(|&x| x != id);
+        }
+    }
+
+    /// The ids of the channels in `channels` the tab shows, in display order.
+    pub fn channel_ids(&self, channels: &[Channel]) -> Vec<ChannelId> {
+        channels.iter().map(|x| x.id).filter(|&id| self.is_subscribed(id)).collect()
+    }
+
+    fn to_record(&self) -> ChatTabRecord {
+        ChatTabRecord {
+            name: self.name.clone(),
+            channels: self.channels.iter().map(|x| x.index()).collect(),
+            all_channels: self.all_channels,
+            send_channel: self.send_channel.index(),
+        }
+    }
+
+    fn from_record(record: &ChatTabRecord) -> ChatTab {
+        ChatTab {
+            name: record.name.clone(),
+            channels: record.channels.iter().map(|&x| ChannelId::new(x)).collect(),
+            all_channels: record.all_channels,
+            send_channel: ChannelId::new(record.send_channel),
+        }
+    }
+}
+
+pub type ChatWindowId = usize;
+
+#[derive(Debug, Clone)]
+pub struct ChatWindow {
+    pub id: ChatWindowId,
+    pub name: String,
+    pub tabs: Vec<ChatTab>,
+    pub active_tab: usize,
+
+    // The whisper conversation shown instead of the active tab, by partner. Conversations are
+    // temporary and aren't saved with the layout.
+    pub conversation: Option<String>,
+
+    // The font the window is drawn in, None uses imgui's default font.
+    pub font: Option<ChatFont>,
+
+    // Where the user moved the window to and what they resized it to. None places the window
+    // relative to the screen, at the default size.
+    pub pos: Option<(f32, f32)>,
+    pub size: Option<(f32, f32)>,
+
+    // When the window was last focused or hovered, messages fade out counting from here.
+    pub last_active: Timestamp,
+}
+
+impl ChatWindow {
+    pub fn active_tab(&self) -> Option<&ChatTab> {
+        self.tabs.get(self.active_tab)
+    }
+}
+
+/// The chat windows on screen and the tabs in each of them.
+#[derive(Debug)]
+pub struct ChatLayout {
+    windows: Vec<ChatWindow>,
+    next_window_id: ChatWindowId,
+
+    // The window the chat input line is drawn in, the one whose tab was selected last.
+    input_window: ChatWindowId,
+}
+
+impl ChatLayout {
+    pub fn new() -> ChatLayout {
+        ChatLayout {
+            windows: vec![],
+            next_window_id: 0,
+            input_window: 0,
+        }
+    }
+
+    /// A single window with a tab showing every channel, followed by a tab for each channel
+    /// except the first.
+    pub fn with_default_tabs(channels: &[Channel]) -> ChatLayout {
+        let mut layout = ChatLayout::new();
+        let mut tabs = vec![];
+        let mut channels = channels.iter();
+        if let Some(first) = channels.next() {
+            let mut tab = ChatTab::all(&first.name);
+            tab.send_channel = first.id;
+            tabs.push(tab);
+        }
+        for channel in channels {
+            tabs.push(ChatTab::new(&channel.name, &[channel.id]));
+        }
+        layout.add_window("Chat", tabs);
+        layout
+    }
+
+    pub fn windows(&self) -> &[ChatWindow] {
+        &self.windows
+    }
+
+    pub fn window(&self, id: ChatWindowId) -> Option<&ChatWindow> {
+        self.windows.iter().find(|x| x.id == id)
+    }
+
+    pub fn window_mut(&mut self, id: ChatWindowId) -> Option<&mut ChatWindow> {
+        self.windows.iter_mut().find(|x| x.id == id)
+    }
+
+    /// Add a window, a window without tabs gets a tab showing every channel.
+    pub fn add_window(&mut self, name: &str, mut tabs: Vec<ChatTab>) -> ChatWindowId {
+        if tabs.is_empty() {
+            tabs.push(ChatTab::all(name));
+        }
+        let id = self.next_window_id;
+        self.next_window_id += 1;
+        self.windows.push(ChatWindow {
+            id: id,
+            name: name.to_owned(),
+            tabs: tabs,
+            active_tab: 0,
+            conversation: None,
+            font: None,
+            pos: None,
+            size: None,
+            last_active: now(),
+        });
+        if self.windows.len() == 1 {
+            self.input_window = id;
+        }
+        id
+    }
+
+    /// Remove a window. The last window can't be removed.
+    pub fn remove_window(&mut self, id: ChatWindowId) -> bool {
+        if self.windows.len() <= 1 {
+            return false;
+        }
+        let pos = match self.windows.iter().position(|x| x.id == id) {
+            Some(pos) => pos,
+            None => return false,
+        };
+        self.windows.remove(pos);
+        if self.input_window == id {
+            self.input_window = self.windows[0].id;
+        }
+        true
+    }
+
+    /// Add a tab to a window, returning its index.
+    pub fn add_tab(&mut self, id: ChatWindowId, tab: ChatTab) -> Option<usize> {
+        self.window_mut(id).map(|window| {
+            window.tabs.push(tab);
+            window.tabs.len() - 1
+        })
+    }
+
+    /// Remove a tab from a window. A window's last tab can't be removed.
+    pub fn remove_tab(&mut self, id: ChatWindowId, index: usize) -> bool {
+        let window = match self.window_mut(id) {
+            Some(window) => window,
+            None => return false,
+        };
+        if window.tabs.len() <= 1 || index >= window.tabs.len() {
+            return false;
+        }
+        window.tabs.remove(index);
+        if window.active_tab >= window.tabs.len() {
+            window.active_tab = window.tabs.len() - 1;
+        }
+        true
+    }
+
+    /// Select a tab, and move the chat input to its window.
+    pub fn select_tab(&mut self, id: ChatWindowId, index: usize) {
+        let mut selected = false;
+        if let Some(window) = self.window_mut(id) {
+            if index < window.tabs.len() {
+                window.active_tab = index;
+                window.conversation = None;
+                selected = true;
+            }
+        }
+        if selected {
+            self.input_window = id;
+        }
+    }
+
+    /// Show the whisper conversation with `partner` in a window, and move the chat input there.
+    pub fn select_conversation(&mut self, id: ChatWindowId, partner: &str) {
+        let mut selected = false;
+        if let Some(window) = self.window_mut(id) {
+            window.conversation = Some(partner.to_owned());
+            selected = true;
+        }
+        if selected {
+            self.input_window = id;
+        }
+    }
+
+    /// Go back to the active tab of every window showing the conversation with `partner`.
+    pub fn close_conversation(&mut self, partner: &str) {
+        for window in &mut self.windows {
+            if window.conversation.as_ref().map_or(false, |x| x == partner) {
+                window.conversation = None;
+            }
+        }
+    }
+
+    pub fn input_window(&self) -> ChatWindowId {
+        self.input_window
+    }
+
+    /// The selected tab of the window holding the chat input.
+    pub fn input_tab(&self) -> Option<&ChatTab> {
+        self.window(self.input_window).and_then(|x| x.active_tab())
+    }
+
+    /// Who the chat input whispers to, when the window holding it shows a conversation.
+    pub fn input_conversation(&self) -> Option<&str> {
+        self.window(self.input_window)
+            .and_then(|x| x.conversation.as_ref())
+            .map(|x| x.as_str())
+    }
+
+    pub fn to_records(&self) -> Vec<ChatWindowRecord> {
+        self.windows
+            .iter()
+            .map(|window| {
+                ChatWindowRecord {
+                    name: window.name.clone(),
+                    active_tab: window.active_tab,
+                    pos: window.pos,
+                    size: window.size,
+                    font: window.font.clone(),
+                    tab: window.tabs.iter().map(|x| x.to_record()).collect(),
+                }
+            })
+            .collect()
+    }
+
+    pub fn from_records(records: &[ChatWindowRecord]) -> ChatLayout {
+        let mut layout = ChatLayout::new();
+        for record in records {
+            let tabs = record.tab.iter().map(ChatTab::from_record).collect();
+            let id = layout.add_window(&record.name, tabs);
+            if let Some(window) = layout.window_mut(id) {
+                window.active_tab = min!(record.active_tab, window.tabs.len() - 1);
+                window.font = record.font.clone();
+                window.pos = record.pos;
+                window.size = record.size;
+            }
+        }
+        layout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tab(name: &str, channels: &[usize], send_channel: usize) -> ChatTabRecord {
+        ChatTabRecord {
+            name: name.to_owned(),
+            channels: channels.to_vec(),
+            all_channels: false,
+            send_channel: send_channel,
+        }
+    }
+
+    fn records() -> Vec<ChatWindowRecord> {
+        vec![ChatWindowRecord {
+                 name: "Chat".to_owned(),
+                 active_tab: 1,
+                 pos: Some((10.0, 20.0)),
+                 size: Some((300.0, 200.0)),
+                 font: Some(ChatFont {
+                     name: "mono".to_owned(),
+                     size: 14,
+                 }),
+                 tab: vec![ChatTabRecord { all_channels: true, ..tab("All", &[0], 0) },
+                           tab("Trade", &[2, 3], 3)],
+             },
+             ChatWindowRecord {
+                 name: "Combat".to_owned(),
+                 active_tab: 0,
+                 pos: None,
+                 size: None,
+                 font: None,
+                 tab: vec![tab("Combat", &[4], 4)],
+             }]
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let records = records();
+        let layout = ChatLayout::from_records(&records);
+        assert_eq!(layout.to_records(), records);
+
+        let window = &layout.windows()[0];
+        assert_eq!(window.active_tab().map(|x| x.name.as_str()), Some("Trade"));
+        assert!(window.tabs[0].all_channels);
+        assert_eq!(window.tabs[1].channels, vec![ChannelId::new(2), ChannelId::new(3)]);
+        assert_eq!(window.tabs[1].send_channel, ChannelId::new(3));
+        assert_eq!(layout.input_window(), window.id);
+    }
+
+    #[test]
+    fn broken_records_still_give_a_usable_layout() {
+        let records = vec![ChatWindowRecord {
+                               name: "Empty".to_owned(),
+                               active_tab: 5,
+                               pos: None,
+                               size: None,
+                               font: None,
+                               tab: vec![],
+                           }];
+        let layout = ChatLayout::from_records(&records);
+        let window = &layout.windows()[0];
+        assert_eq!(window.tabs.len(), 1);
+        assert!(window.tabs[0].all_channels);
+        assert_eq!(window.active_tab, 0);
+    }
+}
@@ -0,0 +1,176 @@
+use chat_history::{Channel, ChatMessage};
+
+use std::fmt;
+
+/// Whether a message is added to the history.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Verdict {
+    Keep,
+    Veto,
+}
+
+/// What happened to a channel.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ChannelChange {
+    Added,
+    Removed,
+    Renamed,
+    Moved,
+}
+
+/// Game code reacting to chat, such as an auto-responder, a bridge to another log or a quest
+/// trigger. Every method does nothing by default.
+///
+/// The chat history is part of the game state, which the world keeps as a resource, so listeners
+/// have to be `Send + Sync` too.
+pub trait ChatListener: Send + Sync {
+    /// Called for every message sent, before it is added to the history. The message may be
+    /// rewritten in place, and answered by pushing to `replies`. Replies are added right after the
+    /// message without being shown to any listener, so listeners can't set each other off.
+    fn on_message(&mut self, _msg: &mut ChatMessage, _replies: &mut Vec<ChatMessage>) -> Verdict {
+        Verdict::Keep
+    }
+
+    /// Called after pruning moved `count` messages into the history backup.
+    fn on_prune(&mut self, _count: usize) {}
+
+    fn on_channel(&mut self, _channel: &Channel, _change: ChannelChange) {}
+}
+
+/// The listeners registered with a chat history, called in the order they were added.
+pub struct Listeners {
+    listeners: Vec<Box<ChatListener>>,
+}
+
+impl Listeners {
+    pub fn new() -> Listeners {
+        Listeners { listeners: vec![] }
+    }
+
+    pub fn add(&mut self, listener: Box<ChatListener>) {
+        self.listeners.push(listener);
+    }
+
+    /// Stops at the first listener to veto the message, the ones after it never see it.
+    pub fn message(&mut self, msg: &mut ChatMessage, replies: &mut Vec<ChatMessage>) -> Verdict {
+        for listener in &mut self.listeners {
+            if listener.on_message(msg, replies) == Verdict::Veto {
+                return Verdict::Veto;
+            }
+        }
+        Verdict::Keep
+    }
+
+    pub fn prune(&mut self, count: usize) {
+        for listener in &mut self.listeners {
+            listener.on_prune(count);
+        }
+    }
+
+    pub fn channel(&mut self, channel: &Channel, change: ChannelChange) {
+        for listener in &mut self.listeners {
+            listener.on_channel(channel, change);
+        }
+    }
+}
+
+impl fmt::Debug for Listeners {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Listeners({})", self.listeners.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_history::{ChatHistory, DEFAULT_CHANNEL, MessageKind};
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Answers any message that is exactly `trigger`, ignoring case, in the same channel.
+    struct AutoResponder {
+        name: String,
+        trigger: String,
+        response: String,
+    }
+
+    impl AutoResponder {
+        fn new(name: &str, trigger: &str, response: &str) -> AutoResponder {
+            AutoResponder {
+                name: name.to_owned(),
+                trigger: trigger.to_lowercase(),
+                response: response.to_owned(),
+            }
+        }
+    }
+
+    impl ChatListener for AutoResponder {
+        fn on_message(&mut self,
+                      msg: &mut ChatMessage,
+                      replies: &mut Vec<ChatMessage>)
+                      -> Verdict {
+            if msg.kind == MessageKind::Player && msg.text.trim().to_lowercase() == self.trigger {
+                let reply = ChatMessage::new(self.response.as_str(), msg.channel_id)
+                    .with_kind(MessageKind::Player)
+                    .with_sender(&self.name);
+                replies.push(reply);
+            }
+            Verdict::Keep
+        }
+    }
+
+    // Vetoes every message.
+    struct Censor;
+
+    impl ChatListener for Censor {
+        fn on_message(&mut self, _: &mut ChatMessage, _: &mut Vec<ChatMessage>) -> Verdict {
+            Verdict::Veto
+        }
+    }
+
+    // Counts the messages it's shown.
+    struct Counter(Arc<AtomicUsize>);
+
+    impl ChatListener for Counter {
+        fn on_message(&mut self, _: &mut ChatMessage, _: &mut Vec<ChatMessage>) -> Verdict {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Verdict::Keep
+        }
+    }
+
+    fn history() -> ChatHistory {
+        let mut history = ChatHistory::new();
+        history.add_channel(DEFAULT_CHANNEL, "General", [1.0, 1.0, 1.0, 1.0]);
+        history
+    }
+
+    #[test]
+    fn replies_follow_the_message_unseen_by_listeners() {
+        let mut history = history();
+        let seen = Arc::new(AtomicUsize::new(0));
+        history.add_listener(Box::new(AutoResponder::new("Turnshroom", "!rules", "Be nice.")));
+        history.add_listener(Box::new(Counter(seen.clone())));
+        history.send_player_message(DEFAULT_CHANNEL, "Bob", "!RULES");
+
+        let sent: Vec<(Option<String>, String)> = history.iter_history()
+            .map(|x| (x.sender.clone(), x.text.to_string()))
+            .collect();
+        assert_eq!(sent,
+                   vec![(Some("Bob".to_owned()), "!RULES".to_owned()),
+                        (Some("Turnshroom".to_owned()), "Be nice.".to_owned())]);
+        assert_eq!(seen.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn a_veto_drops_the_message_before_later_listeners() {
+        let mut history = history();
+        let seen = Arc::new(AtomicUsize::new(0));
+        history.add_listener(Box::new(Censor));
+        history.add_listener(Box::new(Counter(seen.clone())));
+        history.send_player_message(DEFAULT_CHANNEL, "Bob", "hello");
+
+        assert!(history.iter_history().next().is_none());
+        assert_eq!(seen.load(Ordering::SeqCst), 0);
+    }
+}
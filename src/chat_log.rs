@@ -0,0 +1,266 @@
+use chat_font::ChatFont;
+use serde::Serialize;
+use toml;
+
+use std::error::Error;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// Version of the on-disk chat log format. Bump this whenever a record changes shape, and keep
+/// older versions loadable (new fields should be `#[serde(default)]`).
+pub const CHAT_LOG_VERSION: u32 = 5;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ChannelRecord {
+    pub id: usize,
+    pub name: String,
+    pub text_color: [f32; 4],
+
+    // Added in version 4. Removed channels are kept so their ids are never handed out again.
+    #[serde(default)]
+    pub removed: bool,
+
+    // Added in version 5. A table, so it has to stay the last field.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub font: Option<ChatFont>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MessageRecord {
+    pub channel: usize,
+    pub text: String,
+
+    // Whether the message lived in the history backup when the log was written.
+    #[serde(default)]
+    pub backup: bool,
+
+    // Added in version 2, version 1 messages load as system messages without a sender.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+    #[serde(default)]
+    pub timestamp: u64,
+
+    // Added in version 3.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PruneRecord {
+    pub length: i32,
+    pub enabled: bool,
+}
+
+/// The whole log file, as read back from disk.
+///
+/// The file is TOML where every channel and message is an entry of an array of tables. This lets
+/// the log be appended to one record at a time. A crash while appending can leave a half written
+/// record at the end of the file, `ChatLog::read` drops it, so only that record is lost. Channel
+/// records with an id seen earlier in the file replace the earlier definition.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChatLogFile {
+    pub version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prune: Option<PruneRecord>,
+    #[serde(default)]
+    pub channel: Vec<ChannelRecord>,
+    #[serde(default)]
+    pub message: Vec<MessageRecord>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChannelEntry<'a> {
+    channel: &'a [ChannelRecord],
+}
+
+#[derive(Debug, Serialize)]
+struct MessageEntry<'a> {
+    message: &'a [MessageRecord],
+}
+
+/// An append-only handle on a chat log file.
+#[derive(Debug)]
+pub struct ChatLog {
+    path: PathBuf,
+    file: File,
+}
+
+impl ChatLog {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ChatLog, Box<Error>> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if file.metadata()?.len() == 0 {
+            // The version key has to come before any table, so write it while the file is empty.
+            write!(file, "version = {}\n", CHAT_LOG_VERSION)?;
+        }
+        Ok(ChatLog {
+            path: path,
+            file: file,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append_channel(&mut self, record: ChannelRecord) -> Result<(), Box<Error>> {
+        self.append(&ChannelEntry { channel: &[record] })
+    }
+
+    pub fn append_message(&mut self, record: MessageRecord) -> Result<(), Box<Error>> {
+        self.append(&MessageEntry { message: &[record] })
+    }
+
+    fn append<T: Serialize>(&mut self, entry: &T) -> Result<(), Box<Error>> {
+        let text = toml::to_string(entry)?;
+        self.file.write_all(b"\n")?;
+        self.file.write_all(text.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<ChatLogFile, Box<Error>> {
+        let contents = {
+            let mut file = File::open(path)?;
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            contents
+        };
+        let log: ChatLogFile = match toml::from_str(&contents) {
+            Ok(log) => log,
+            Err(e) => {
+                // Every record starts on a line of its own, try again without the last one.
+                match contents.rfind("\n[[") {
+                    Some(end) => toml::from_str(&contents[..end]).map_err(|_| e)?,
+                    None => return Err(e.into()),
+                }
+            }
+        };
+        if log.version > CHAT_LOG_VERSION {
+            let msg = format!("chat log version {} is newer than the supported version {}",
+                              log.version,
+                              CHAT_LOG_VERSION);
+            return Err(msg.into());
+        }
+        Ok(log)
+    }
+
+    /// Replace the contents of the file at `path` with `log`. The log is written next to it first
+    /// and then moved over it, so a crash halfway leaves the old file in place.
+    pub fn write<P: AsRef<Path>>(path: P, log: &ChatLogFile) -> Result<(), Box<Error>> {
+        let path = path.as_ref();
+        let text = toml::to_string(log)?;
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(text.as_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // A path in the temp directory with nothing there yet.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("softland-chat-log-{}.toml", name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn write_file(path: &Path, contents: &str) {
+        File::create(path).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+
+    fn message(text: &str) -> MessageRecord {
+        MessageRecord {
+            channel: 0,
+            text: text.to_owned(),
+            backup: false,
+            kind: Some("Player".to_owned()),
+            sender: Some("Bob".to_owned()),
+            timestamp: 10,
+            recipient: None,
+        }
+    }
+
+    #[test]
+    fn appended_records_read_back() {
+        let path = temp_path("append");
+        let channel = ChannelRecord {
+            id: 0,
+            name: "System".to_owned(),
+            text_color: [1.0, 1.0, 1.0, 1.0],
+            removed: false,
+            font: None,
+        };
+        {
+            let mut log = ChatLog::open(&path).unwrap();
+            log.append_channel(channel).unwrap();
+            log.append_message(message("one")).unwrap();
+        }
+        ChatLog::open(&path).unwrap().append_message(message("two")).unwrap();
+
+        let log = ChatLog::read(&path).unwrap();
+        assert_eq!(log.version, CHAT_LOG_VERSION);
+        assert_eq!(log.channel.len(), 1);
+        let texts: Vec<&str> = log.message.iter().map(|x| x.text.as_str()).collect();
+        assert_eq!(texts, vec!["one", "two"]);
+        assert_eq!(log.message[1].sender, Some("Bob".to_owned()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn half_written_last_record_is_dropped() {
+        let path = temp_path("half-written");
+        {
+            let mut log = ChatLog::open(&path).unwrap();
+            log.append_message(message("whole")).unwrap();
+        }
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"\n[[message]]\nchannel = 0\ntext = \"half wr")
+            .unwrap();
+
+        let log = ChatLog::read(&path).unwrap();
+        let texts: Vec<&str> = log.message.iter().map(|x| x.text.as_str()).collect();
+        assert_eq!(texts, vec!["whole"]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn old_versions_load_without_the_new_fields() {
+        let path = temp_path("version-1");
+        write_file(&path,
+                   "version = 1\n\n[[channel]]\nid = 0\nname = \"System\"\n\
+                    text_color = [1.0, 1.0, 1.0, 1.0]\n\n\
+                    [[message]]\nchannel = 0\ntext = \"hello\"\n");
+
+        let log = ChatLog::read(&path).unwrap();
+        assert!(!log.channel[0].removed);
+        assert!(log.channel[0].font.is_none());
+        let msg = &log.message[0];
+        assert_eq!((msg.kind.clone(), msg.sender.clone(), msg.timestamp), (None, None, 0));
+        assert!(!msg.backup);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        let path = temp_path("newer");
+        write_file(&path, &format!("version = {}\n", CHAT_LOG_VERSION + 1));
+        assert!(ChatLog::read(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
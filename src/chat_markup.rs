@@ -0,0 +1,267 @@
+/// What clicking a link in a chat message does.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Link {
+    // Start a whisper to the player.
+    Player(String),
+    // Show the item's tooltip.
+    Item(String),
+    // Point the camera at the location.
+    Location([f32; 3]),
+}
+
+/// A run of chat text drawn in a single style.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub text: String,
+
+    // None draws the span in its channel's color.
+    pub color: Option<[f32; 4]>,
+    pub bold: bool,
+    pub link: Option<Link>,
+}
+
+impl Span {
+    pub fn plain(text: &str) -> Span {
+        Span {
+            text: text.to_owned(),
+            color: None,
+            bold: false,
+            link: None,
+        }
+    }
+
+    pub fn link(text: &str, link: Link) -> Span {
+        Span { link: Some(link), ..Span::plain(text) }
+    }
+
+    pub fn with_bold(mut self) -> Span {
+        self.bold = true;
+        self
+    }
+}
+
+// Parse "#rrggbb" or "#rrggbbaa".
+fn parse_color(text: &str) -> Option<[f32; 4]> {
+    if !text.starts_with('#') || !text[1..].chars().all(|c| c.is_digit(16)) {
+        return None;
+    }
+    let hex = &text[1..];
+    if hex.len() != 6 && hex.len() != 8 {
+        return None;
+    }
+    let mut color = [1.0; 4];
+    for (idx, component) in color.iter_mut().enumerate().take(hex.len() / 2) {
+        let byte = match u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16) {
+            Ok(byte) => byte,
+            Err(_) => return None,
+        };
+        *component = byte as f32 / 255.0;
+    }
+    Some(color)
+}
+
+// Parse "x,y,z".
+fn parse_location(text: &str) -> Option<[f32; 3]> {
+    let coords: Vec<f32> = text.split(',').filter_map(|x| x.trim().parse().ok()).collect();
+    if coords.len() != 3 || text.split(',').count() != 3 {
+        return None;
+    }
+    Some([coords[0], coords[1], coords[2]])
+}
+
+// The link a `[player]`, `[item]` or `[loc]` tag holds, and the text it's shown as.
+fn parse_link(tag: &str, inner: &str) -> Option<(String, Link)> {
+    let inner = inner.trim();
+    if inner.is_empty() {
+        return None;
+    }
+    match tag {
+        "player" if !inner.contains(char::is_whitespace) => {
+            Some((inner.to_owned(), Link::Player(inner.to_owned())))
+        }
+        "item" => Some((format!("[{}]", inner), Link::Item(inner.to_owned()))),
+        "loc" => parse_location(inner).map(|x| (format!("({})", inner), Link::Location(x))),
+        _ => None,
+    }
+}
+
+struct Parser {
+    spans: Vec<Span>,
+    // The text read since the style last changed.
+    current: String,
+    colors: Vec<[f32; 4]>,
+    bold: usize,
+}
+
+impl Parser {
+    fn span(&self, text: String, link: Option<Link>) -> Span {
+        Span {
+            text: text,
+            color: self.colors.last().cloned(),
+            bold: self.bold > 0,
+            link: link,
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.current.is_empty() {
+            let text = ::std::mem::replace(&mut self.current, String::new());
+            let span = self.span(text, None);
+            self.spans.push(span);
+        }
+    }
+
+    // Apply the tag `[tag]` followed by `after`. Returns how much of `after` the tag used up, or
+    // None if it isn't valid markup.
+    fn tag(&mut self, tag: &str, after: &str) -> Option<usize> {
+        match tag {
+            "b" => {
+                self.flush();
+                self.bold += 1;
+                Some(0)
+            }
+            "/b" if self.bold > 0 => {
+                self.flush();
+                self.bold -= 1;
+                Some(0)
+            }
+            "/color" if !self.colors.is_empty() => {
+                self.flush();
+                self.colors.pop();
+                Some(0)
+            }
+            "player" | "item" | "loc" => {
+                let end_tag = format!("[/{}]", tag);
+                let end = match after.find(&end_tag) {
+                    Some(end) => end,
+                    None => return None,
+                };
+                parse_link(tag, &after[..end]).map(|(text, link)| {
+                    self.flush();
+                    let span = self.span(text, Some(link));
+                    self.spans.push(span);
+                    end + end_tag.len()
+                })
+            }
+            _ if tag.starts_with("color=") => {
+                parse_color(&tag["color=".len()..]).map(|color| {
+                    self.flush();
+                    self.colors.push(color);
+                    0
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Split chat text into styled spans.
+///
+/// Understands `[b]bold[/b]`, `[color=#rrggbb]colored[/color]` and the links
+/// `[player]Name[/player]`, `[item]Name[/item]` and `[loc]x,y,z[/loc]`. Styles nest, links
+/// can't hold other markup. Anything that isn't valid markup, such as an unknown tag, is kept as
+/// plain text.
+pub fn parse(text: &str) -> Vec<Span> {
+    let mut parser = Parser {
+        spans: vec![],
+        current: String::new(),
+        colors: vec![],
+        bold: 0,
+    };
+    let mut rest = text;
+    while let Some(open) = rest.find('[') {
+        parser.current.push_str(&rest[..open]);
+        rest = &rest[open..];
+        let close = match rest.find(']') {
+            Some(close) => close,
+            None => break,
+        };
+        let after = &rest[close + 1..];
+        match parser.tag(&rest[1..close], after) {
+            Some(used) => rest = &after[used..],
+            None => {
+                parser.current.push('[');
+                rest = &rest[1..];
+            }
+        }
+    }
+    parser.current.push_str(rest);
+    parser.flush();
+    parser.spans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: [f32; 4] = [1.0, 0.0, 0.0, 1.0];
+
+    fn colored(text: &str, color: [f32; 4]) -> Span {
+        Span { color: Some(color), ..Span::plain(text) }
+    }
+
+    #[test]
+    fn styles_nest() {
+        assert_eq!(parse("[b]a[color=#ff0000]b[/b]c[/color]d"),
+                   vec![Span::plain("a").with_bold(),
+                        colored("b", RED).with_bold(),
+                        colored("c", RED),
+                        Span::plain("d")]);
+        assert_eq!(parse("[b][b]a[/b]b[/b]c"),
+                   vec![Span::plain("a").with_bold(),
+                        Span::plain("b").with_bold(),
+                        Span::plain("c")]);
+    }
+
+    #[test]
+    fn unclosed_styles_run_to_the_end() {
+        assert_eq!(parse("a[b]b"), vec![Span::plain("a"), Span::plain("b").with_bold()]);
+    }
+
+    #[test]
+    fn stray_closing_tags_are_text() {
+        assert_eq!(parse("a[/b]b"), vec![Span::plain("a[/b]b")]);
+        assert_eq!(parse("[/color]"), vec![Span::plain("[/color]")]);
+    }
+
+    #[test]
+    fn colors_need_six_or_eight_hex_digits() {
+        let half_red = [1.0, 0.0, 0.0, 128.0 / 255.0];
+        assert_eq!(parse("[color=#ff000080]a"), vec![colored("a", half_red)]);
+        assert_eq!(parse("[color=#ff00]a[/color]"), vec![Span::plain("[color=#ff00]a[/color]")]);
+        assert_eq!(parse("[color=#gg0000]a"), vec![Span::plain("[color=#gg0000]a")]);
+        assert_eq!(parse("[color=ff0000]a"), vec![Span::plain("[color=ff0000]a")]);
+    }
+
+    #[test]
+    fn links() {
+        assert_eq!(parse("hi [player]Bob[/player]!"),
+                   vec![Span::plain("hi "),
+                        Span::link("Bob", Link::Player("Bob".to_owned())),
+                        Span::plain("!")]);
+        assert_eq!(parse("[item]Iron Sword[/item]"),
+                   vec![Span::link("[Iron Sword]", Link::Item("Iron Sword".to_owned()))]);
+        assert_eq!(parse("[b][loc]1, 2.5, -3[/loc][/b]"),
+                   vec![Span::link("(1, 2.5, -3)", Link::Location([1.0, 2.5, -3.0])).with_bold()]);
+    }
+
+    #[test]
+    fn invalid_links_are_text() {
+        for text in &["[player]Bob",
+                      "[player]Bob Smith[/player]",
+                      "[player][/player]",
+                      "[loc]1,2[/loc]",
+                      "[loc]1,2,3,4[/loc]",
+                      "[loc]1,x,3[/loc]"] {
+            assert_eq!(parse(text), vec![Span::plain(text)]);
+        }
+    }
+
+    #[test]
+    fn unknown_tags_are_text() {
+        assert_eq!(parse("[u]a[/u]"), vec![Span::plain("[u]a[/u]")]);
+        assert_eq!(parse("a [ b"), vec![Span::plain("a [ b")]);
+        assert_eq!(parse("[]"), vec![Span::plain("[]")]);
+        assert_eq!(parse(""), vec![]);
+    }
+}
@@ -0,0 +1,130 @@
+use chat_history::{ChatMessage, MessageKind, Timestamp};
+
+/// Messages a channel received since it was last viewed.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Unread {
+    pub count: usize,
+    // How many of them matched a highlight rule.
+    pub highlights: usize,
+    // When the newest highlighted message arrived.
+    pub last_highlight: Timestamp,
+}
+
+impl Unread {
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+}
+
+/// Which messages deserve the player's attention.
+#[derive(Debug, Clone)]
+pub struct HighlightRules {
+    // The player's name. Messages mentioning it are highlighted, and the player's own messages
+    // never count as unread.
+    pub own_name: Option<String>,
+
+    // Other words that highlight a message, matched as whole words ignoring case.
+    pub keywords: Vec<String>,
+
+    // Highlight every whisper sent to the player.
+    pub whispers: bool,
+}
+
+// Whether `text` contains `word` as a whole word, ignoring case.
+fn contains_word(text: &str, word: &str) -> bool {
+    let word = word.to_lowercase();
+    !word.is_empty() &&
+    text.split(|c: char| !c.is_alphanumeric() && c != '_' && c != '\'')
+        .any(|x| x.to_lowercase() == word)
+}
+
+impl HighlightRules {
+    pub fn new() -> HighlightRules {
+        HighlightRules {
+            own_name: None,
+            keywords: vec![],
+            whispers: true,
+        }
+    }
+
+    /// Whether the player sent `msg`.
+    pub fn is_own(&self, msg: &ChatMessage) -> bool {
+        match (self.own_name.as_ref(), msg.sender.as_ref()) {
+            (Some(name), Some(sender)) => name.to_lowercase() == sender.to_lowercase(),
+            _ => false,
+        }
+    }
+
+    pub fn highlights(&self, msg: &ChatMessage) -> bool {
+        if self.is_own(msg) {
+            return false;
+        }
+        if self.whispers && msg.kind == MessageKind::Whisper && msg.recipient.is_none() {
+            return true;
+        }
+        let text = &msg.text;
+        self.own_name.iter().chain(self.keywords.iter()).any(|word| contains_word(text, word))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_history::{ChannelId, DEFAULT_CHANNEL};
+
+    fn rules() -> HighlightRules {
+        HighlightRules {
+            own_name: Some("Adventurer".to_owned()),
+            keywords: vec!["loot".to_owned()],
+            whispers: true,
+        }
+    }
+
+    fn message(sender: &str, text: &str) -> ChatMessage {
+        ChatMessage::new(text, DEFAULT_CHANNEL)
+            .with_kind(MessageKind::Player)
+            .with_sender(sender)
+    }
+
+    #[test]
+    fn words_match_whole_and_ignoring_case() {
+        assert!(contains_word("Hello ADVENTURER!", "adventurer"));
+        assert!(contains_word("adventurer's sword", "adventurer's"));
+        assert!(!contains_word("Adventurers unite", "adventurer"));
+        assert!(!contains_word("anything", ""));
+    }
+
+    #[test]
+    fn mentions_and_keywords_highlight() {
+        let rules = rules();
+        assert!(rules.highlights(&message("Bob", "hi adventurer")));
+        assert!(rules.highlights(&message("Bob", "Loot is up")));
+        assert!(!rules.highlights(&message("Bob", "looting is slow")));
+    }
+
+    #[test]
+    fn own_messages_never_highlight() {
+        let rules = rules();
+        let msg = message("adventurer", "I am the Adventurer, I want loot");
+        assert!(rules.is_own(&msg));
+        assert!(!rules.highlights(&msg));
+    }
+
+    #[test]
+    fn only_incoming_whispers_highlight() {
+        let mut rules = rules();
+        let channel = ChannelId::new(1);
+        let incoming = ChatMessage::new("psst", channel)
+            .with_kind(MessageKind::Whisper)
+            .with_sender("Bob");
+        let outgoing = ChatMessage::new("psst", channel)
+            .with_kind(MessageKind::Whisper)
+            .with_sender("Carol")
+            .with_recipient("Bob");
+        assert!(rules.highlights(&incoming));
+        assert!(!rules.highlights(&outgoing));
+
+        rules.whispers = false;
+        assert!(!rules.highlights(&incoming));
+    }
+}
@@ -0,0 +1,300 @@
+use chat_history::{ChannelId, ChatMessage, Timestamp};
+use chat_text::ChatText;
+use toml;
+
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// How fast messages may be sent to a channel. A limit of 0 turns that check off.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RateLimit {
+    // How many messages a sender may send within `window` milliseconds.
+    pub messages: usize,
+    pub window: Timestamp,
+
+    // Sending the same message again within this many milliseconds is rejected.
+    pub duplicate_window: Timestamp,
+
+    // The longest message allowed, in bytes.
+    pub max_length: usize,
+}
+
+impl Default for RateLimit {
+    fn default() -> RateLimit {
+        RateLimit {
+            messages: 5,
+            window: 10000,
+            duplicate_window: 30000,
+            max_length: 256,
+        }
+    }
+}
+
+/// Why a message was rejected.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Rejection {
+    // Too many messages within the window, the next one is allowed in `wait` milliseconds.
+    TooFast { wait: Timestamp },
+    Duplicate,
+    TooLong { length: usize, max_length: usize },
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Rejection::TooFast { wait } => {
+                let wait = wait as f64 / 1000.0;
+                write!(f, "you're sending messages too fast, wait {:.1} seconds", wait)
+            }
+            Rejection::Duplicate => write!(f, "you just sent the same message"),
+            Rejection::TooLong { length, max_length } => {
+                write!(f, "it is {} bytes long, at most {} are allowed", length, max_length)
+            }
+        }
+    }
+}
+
+/// A channel's own limit, overriding the default.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChannelRateLimit {
+    pub id: usize,
+    pub messages: usize,
+    pub window: Timestamp,
+    pub duplicate_window: Timestamp,
+    pub max_length: usize,
+}
+
+/// The rate limit file as stored on disk.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct RateLimitFile {
+    #[serde(default)]
+    pub default: RateLimit,
+    #[serde(default)]
+    pub channel: Vec<ChannelRateLimit>,
+}
+
+/// Flood protection, checks every message against its channel's rate limit. Each sender is
+/// counted separately, so one player flooding a channel doesn't silence everybody else.
+#[derive(Debug)]
+pub struct RateLimiter {
+    default: RateLimit,
+    limits: HashMap<ChannelId, RateLimit>,
+
+    // What each sender sent to each channel lately, oldest first. Senders are forgotten once
+    // nothing they sent counts against them any more.
+    recent: HashMap<(ChannelId, String), VecDeque<(Timestamp, ChatText)>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter {
+            default: RateLimit::default(),
+            limits: HashMap::new(),
+            recent: HashMap::new(),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<RateLimiter, Box<Error>> {
+        let contents = {
+            let mut file = File::open(&path)?;
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            contents
+        };
+        let file: RateLimitFile = toml::from_str(&contents)?;
+        let mut limiter = RateLimiter::new();
+        limiter.default = file.default;
+        for limit in file.channel {
+            limiter.set_limit(ChannelId::new(limit.id),
+                              RateLimit {
+                                  messages: limit.messages,
+                                  window: limit.window,
+                                  duplicate_window: limit.duplicate_window,
+                                  max_length: limit.max_length,
+                              });
+        }
+        Ok(limiter)
+    }
+
+    /// The limit channel `id` is held to.
+    pub fn limit(&self, id: ChannelId) -> RateLimit {
+        self.limits.get(&id).cloned().unwrap_or(self.default)
+    }
+
+    pub fn set_limit(&mut self, id: ChannelId, limit: RateLimit) {
+        self.limits.insert(id, limit);
+    }
+
+    /// Check whether `msg` may be sent. Messages that pass are remembered, and count against the
+    /// ones sent after them. Messages without a sender, such as the game's own, always pass.
+    pub fn check(&mut self, msg: &ChatMessage) -> Result<(), Rejection> {
+        let sender = match msg.sender {
+            Some(ref sender) => sender.clone(),
+            None => return Ok(()),
+        };
+        let limit = self.limit(msg.channel_id);
+        if limit.max_length > 0 && msg.text.len() > limit.max_length {
+            return Err(Rejection::TooLong {
+                length: msg.text.len(),
+                max_length: limit.max_length,
+            });
+        }
+
+        self.forget_stale(msg.timestamp);
+        let sent = self.recent.entry((msg.channel_id, sender)).or_insert_with(VecDeque::new);
+        let age = |timestamp: Timestamp| msg.timestamp.saturating_sub(timestamp);
+
+        // Forget whatever is too old to count against either limit.
+        let keep = max!(limit.window, limit.duplicate_window);
+        while sent.front().map_or(false, |&(timestamp, _)| age(timestamp) >= keep) {
+            sent.pop_front();
+        }
+
+        let duplicate = sent.iter().any(|&(timestamp, ref text)| {
+            age(timestamp) < limit.duplicate_window && *text == msg.text
+        });
+        if duplicate {
+            return Err(Rejection::Duplicate);
+        }
+
+        if limit.messages > 0 {
+            let in_window: Vec<Timestamp> = sent.iter()
+                .map(|&(timestamp, _)| timestamp)
+                .filter(|&timestamp| age(timestamp) < limit.window)
+                .collect();
+            if in_window.len() >= limit.messages {
+                let wait = limit.window - age(in_window[in_window.len() - limit.messages]);
+                return Err(Rejection::TooFast { wait: wait });
+            }
+        }
+
+        sent.push_back((msg.timestamp, msg.text.clone()));
+        Ok(())
+    }
+
+    // Drop every sender whose newest message is too old at `now` to count against either limit.
+    fn forget_stale(&mut self, now: Timestamp) {
+        let (default, limits) = (self.default, &self.limits);
+        self.recent.retain(|&(id, _), sent| {
+            let limit = limits.get(&id).cloned().unwrap_or(default);
+            let keep = max!(limit.window, limit.duplicate_window);
+            sent.back().map_or(false, |&(timestamp, _)| now.saturating_sub(timestamp) < keep)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_history::{DEFAULT_CHANNEL, MessageKind};
+
+    fn limiter(limit: RateLimit) -> RateLimiter {
+        let mut limiter = RateLimiter::new();
+        limiter.set_limit(DEFAULT_CHANNEL, limit);
+        limiter
+    }
+
+    fn message(sender: &str, text: &str, timestamp: Timestamp) -> ChatMessage {
+        ChatMessage::new(text, DEFAULT_CHANNEL)
+            .with_kind(MessageKind::Player)
+            .with_sender(sender)
+            .with_timestamp(timestamp)
+    }
+
+    #[test]
+    fn too_fast_until_the_oldest_leaves_the_window() {
+        let mut limiter = limiter(RateLimit {
+            messages: 2,
+            window: 1000,
+            duplicate_window: 0,
+            max_length: 0,
+        });
+        assert_eq!(limiter.check(&message("Bob", "one", 0)), Ok(()));
+        assert_eq!(limiter.check(&message("Bob", "two", 100)), Ok(()));
+        assert_eq!(limiter.check(&message("Bob", "three", 500)),
+                   Err(Rejection::TooFast { wait: 500 }));
+        assert_eq!(limiter.check(&message("Bob", "three", 999)),
+                   Err(Rejection::TooFast { wait: 1 }));
+        // The first message is exactly `window` old, so it no longer counts.
+        assert_eq!(limiter.check(&message("Bob", "three", 1000)), Ok(()));
+        assert_eq!(limiter.check(&message("Bob", "four", 1050)),
+                   Err(Rejection::TooFast { wait: 50 }));
+    }
+
+    #[test]
+    fn senders_are_counted_separately() {
+        let mut limiter = limiter(RateLimit {
+            messages: 1,
+            window: 1000,
+            duplicate_window: 0,
+            max_length: 0,
+        });
+        assert_eq!(limiter.check(&message("Bob", "hi", 0)), Ok(()));
+        assert_eq!(limiter.check(&message("Carol", "hi", 0)), Ok(()));
+        assert!(limiter.check(&message("Bob", "hi again", 10)).is_err());
+    }
+
+    #[test]
+    fn duplicates_rejected_within_their_window() {
+        let mut limiter = limiter(RateLimit {
+            messages: 0,
+            window: 0,
+            duplicate_window: 1000,
+            max_length: 0,
+        });
+        assert_eq!(limiter.check(&message("Bob", "buy gold", 0)), Ok(()));
+        assert_eq!(limiter.check(&message("Bob", "buy gold", 999)), Err(Rejection::Duplicate));
+        assert_eq!(limiter.check(&message("Bob", "sell gold", 999)), Ok(()));
+        assert_eq!(limiter.check(&message("Bob", "buy gold", 1000)), Ok(()));
+    }
+
+    #[test]
+    fn messages_without_a_sender_always_pass() {
+        let mut limiter = limiter(RateLimit {
+            messages: 1,
+            window: 1000,
+            duplicate_window: 1000,
+            max_length: 1,
+        });
+        for timestamp in 0..3 {
+            let msg = ChatMessage::new("same old notice", DEFAULT_CHANNEL).with_timestamp(timestamp);
+            assert_eq!(limiter.check(&msg), Ok(()));
+        }
+        assert!(limiter.recent.is_empty());
+    }
+
+    #[test]
+    fn quiet_senders_are_forgotten() {
+        let mut limiter = limiter(RateLimit {
+            messages: 1,
+            window: 1000,
+            duplicate_window: 500,
+            max_length: 0,
+        });
+        assert_eq!(limiter.check(&message("Bob", "hi", 0)), Ok(()));
+        assert_eq!(limiter.check(&message("Carol", "hi", 999)), Ok(()));
+        assert_eq!(limiter.recent.len(), 2);
+        assert_eq!(limiter.check(&message("Carol", "hi", 1999)), Ok(()));
+        assert_eq!(limiter.recent.len(), 1);
+    }
+
+    #[test]
+    fn long_messages_rejected() {
+        let mut limiter = limiter(RateLimit {
+            messages: 0,
+            window: 0,
+            duplicate_window: 0,
+            max_length: 4,
+        });
+        assert_eq!(limiter.check(&message("Bob", "four", 0)), Ok(()));
+        assert_eq!(limiter.check(&message("Bob", "five!", 0)),
+                   Err(Rejection::TooLong {
+                       length: 5,
+                       max_length: 4,
+                   }));
+    }
+}
@@ -0,0 +1,204 @@
+use chat_history::{ChannelId, ChatMessage, MessageRef, Timestamp};
+use regex;
+use regex::{Regex, RegexBuilder};
+
+
+/// Which messages a search should return. Every filter that is set has to match; an empty query
+/// matches everything.
+#[derive(Clone, Debug)]
+pub struct SearchQuery {
+    pattern: Option<Regex>,
+    channels: Option<Vec<ChannelId>>,
+    sender: Option<String>,
+    since: Option<Timestamp>,
+    until: Option<Timestamp>,
+}
+
+/// A message that matched a search. It doesn't borrow the history, so results can be kept
+/// around while more messages arrive.
+#[derive(Clone, Debug)]
+pub struct SearchMatch {
+    pub message: MessageRef,
+
+    // Whether the message was found in the history backup rather than the recent history.
+    pub in_backup: bool,
+
+    // Byte ranges of the message's text matched by the pattern, in order. Empty when the query
+    // has no pattern.
+    pub ranges: Vec<(usize, usize)>,
+}
+
+impl SearchQuery {
+    pub fn new() -> SearchQuery {
+        SearchQuery {
+            pattern: None,
+            channels: None,
+            sender: None,
+            since: None,
+            until: None,
+        }
+    }
+
+    /// Match messages containing `text`, ignoring case.
+    pub fn substring(text: &str) -> SearchQuery {
+        let pattern = RegexBuilder::new(&regex::escape(text))
+            .case_insensitive(true)
+            .build()
+            .expect("an escaped pattern is always a valid regex");
+        SearchQuery { pattern: Some(pattern), ..SearchQuery::new() }
+    }
+
+    /// Match messages against a regular expression.
+    pub fn regex(pattern: &str) -> Result<SearchQuery, regex::Error> {
+        let pattern = Regex::new(pattern)?;
+        Ok(SearchQuery { pattern: Some(pattern), ..SearchQuery::new() })
+    }
+
+    /// Only match messages sent to one of `channels`.
+    pub fn in_channels(mut self, channels: &[ChannelId]) -> SearchQuery {
+        self.channels = Some(channels.to_vec());
+        self
+    }
+
+    /// Only match messages sent by `sender`, ignoring case.
+    pub fn from_sender(mut self, sender: &str) -> SearchQuery {
+        self.sender = Some(sender.to_lowercase());
+        self
+    }
+
+    /// Only match messages sent within `[since, until)`, either end may be left open.
+    pub fn between(mut self, since: Option<Timestamp>, until: Option<Timestamp>) -> SearchQuery {
+        self.since = since;
+        self.until = until;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pattern.is_none() && self.channels.is_none() && self.sender.is_none() &&
+        self.since.is_none() && self.until.is_none()
+    }
+
+    /// Match a single message kept at `at`, returning None when it doesn't match.
+    pub fn matches(&self,
+                   at: MessageRef,
+                   msg: &ChatMessage,
+                   in_backup: bool)
+                   -> Option<SearchMatch> {
+        if let Some(ref channels) = self.channels {
+            if !channels.contains(&msg.channel_id) {
+                return None;
+            }
+        }
+        if let Some(ref sender) = self.sender {
+            match msg.sender {
+                Some(ref name) if name.to_lowercase() == *sender => {}
+                _ => return None,
+            }
+        }
+        if self.since.map_or(false, |since| msg.timestamp < since) ||
+           self.until.map_or(false, |until| msg.timestamp >= until) {
+            return None;
+        }
+
+        let text = msg.text.as_str();
+        let ranges = match self.pattern {
+            Some(ref pattern) => {
+                let ranges: Vec<(usize, usize)> = pattern.find_iter(&text)
+                    .map(|m| (m.start(), m.end()))
+                    .filter(|&(start, end)| start != end)
+                    .collect();
+                if ranges.is_empty() {
+                    return None;
+                }
+                ranges
+            }
+            None => vec![],
+        };
+        Some(SearchMatch {
+            message: at,
+            in_backup: in_backup,
+            ranges: ranges,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_history::MessageKind;
+
+    const AT: MessageRef = MessageRef::Store(0);
+
+    fn message(channel: usize, sender: &str, text: &str, timestamp: Timestamp) -> ChatMessage {
+        ChatMessage::new(text, ChannelId::new(channel))
+            .with_kind(MessageKind::Player)
+            .with_sender(sender)
+            .with_timestamp(timestamp)
+    }
+
+    fn ranges(query: &SearchQuery, msg: &ChatMessage) -> Option<Vec<(usize, usize)>> {
+        query.matches(AT, msg, false).map(|x| x.ranges)
+    }
+
+    #[test]
+    fn substrings_ignore_case_and_regex_characters() {
+        let msg = message(1, "Bob", "Buy a sword? SWORD? (cheap)", 0);
+        assert_eq!(ranges(&SearchQuery::substring("sword?"), &msg),
+                   Some(vec![(6, 12), (13, 19)]));
+        assert_eq!(ranges(&SearchQuery::substring("(cheap)"), &msg), Some(vec![(20, 27)]));
+        assert_eq!(ranges(&SearchQuery::substring("shield"), &msg), None);
+    }
+
+    #[test]
+    fn regexes_match_as_written() {
+        let msg = message(1, "Bob", "wts sword 50g, shield 20g", 0);
+        let query = SearchQuery::regex(r"\d+g").unwrap();
+        assert_eq!(ranges(&query, &msg), Some(vec![(10, 13), (22, 25)]));
+        // Case sensitive, unlike a plain search.
+        assert_eq!(ranges(&SearchQuery::regex("WTS").unwrap(), &msg), None);
+        // Empty matches don't count.
+        assert_eq!(ranges(&SearchQuery::regex("x*").unwrap(), &msg), None);
+        assert!(SearchQuery::regex("(unclosed").is_err());
+    }
+
+    #[test]
+    fn filters_without_a_pattern() {
+        let msg = message(1, "Bob", "hello", 1000);
+        assert!(SearchQuery::new().is_empty());
+        assert_eq!(ranges(&SearchQuery::new(), &msg), Some(vec![]));
+        assert!(SearchQuery::new().from_sender("BOB").matches(AT, &msg, false).is_some());
+        assert!(SearchQuery::new().from_sender("Bo").matches(AT, &msg, false).is_none());
+
+        let system = ChatMessage::new("hello", ChannelId::new(1));
+        assert!(SearchQuery::new().from_sender("Bob").matches(AT, &system, false).is_none());
+    }
+
+    #[test]
+    fn time_window_includes_since_but_not_until() {
+        let query = SearchQuery::new().between(Some(1000), Some(2000));
+        assert!(!query.is_empty());
+        assert!(query.matches(AT, &message(1, "Bob", "a", 999), false).is_none());
+        assert!(query.matches(AT, &message(1, "Bob", "a", 1000), false).is_some());
+        assert!(query.matches(AT, &message(1, "Bob", "a", 1999), false).is_some());
+        assert!(query.matches(AT, &message(1, "Bob", "a", 2000), false).is_none());
+        let open = SearchQuery::new().between(None, Some(2000));
+        assert!(open.matches(AT, &message(1, "Bob", "a", 0), false).is_some());
+    }
+
+    #[test]
+    fn only_the_chosen_channels() {
+        let query = SearchQuery::substring("hi")
+            .in_channels(&[ChannelId::new(1), ChannelId::new(3)]);
+        assert!(query.matches(AT, &message(1, "Bob", "hi", 0), false).is_some());
+        assert!(query.matches(AT, &message(2, "Bob", "hi", 0), false).is_none());
+        assert!(query.matches(AT, &message(3, "Bob", "bye", 0), false).is_none());
+    }
+
+    #[test]
+    fn matches_remember_where_they_were_found() {
+        let msg = message(1, "Bob", "hi", 0);
+        let found = SearchQuery::new().matches(MessageRef::Whisper(2, 7), &msg, true).unwrap();
+        assert_eq!(found.message, MessageRef::Whisper(2, 7));
+        assert!(found.in_backup);
+    }
+}
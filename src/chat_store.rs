@@ -0,0 +1,245 @@
+use chat_history::{ChannelId, ChatMessage};
+
+use std::collections::{HashMap, VecDeque};
+use std::iter::Chain;
+use std::slice;
+
+/// Position of a message in the store. Every message pushed gets the next sequence number, so
+/// sequence numbers keep increasing even after old messages are evicted.
+pub type MessageSeq = u64;
+
+/// Capacity used by `ChatHistory` unless told otherwise.
+pub const DEFAULT_CAPACITY: usize = 1 << 17;
+
+type DequeIter<'a, T> = Chain<slice::Iter<'a, T>, slice::Iter<'a, T>>;
+
+// Iterate a VecDeque starting at `start`, without walking the elements before it.
+fn deque_iter_from<T>(deque: &VecDeque<T>, start: usize) -> DequeIter<T> {
+    let (front, back) = deque.as_slices();
+    let front_start = min!(start, front.len());
+    let back_start = min!(start - front_start, back.len());
+    front[front_start..].iter().chain(back[back_start..].iter())
+}
+
+// Index of the first element in the sorted `seqs` that is >= `seq`.
+fn lower_bound(seqs: &VecDeque<MessageSeq>, seq: MessageSeq) -> usize {
+    let (mut low, mut high) = (0, seqs.len());
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if seqs[mid] < seq {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    low
+}
+
+/// A bounded ring buffer of chat messages, indexed by channel.
+///
+/// Looking up a message by sequence number is O(1), and iterating a channel only touches the
+/// messages in that channel. Once the store is full the oldest message is evicted for every new
+/// one.
+#[derive(Debug)]
+pub struct ChatStore {
+    messages: VecDeque<ChatMessage>,
+
+    // Sequence number of the front of `messages`.
+    first_seq: MessageSeq,
+
+    // For every channel, the sequence numbers of its messages in ascending order.
+    channels: HashMap<ChannelId, VecDeque<MessageSeq>>,
+    capacity: usize,
+}
+
+impl ChatStore {
+    pub fn with_capacity(capacity: usize) -> ChatStore {
+        ChatStore {
+            messages: VecDeque::new(),
+            first_seq: 0,
+            channels: HashMap::new(),
+            capacity: max!(capacity, 1),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Change the capacity, evicting the oldest messages if there are more than fit.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = max!(capacity, 1);
+        while self.messages.len() > self.capacity {
+            self.evict();
+        }
+    }
+
+    /// Sequence number of the oldest message still in the store.
+    pub fn first_seq(&self) -> MessageSeq {
+        self.first_seq
+    }
+
+    /// Sequence number the next message pushed will get.
+    pub fn end_seq(&self) -> MessageSeq {
+        self.first_seq + self.messages.len() as MessageSeq
+    }
+
+    pub fn push(&mut self, msg: ChatMessage) -> MessageSeq {
+        if self.messages.len() == self.capacity {
+            self.evict();
+        }
+        let seq = self.end_seq();
+        self.channels.entry(msg.channel_id).or_insert_with(VecDeque::new).push_back(seq);
+        self.messages.push_back(msg);
+        seq
+    }
+
+    fn evict(&mut self) {
+        if let Some(msg) = self.messages.pop_front() {
+            let mut channel_empty = false;
+            if let Some(seqs) = self.channels.get_mut(&msg.channel_id) {
+                // The oldest message overall is also the oldest message of its channel.
+                seqs.pop_front();
+                channel_empty = seqs.is_empty();
+            }
+            if channel_empty {
+                self.channels.remove(&msg.channel_id);
+            }
+            self.first_seq += 1;
+        }
+    }
+
+    pub fn get(&self, seq: MessageSeq) -> Option<&ChatMessage> {
+        if seq < self.first_seq {
+            return None;
+        }
+        self.messages.get((seq - self.first_seq) as usize)
+    }
+
+    pub fn get_mut(&mut self, seq: MessageSeq) -> Option<&mut ChatMessage> {
+        if seq < self.first_seq {
+            return None;
+        }
+        self.messages.get_mut((seq - self.first_seq) as usize)
+    }
+
+    /// Iterate every message with a sequence number of at least `from`, oldest first.
+    pub fn iter_from(&self, from: MessageSeq) -> StoreIter {
+        let from = max!(from, self.first_seq);
+        StoreIter {
+            seq: from,
+            inner: deque_iter_from(&self.messages, (from - self.first_seq) as usize),
+        }
+    }
+
+    /// Iterate the messages of one channel with a sequence number of at least `from`.
+    pub fn iter_channel_from(&self, id: ChannelId, from: MessageSeq) -> ChannelIter {
+        let seqs = self.channels.get(&id).map(|seqs| {
+            let start = lower_bound(seqs, from);
+            deque_iter_from(seqs, start)
+        });
+        ChannelIter {
+            store: self,
+            seqs: seqs,
+        }
+    }
+
+    /// Number of messages in a channel with a sequence number of at least `from`.
+    pub fn channel_len_from(&self, id: ChannelId, from: MessageSeq) -> usize {
+        match self.channels.get(&id) {
+            Some(seqs) => seqs.len() - lower_bound(seqs, from),
+            None => 0,
+        }
+    }
+}
+
+pub struct StoreIter<'a> {
+    seq: MessageSeq,
+    inner: DequeIter<'a, ChatMessage>,
+}
+
+impl<'a> Iterator for StoreIter<'a> {
+    type Item = (MessageSeq, &'a ChatMessage);
+    fn next(&mut self) -> Option<(MessageSeq, &'a ChatMessage)> {
+        let seq = self.seq;
+        self.seq += 1;
+        self.inner.next().map(|msg| (seq, msg))
+    }
+}
+
+pub struct ChannelIter<'a> {
+    store: &'a ChatStore,
+    seqs: Option<DequeIter<'a, MessageSeq>>,
+}
+
+impl<'a> Iterator for ChannelIter<'a> {
+    type Item = (MessageSeq, &'a ChatMessage);
+    fn next(&mut self) -> Option<(MessageSeq, &'a ChatMessage)> {
+        let store = self.store;
+        match self.seqs {
+            Some(ref mut seqs) => seqs.next().and_then(|&seq| store.get(seq).map(|msg| (seq, msg))),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(capacity: usize, channels: &[usize]) -> ChatStore {
+        let mut store = ChatStore::with_capacity(capacity);
+        for (idx, &channel) in channels.iter().enumerate() {
+            store.push(ChatMessage::new(idx.to_string(), ChannelId::new(channel)));
+        }
+        store
+    }
+
+    fn texts(iter: StoreIter) -> Vec<(MessageSeq, String)> {
+        iter.map(|(seq, msg)| (seq, msg.text.to_string())).collect()
+    }
+
+    #[test]
+    fn full_store_evicts_the_oldest() {
+        let store = store(3, &[0, 1, 0, 1, 0]);
+        assert_eq!(store.len(), 3);
+        assert_eq!((store.first_seq(), store.end_seq()), (2, 5));
+        assert!(store.get(1).is_none());
+        assert_eq!(store.get(2).map(|x| x.text.to_string()), Some("2".to_owned()));
+        assert_eq!(texts(store.iter_from(0)),
+                   vec![(2, "2".to_owned()), (3, "3".to_owned()), (4, "4".to_owned())]);
+    }
+
+    #[test]
+    fn channels_only_hold_what_is_left() {
+        let store = store(3, &[0, 1, 0, 1, 0]);
+        let channel: Vec<MessageSeq> = store.iter_channel_from(ChannelId::new(0), 0)
+            .map(|(seq, _)| seq)
+            .collect();
+        assert_eq!(channel, vec![2, 4]);
+        assert_eq!(store.channel_len_from(ChannelId::new(0), 3), 1);
+        assert_eq!(store.channel_len_from(ChannelId::new(1), 0), 1);
+        assert_eq!(store.channel_len_from(ChannelId::new(2), 0), 0);
+    }
+
+    #[test]
+    fn evicting_a_channel_s_last_message_forgets_the_channel() {
+        let mut store = store(2, &[1, 0]);
+        store.push(ChatMessage::new("2", ChannelId::new(0)));
+        assert_eq!(store.iter_channel_from(ChannelId::new(1), 0).count(), 0);
+        assert!(!store.channels.contains_key(&ChannelId::new(1)));
+    }
+
+    #[test]
+    fn shrinking_evicts_and_keeps_seqs() {
+        let mut store = store(5, &[0, 0, 0, 0]);
+        store.set_capacity(2);
+        assert_eq!((store.first_seq(), store.end_seq()), (2, 4));
+        assert_eq!(store.push(ChatMessage::new("4", ChannelId::new(0))), 4);
+        assert_eq!(store.first_seq(), 3);
+    }
+}
@@ -0,0 +1,119 @@
+use imgui::ImString;
+
+use std::fmt;
+use std::ops::Deref;
+
+/// Text that is safe to show: valid UTF-8 without any NUL characters.
+///
+/// Chat messages and channel names are turned into `ChatText` as soon as they enter the game,
+/// whether typed, read from disk or received. Invalid UTF-8 is replaced with U+FFFD and NULs are
+/// dropped, so nothing further down has to check again before handing the text to imgui.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ChatText(String);
+
+impl ChatText {
+    pub fn new(text: &str) -> ChatText {
+        if text.contains('\0') {
+            ChatText(text.chars().filter(|&c| c != '\0').collect())
+        } else {
+            ChatText(text.to_owned())
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> ChatText {
+        ChatText::new(&String::from_utf8_lossy(bytes))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+
+    pub fn into_im_string(self) -> ImString {
+        // Safe, there is no NUL for imgui to stop at early.
+        unsafe { ImString::from_string_unchecked(self.0) }
+    }
+}
+
+impl Deref for ChatText {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for ChatText {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<'a> From<&'a str> for ChatText {
+    fn from(text: &'a str) -> ChatText {
+        ChatText::new(text)
+    }
+}
+
+impl From<String> for ChatText {
+    fn from(text: String) -> ChatText {
+        if text.contains('\0') {
+            ChatText::new(&text)
+        } else {
+            ChatText(text)
+        }
+    }
+}
+
+impl<'a> From<&'a [u8]> for ChatText {
+    fn from(bytes: &'a [u8]) -> ChatText {
+        ChatText::from_bytes(bytes)
+    }
+}
+
+impl From<Vec<u8>> for ChatText {
+    fn from(bytes: Vec<u8>) -> ChatText {
+        match String::from_utf8(bytes) {
+            Ok(text) => ChatText::from(text),
+            Err(e) => ChatText::from_bytes(&e.into_bytes()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    #[test]
+    fn valid_text_is_kept() {
+        assert_eq!(ChatText::new("héllo").as_str(), "héllo");
+        assert_eq!(ChatText::from(b"hello".to_vec()).as_str(), "hello");
+        assert_eq!(ChatText::from_bytes("héllo".as_bytes()).as_str(), "héllo");
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced() {
+        let bytes = b"ab\xffc\xe2\x82";
+        assert_eq!(ChatText::from_bytes(bytes).as_str(), "ab\u{fffd}c\u{fffd}");
+        assert_eq!(ChatText::from(bytes.to_vec()).as_str(), "ab\u{fffd}c\u{fffd}");
+    }
+
+    #[test]
+    fn nuls_are_stripped() {
+        assert_eq!(ChatText::new("a\0b\0").as_str(), "ab");
+        assert_eq!(ChatText::from("\0a".to_owned()).as_str(), "a");
+        assert_eq!(ChatText::from(b"a\0\xffb".to_vec()).as_str(), "a\u{fffd}b");
+    }
+
+    #[test]
+    fn always_a_valid_c_string() {
+        let inputs: [&[u8]; 4] = [b"", b"plain", b"\0\0", b"x\0\xc3\0y"];
+        for &bytes in inputs.iter() {
+            let text = ChatText::from_bytes(bytes);
+            assert!(CString::new(text.into_string()).is_ok());
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use chat_history::ChatMessage;
+use chat_notify::Unread;
+use chat_store::MessageSeq;
+
+use std::collections::VecDeque;
+use std::collections::vec_deque;
+
+/// How many whispers are kept per conversation, the oldest are dropped first.
+pub const DEFAULT_CONVERSATION_LENGTH: usize = 500;
+
+/// The whispers exchanged with one other player.
+#[derive(Debug)]
+pub struct Conversation {
+    pub partner: String,
+    messages: VecDeque<ChatMessage>,
+
+    // Sequence number of the front of `messages`, counted per conversation like the store does.
+    first_seq: MessageSeq,
+    pub unread: Unread,
+
+    // Whether the conversation has a tab. Closing the tab keeps the whispers, the tab comes back
+    // with the next whisper to or from the partner.
+    pub open: bool,
+}
+
+impl Conversation {
+    fn new(partner: &str) -> Conversation {
+        Conversation {
+            partner: partner.to_owned(),
+            messages: VecDeque::new(),
+            first_seq: 0,
+            unread: Unread::default(),
+            open: false,
+        }
+    }
+
+    pub fn iter(&self) -> vec_deque::Iter<ChatMessage> {
+        self.messages.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> vec_deque::IterMut<ChatMessage> {
+        self.messages.iter_mut()
+    }
+
+    pub fn last(&self) -> Option<&ChatMessage> {
+        self.messages.back()
+    }
+
+    /// Sequence number of the oldest whisper still kept.
+    pub fn first_seq(&self) -> MessageSeq {
+        self.first_seq
+    }
+
+    pub fn get(&self, seq: MessageSeq) -> Option<&ChatMessage> {
+        if seq < self.first_seq {
+            return None;
+        }
+        self.messages.get((seq - self.first_seq) as usize)
+    }
+}
+
+/// Whispers grouped by who they were exchanged with, kept apart from the channel history so
+/// channel traffic can't push them out.
+#[derive(Debug)]
+pub struct Conversations {
+    // In the order they were started.
+    conversations: Vec<Conversation>,
+    capacity: usize,
+
+    // Whoever whispered the player last, the target of /reply.
+    last_whisperer: Option<String>,
+
+    // How many whispers were ever pushed.
+    pushed: u64,
+}
+
+impl Conversations {
+    pub fn new() -> Conversations {
+        Conversations {
+            conversations: vec![],
+            capacity: DEFAULT_CONVERSATION_LENGTH,
+            last_whisperer: None,
+            pushed: 0,
+        }
+    }
+
+    pub fn iter(&self) -> ::std::slice::Iter<Conversation> {
+        self.conversations.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> ::std::slice::IterMut<Conversation> {
+        self.conversations.iter_mut()
+    }
+
+    /// Conversations are never removed, so the index in `iter` stays the same.
+    pub fn get(&self, idx: usize) -> Option<&Conversation> {
+        self.conversations.get(idx)
+    }
+
+    /// Partners are matched ignoring case.
+    pub fn find(&self, partner: &str) -> Option<&Conversation> {
+        let partner = partner.to_lowercase();
+        self.conversations.iter().find(|x| x.partner.to_lowercase() == partner)
+    }
+
+    pub fn find_mut(&mut self, partner: &str) -> Option<&mut Conversation> {
+        let partner = partner.to_lowercase();
+        self.conversations.iter_mut().find(|x| x.partner.to_lowercase() == partner)
+    }
+
+    /// Add a whisper to the conversation with `partner`, starting one if needed.
+    pub fn push(&mut self, partner: &str, msg: ChatMessage) {
+        if self.find(partner).is_none() {
+            self.conversations.push(Conversation::new(partner));
+        }
+        let capacity = self.capacity;
+        if let Some(conversation) = self.find_mut(partner) {
+            if conversation.messages.len() == capacity {
+                conversation.messages.pop_front();
+                conversation.first_seq += 1;
+            }
+            conversation.messages.push_back(msg);
+        }
+        self.pushed += 1;
+    }
+
+    pub fn pushed(&self) -> u64 {
+        self.pushed
+    }
+
+    pub fn last_whisperer(&self) -> Option<&str> {
+        self.last_whisperer.as_ref().map(|x| x.as_str())
+    }
+
+    pub fn set_last_whisperer(&mut self, partner: &str) {
+        self.last_whisperer = Some(partner.to_owned());
+    }
+}
@@ -0,0 +1,138 @@
+use chat_history::{Timestamp, now};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DamageDirection {
+    // Damage the player took.
+    Taken,
+    // Damage the player dealt.
+    Dealt,
+}
+
+/// A single hit, as reported in the combat log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CombatEvent {
+    pub direction: DamageDirection,
+    pub amount: u32,
+
+    // Who hit the player, or who the player hit, when known.
+    pub other: Option<String>,
+    pub timestamp: Timestamp,
+}
+
+impl CombatEvent {
+    pub fn new(direction: DamageDirection, amount: u32) -> CombatEvent {
+        CombatEvent {
+            direction: direction,
+            amount: amount,
+            other: None,
+            timestamp: now(),
+        }
+    }
+
+    pub fn taken(amount: u32) -> CombatEvent {
+        CombatEvent::new(DamageDirection::Taken, amount)
+    }
+
+    pub fn dealt(amount: u32) -> CombatEvent {
+        CombatEvent::new(DamageDirection::Dealt, amount)
+    }
+
+    pub fn with_other(mut self, other: &str) -> CombatEvent {
+        self.other = Some(other.to_owned());
+        self
+    }
+
+    pub fn with_timestamp(mut self, timestamp: Timestamp) -> CombatEvent {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// The line shown in the combat log for this event. `parse` turns it back into the event.
+    pub fn render(&self) -> String {
+        match (self.direction, self.other.as_ref()) {
+            (DamageDirection::Taken, None) => format!("You took {} damage.", self.amount),
+            (DamageDirection::Taken, Some(other)) => {
+                format!("You took {} damage from {}.", self.amount, other)
+            }
+            (DamageDirection::Dealt, None) => format!("You've given {} damage.", self.amount),
+            (DamageDirection::Dealt, Some(other)) => {
+                format!("You've given {} damage to {}.", self.amount, other)
+            }
+        }
+    }
+
+    /// Parse a combat log line written by `render`, returning None for any other text.
+    pub fn parse(text: &str, timestamp: Timestamp) -> Option<CombatEvent> {
+        let text = text.trim();
+        if !text.ends_with('.') {
+            return None;
+        }
+        let text = &text[..text.len() - 1];
+        let (direction, rest, other_prefix) = if text.starts_with("You took ") {
+            (DamageDirection::Taken, &text["You took ".len()..], " from ")
+        } else if text.starts_with("You've given ") {
+            (DamageDirection::Dealt, &text["You've given ".len()..], " to ")
+        } else {
+            return None;
+        };
+
+        let (amount, rest) = match rest.find(' ') {
+            Some(pos) => (&rest[..pos], &rest[pos..]),
+            None => return None,
+        };
+        let amount = match amount.parse::<u32>() {
+            Ok(amount) => amount,
+            Err(_) => return None,
+        };
+        if !rest.starts_with(" damage") {
+            return None;
+        }
+        let rest = &rest[" damage".len()..];
+        let other = if rest.is_empty() {
+            None
+        } else if rest.starts_with(other_prefix) && rest.len() > other_prefix.len() {
+            Some(rest[other_prefix.len()..].to_owned())
+        } else {
+            return None;
+        };
+        Some(CombatEvent {
+            direction: direction,
+            amount: amount,
+            other: other,
+            timestamp: timestamp,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rendered_events_parse_back() {
+        let events = [CombatEvent::taken(31).with_timestamp(5),
+                      CombatEvent::dealt(7).with_other("Goblin").with_timestamp(5)];
+        for event in &events {
+            assert_eq!(CombatEvent::parse(&event.render(), 5).as_ref(), Some(event));
+        }
+    }
+
+    #[test]
+    fn parses_the_combat_log_lines() {
+        let event = CombatEvent::parse("You took 31 damage.", 0).unwrap();
+        assert_eq!((event.direction, event.amount), (DamageDirection::Taken, 31));
+        assert_eq!(event.other, None);
+
+        let event = CombatEvent::parse("You've given 12 damage to Goblin.", 0).unwrap();
+        assert_eq!((event.direction, event.amount), (DamageDirection::Dealt, 12));
+        assert_eq!(event.other, Some("Goblin".to_owned()));
+    }
+
+    #[test]
+    fn other_text_is_not_combat() {
+        assert!(CombatEvent::parse("Lorem ipsum dolor sit amet.", 0).is_none());
+        assert!(CombatEvent::parse("You took 31 damage", 0).is_none());
+        assert!(CombatEvent::parse("You took lots of damage.", 0).is_none());
+        assert!(CombatEvent::parse("You took 31 damage from .", 0).is_none());
+    }
+}
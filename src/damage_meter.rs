@@ -0,0 +1,146 @@
+use chat_history::Timestamp;
+use combat::{CombatEvent, DamageDirection};
+
+use std::collections::VecDeque;
+
+/// How far back the DPS readout looks, in milliseconds.
+pub const DEFAULT_DPS_WINDOW: Timestamp = 5_000;
+
+/// How long combat has to be quiet before the next hit starts a new encounter, in milliseconds.
+pub const DEFAULT_ENCOUNTER_TIMEOUT: Timestamp = 10_000;
+
+/// Totals for one stretch of uninterrupted combat.
+#[derive(Debug, Clone)]
+pub struct Encounter {
+    pub start: Timestamp,
+    pub end: Timestamp,
+    pub taken: u64,
+    pub dealt: u64,
+    pub hits_taken: u32,
+    pub hits_dealt: u32,
+}
+
+impl Encounter {
+    fn new(timestamp: Timestamp) -> Encounter {
+        Encounter {
+            start: timestamp,
+            end: timestamp,
+            taken: 0,
+            dealt: 0,
+            hits_taken: 0,
+            hits_dealt: 0,
+        }
+    }
+
+    fn record(&mut self, event: &CombatEvent) {
+        self.start = min!(self.start, event.timestamp);
+        self.end = max!(self.end, event.timestamp);
+        match event.direction {
+            DamageDirection::Taken => {
+                self.taken += event.amount as u64;
+                self.hits_taken += 1;
+            }
+            DamageDirection::Dealt => {
+                self.dealt += event.amount as u64;
+                self.hits_dealt += 1;
+            }
+        }
+    }
+
+    /// Length of the encounter in seconds. An encounter of a single hit counts as one second, so
+    /// its DPS isn't infinite.
+    pub fn duration_secs(&self) -> f32 {
+        max!(self.end - self.start, 1000) as f32 / 1000.0
+    }
+
+    pub fn total(&self, direction: DamageDirection) -> u64 {
+        match direction {
+            DamageDirection::Taken => self.taken,
+            DamageDirection::Dealt => self.dealt,
+        }
+    }
+
+    /// Average damage per second over the whole encounter.
+    pub fn dps(&self, direction: DamageDirection) -> f32 {
+        self.total(direction) as f32 / self.duration_secs()
+    }
+}
+
+/// Tallies combat events into per-encounter totals and a sliding window DPS.
+#[derive(Debug)]
+pub struct DamageMeter {
+    // Events inside the DPS window of the newest event, oldest first.
+    recent: VecDeque<CombatEvent>,
+    encounters: Vec<Encounter>,
+    dps_window: Timestamp,
+    encounter_timeout: Timestamp,
+}
+
+impl DamageMeter {
+    pub fn new() -> DamageMeter {
+        DamageMeter {
+            recent: VecDeque::new(),
+            encounters: vec![],
+            dps_window: DEFAULT_DPS_WINDOW,
+            encounter_timeout: DEFAULT_ENCOUNTER_TIMEOUT,
+        }
+    }
+
+    pub fn dps_window(&self) -> Timestamp {
+        self.dps_window
+    }
+
+    pub fn record(&mut self, event: &CombatEvent) {
+        let starts_encounter = match self.encounters.last() {
+            Some(encounter) => event.timestamp > encounter.end + self.encounter_timeout,
+            None => true,
+        };
+        if starts_encounter {
+            self.encounters.push(Encounter::new(event.timestamp));
+        }
+        if let Some(encounter) = self.encounters.last_mut() {
+            encounter.record(event);
+        }
+
+        self.recent.push_back(event.clone());
+        let newest = self.recent.iter().map(|x| x.timestamp).max().unwrap_or(event.timestamp);
+        while self.recent.front().map_or(false, |x| x.timestamp + self.dps_window < newest) {
+            self.recent.pop_front();
+        }
+    }
+
+    /// Damage per second over the DPS window ending at `now`.
+    pub fn dps(&self, direction: DamageDirection, now: Timestamp) -> f32 {
+        let since = now.saturating_sub(self.dps_window);
+        let total: u64 = self.recent
+            .iter()
+            .filter(|x| x.direction == direction && x.timestamp > since && x.timestamp <= now)
+            .map(|x| x.amount as u64)
+            .sum();
+        total as f32 / (self.dps_window as f32 / 1000.0)
+    }
+
+    /// Every encounter so far, oldest first.
+    pub fn encounters(&self) -> &[Encounter] {
+        &self.encounters
+    }
+
+    /// The encounter still in progress at `now`, if any.
+    pub fn current_encounter(&self, now: Timestamp) -> Option<&Encounter> {
+        self.encounters.last().and_then(|encounter| if now <= encounter.end + self.encounter_timeout {
+            Some(encounter)
+        } else {
+            None
+        })
+    }
+
+    /// Damage taken or dealt over every encounter.
+    pub fn total(&self, direction: DamageDirection) -> u64 {
+        self.encounters.iter().map(|x| x.total(direction)).sum()
+    }
+
+    pub fn reset(&mut self) {
+        self.recent.clear();
+        self.encounters.clear();
+    }
+}
@@ -15,17 +15,6 @@ pub struct PsoFactory<'a, R, F>
     phantom: PhantomData<R>,
 }
 
-macro_rules! triangle_strip {
-        ($vshader:ident, $fshader:ident, $factory:ident, $pipe:ident) => ({
-            let set = $factory.create_shader_set($vshader, $fshader).unwrap();
-            let primitive = gfx::Primitive::TriangleStrip;
-            let rasterizer = gfx::state::Rasterizer::new_fill().with_cull_back();
-            $factory
-                .create_pipeline_state(&set, primitive, rasterizer, $pipe)
-                .unwrap()
-        })
-    }
-
 macro_rules! triangle_list {
         ($vshader:ident, $fshader:ident, $factory:ident, $pipe:ident) => ({
             let set = $factory.create_shader_set($vshader, $fshader).unwrap();
@@ -48,27 +37,78 @@ impl<'a, R, F> PsoFactory<'a, R, F>
         }
     }
 
-    pub fn triangle_strip_colors(&mut self) -> gfx::PipelineState<R, ColorPipe::Meta> {
-        let pipe = ColorPipe::new();
+    pub fn triangle_list_colors_instanced(&mut self)
+                                          -> gfx::PipelineState<R, InstancedColorPipe::Meta> {
+        let pipe = InstancedColorPipe::new();
         let factory = &mut self.factory;
-        triangle_strip!(COLOR_CUBE_SHADER_V, COLOR_CUBE_SHADER_F, factory, pipe)
+        triangle_list!(INSTANCED_COLOR_CUBE_SHADER_V, COLOR_CUBE_SHADER_F, factory, pipe)
     }
 
-    pub fn triangle_list_colors(&mut self) -> gfx::PipelineState<R, ColorPipe::Meta> {
-        let pipe = ColorPipe::new();
+    pub fn triangle_list_uv_instanced(&mut self)
+                                      -> gfx::PipelineState<R, InstancedUvPipe::Meta> {
+        let pipe = InstancedUvPipe::new();
         let factory = &mut self.factory;
-        triangle_list!(COLOR_CUBE_SHADER_V, COLOR_CUBE_SHADER_F, factory, pipe)
+        triangle_list!(INSTANCED_UV_CUBE_SHADER_V, UV_CUBE_SHADER_F, factory, pipe)
     }
+}
 
-    pub fn triangle_strip_uv(&mut self) -> gfx::PipelineState<R, UvPipe::Meta> {
-        let pipe = UvPipe::new();
-        let factory = &mut self.factory;
-        triangle_strip!(UV_CUBE_SHADER_V, UV_CUBE_SHADER_F, factory, pipe)
+/// Per instance data for one instanced draw call, rewritten every frame. The buffer is only
+/// recreated when there are more instances than fit in it.
+pub struct InstanceBuffer<R: gfx::Resources> {
+    buffer: gfx::handle::Buffer<R, Instance>,
+    capacity: usize,
+    len: usize,
+}
+
+impl<R: gfx::Resources> InstanceBuffer<R> {
+    pub fn new<F: gfx::Factory<R>>(factory: &mut F, capacity: usize) -> InstanceBuffer<R> {
+        // A buffer can't be empty.
+        let capacity = max!(capacity, 1);
+        InstanceBuffer {
+            buffer: create_instance_buffer(factory, capacity),
+            capacity: capacity,
+            len: 0,
+        }
     }
 
-    pub fn triangle_list_uv(&mut self) -> gfx::PipelineState<R, UvPipe::Meta> {
-        let pipe = UvPipe::new();
-        let factory = &mut self.factory;
-        triangle_list!(UV_CUBE_SHADER_V, UV_CUBE_SHADER_F, factory, pipe)
+    pub fn update<F, C>(&mut self,
+                        factory: &mut F,
+                        encoder: &mut gfx::Encoder<R, C>,
+                        instances: &[Instance])
+        where F: gfx::Factory<R>,
+              C: gfx::CommandBuffer<R>
+    {
+        if instances.len() > self.capacity {
+            self.capacity = max!(instances.len(), self.capacity * 2);
+            self.buffer = create_instance_buffer(factory, self.capacity);
+        }
+        if !instances.is_empty() {
+            encoder.update_buffer(&self.buffer, instances, 0).unwrap();
+        }
+        self.len = instances.len();
+    }
+
+    pub fn buffer(&self) -> &gfx::handle::Buffer<R, Instance> {
+        &self.buffer
     }
+
+    /// `slice` set up to draw its vertices once for every instance.
+    pub fn slice(&self, slice: &gfx::Slice<R>) -> gfx::Slice<R> {
+        let mut slice = slice.clone();
+        slice.instances = Some((self.len as gfx::InstanceCount, 0));
+        slice
+    }
+}
+
+fn create_instance_buffer<R, F>(factory: &mut F,
+                                capacity: usize)
+                                -> gfx::handle::Buffer<R, Instance>
+    where R: gfx::Resources,
+          F: gfx::Factory<R>
+{
+    factory.create_buffer(capacity,
+                          gfx::buffer::Role::Vertex,
+                          gfx::memory::Usage::Dynamic,
+                          gfx::Bind::empty())
+        .unwrap()
 }
@@ -0,0 +1,181 @@
+use shader::LightParams;
+use specs::*;
+
+/// The most lights a draw is lit by, matches `MAX_LIGHTS` in the fragment shaders. Any enabled
+/// lights past this are left out.
+pub const MAX_LIGHTS: usize = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LightKind {
+    // Shines every way from its position, fading with distance.
+    Point,
+    // Shines one way everywhere, like the sun.
+    Directional,
+    // A point light that only shines within a cone around its direction.
+    Spot,
+}
+
+impl LightKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            LightKind::Point => "Point",
+            LightKind::Directional => "Directional",
+            LightKind::Spot => "Spot",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Light {
+    pub name: String,
+    pub kind: LightKind,
+    pub enabled: bool,
+    pub color: [f32; 4],
+    pub position: [f32; 3],
+
+    // Which way directional lights and spots shine.
+    pub direction: [f32; 3],
+
+    // How point lights and spots fade at distance d, as the constant, linear and quadratic terms
+    // of 1 / (constant + linear * d + quadratic * d * d).
+    pub attenuation: [f32; 3],
+
+    // Spots are at full brightness within `inner_angle` degrees of their direction, fading out
+    // until `outer_angle`.
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+}
+
+impl Component for Light {
+    type Storage = VecStorage<Self>;
+}
+
+impl Light {
+    pub fn point(name: &str, position: [f32; 3], color: [f32; 4]) -> Light {
+        Light {
+            name: name.to_owned(),
+            kind: LightKind::Point,
+            enabled: true,
+            color: color,
+            position: position,
+            direction: [0.0, -1.0, 0.0],
+            attenuation: [1.0, 0.0, 0.0],
+            inner_angle: 12.5,
+            outer_angle: 17.5,
+        }
+    }
+
+    pub fn directional(name: &str, direction: [f32; 3], color: [f32; 4]) -> Light {
+        Light {
+            kind: LightKind::Directional,
+            direction: direction,
+            ..Light::point(name, [0.0, 0.0, 0.0], color)
+        }
+    }
+
+    pub fn spot(name: &str, position: [f32; 3], direction: [f32; 3], color: [f32; 4]) -> Light {
+        Light {
+            kind: LightKind::Spot,
+            direction: direction,
+            ..Light::point(name, position, color)
+        }
+    }
+
+    pub fn params(&self) -> LightParams {
+        let kind = match self.kind {
+            LightKind::Point => 0.0,
+            LightKind::Directional => 1.0,
+            LightKind::Spot => 2.0,
+        };
+        let (p, d, a) = (self.position, self.direction, self.attenuation);
+        // The shader fades a spot out between the two angles, which only works while the inner
+        // one is within the outer one.
+        let outer = self.outer_angle.max(0.0).min(90.0);
+        let inner = self.inner_angle.max(0.0).min(outer);
+        LightParams {
+            color: self.color,
+            position: [p[0], p[1], p[2], 1.0],
+            direction: [d[0], d[1], d[2], 0.0],
+            attenuation: [a[0], a[1], a[2], 0.0],
+            params: [kind,
+                     inner.to_radians().cos(),
+                     outer.to_radians().cos(),
+                     0.0],
+        }
+    }
+}
+
+/// The enabled lights in the layout the shaders take, and how many of them there are.
+pub fn light_params<'a, I>(lights: I) -> ([LightParams; MAX_LIGHTS], usize)
+    where I: IntoIterator<Item = &'a Light>
+{
+    let unused = LightParams {
+        color: [0.0, 0.0, 0.0, 0.0],
+        position: [0.0, 0.0, 0.0, 1.0],
+        direction: [0.0, 0.0, 0.0, 0.0],
+        attenuation: [1.0, 0.0, 0.0, 0.0],
+        params: [0.0, 0.0, 0.0, 0.0],
+    };
+    let mut params = [unused; MAX_LIGHTS];
+    let mut count = 0;
+    for light in lights.into_iter().filter(|x| x.enabled).take(MAX_LIGHTS) {
+        params[count] = light.params();
+        count += 1;
+    }
+    (params, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+
+    fn spot(inner_angle: f32, outer_angle: f32) -> Light {
+        Light {
+            inner_angle: inner_angle,
+            outer_angle: outer_angle,
+            ..Light::spot("Spot", [0.0, 0.0, 0.0], [0.0, -1.0, 0.0], WHITE)
+        }
+    }
+
+    fn cosines(light: &Light) -> (f32, f32) {
+        let (params, count) = light_params(Some(light));
+        assert_eq!(count, 1);
+        (params[0].params[1], params[0].params[2])
+    }
+
+    #[test]
+    fn spot_angles_are_passed_as_cosines() {
+        let (inner, outer) = cosines(&spot(0.0, 60.0));
+        assert_eq!(inner, 1.0);
+        assert!((outer - 0.5).abs() < 1e-6);
+        assert_eq!(light_params(Some(&spot(0.0, 60.0))).0[0].params[0], 2.0);
+    }
+
+    #[test]
+    fn spot_inner_angle_is_kept_within_the_outer_one() {
+        assert_eq!(cosines(&spot(30.0, 20.0)), cosines(&spot(20.0, 20.0)));
+        assert_eq!(cosines(&spot(-10.0, 20.0)), cosines(&spot(0.0, 20.0)));
+        assert_eq!(cosines(&spot(10.0, 120.0)), cosines(&spot(10.0, 90.0)));
+        assert_eq!(cosines(&spot(120.0, 120.0)), cosines(&spot(90.0, 90.0)));
+    }
+
+    #[test]
+    fn only_enabled_lights_up_to_the_maximum() {
+        let mut lights: Vec<Light> = (0..MAX_LIGHTS + 2)
+            .map(|idx| Light::point(&idx.to_string(), [idx as f32, 0.0, 0.0], WHITE))
+            .collect();
+        lights[0].enabled = false;
+
+        let (params, count) = light_params(&lights);
+        assert_eq!(count, MAX_LIGHTS);
+        let xs: Vec<f32> = params.iter().map(|x| x.position[0]).collect();
+        let expected: Vec<f32> = (1..MAX_LIGHTS + 1).map(|x| x as f32).collect();
+        assert_eq!(xs, expected);
+
+        let (params, count) = light_params(&lights[..3]);
+        assert_eq!(count, 2);
+        assert_eq!(params[2].color, [0.0, 0.0, 0.0, 0.0]);
+    }
+}
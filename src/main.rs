@@ -21,94 +21,142 @@ extern crate min_max_macros;
 use imgui::*;
 use itertools::Itertools;
 
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 
 use camera::Camera;
-use chat_history::{ChannelId, ChatHistory, ChatPrune};
-use state::{ChatWindowState, EditingFieldOption, Player, State, UiBuffers};
+use chat_command::CommandRegistry;
+use chat_filter::ChatFilter;
+use chat_font::Fonts;
+use chat_history::{ChannelId, ChatHistory, ChatPrune, MessageKind};
+use chat_input::{DEFAULT_INPUT_HISTORY_LENGTH, InputHistory};
+use chat_layout::ChatLayout;
+use chat_notify::HighlightRules;
+use chat_rate::RateLimiter;
+use chat_text::ChatText;
+use damage_meter::DamageMeter;
+use settings::{CHAT_SETTINGS_PATH, ChatSettings};
+use state::{EditingFieldOption, Player, State, UiBuffers};
 
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
 
 extern crate genmesh;
 extern crate noise;
 extern crate rand;
+extern crate regex;
 
 extern crate specs;
 extern crate toml;
 
 mod camera;
+mod chat_command;
+mod chat_export;
+mod chat_filter;
+mod chat_font;
 mod chat_history;
+mod chat_input;
+mod chat_layout;
+mod chat_listener;
+mod chat_log;
+mod chat_markup;
+mod chat_notify;
+mod chat_rate;
+mod chat_search;
+mod chat_store;
+mod chat_text;
+mod chat_whisper;
 mod color;
+mod combat;
+mod damage_meter;
 mod gpu;
+mod light;
+mod material;
+mod mesh;
+mod settings;
 mod shader;
 mod shape;
 mod state;
 mod support;
 mod ui;
 
+const CHAT_LOG_PATH: &str = "data/chat_log.toml";
+const CHAT_FILTER_PATH: &str = "data/chat_filter.toml";
+const CHAT_RATE_LIMIT_PATH: &str = "data/chat_rate_limit.toml";
+
 fn main() {
-    let chat_config = ChatWindowState {
-        dimensions: (480.0, 200.0),
-        offset: (10.0, 6.0),
-        button_padding: 20.0,
-        window_rounding: 0.0,
-        max_length_chat_input_text: 128,
-        max_length_menu_input_text: 10,
-        pos: (0.0, 0.0),
-        movable: false,
-        resizable: false,
-        save_settings: false,
-        view_all: false,
-        user_editing: false,
+    let settings = match ChatSettings::load(CHAT_SETTINGS_PATH) {
+        Ok(settings) => settings,
+        Err(e) => {
+            println!("not loading chat settings '{}': {}", CHAT_SETTINGS_PATH, e);
+            ChatSettings::default()
+        }
     };
+    let chat_config = settings.chat_window;
     let chat_buffer_capacity = chat_config.max_length_chat_input_text;
     let menu_input_buffer_capacity = chat_config.max_length_menu_input_text;
     let chat_history_text =
-        &[("Welcome to the server 'Turnshroom Habitat'", ChannelId::new(0)),
-          ("Wizz: Hey", ChannelId::new(0)),
-          ("Thorny: Yo", ChannelId::new(0)),
-          ("Mufk: SUp man", ChannelId::new(0)),
-          ("Kazaghual: anyone w2b this axe I just found?", ChannelId::new(2)),
-          ("PizzaMan: Yo I'm here to deliver this pizza, I'll just leave it over here by the \
-            dragon ok?",
+        &[(None,
+           "Welcome to the server 'Turnshroom Habitat'",
+           MessageKind::System,
+           ChannelId::new(0)),
+          (Some("Wizz"), "Hey", MessageKind::Player, ChannelId::new(0)),
+          (Some("Thorny"), "Yo", MessageKind::Player, ChannelId::new(0)),
+          (Some("Mufk"), "SUp man", MessageKind::Player, ChannelId::new(0)),
+          (Some("Kazaghual"),
+           "anyone w2b this axe I just found?",
+           MessageKind::Player,
            ChannelId::new(2)),
-          ("Moo: grass plz", ChannelId::new(3)),
-          ("Aladin: STFU Jafar", ChannelId::new(4)),
-          ("Rocky: JKSLFJS", ChannelId::new(5)),
-          ("You took 31 damage.", ChannelId::new(1)),
-          ("You've given 25 damage.", ChannelId::new(1)),
-          ("You took 61 damage.", ChannelId::new(1)),
-          ("You've given 20 damage.", ChannelId::new(1)),
-          ("A gender chalks in the vintage coke. When will the murder pocket a wanted symptom? My \
+          (Some("PizzaMan"),
+           "Yo I'm here to deliver this pizza, I'll just leave it over here by the dragon ok?",
+           MessageKind::Player,
+           ChannelId::new(2)),
+          (Some("Moo"), "grass plz", MessageKind::Player, ChannelId::new(3)),
+          (Some("Aladin"), "STFU Jafar", MessageKind::Player, ChannelId::new(4)),
+          (Some("Rocky"), "JKSLFJS", MessageKind::Player, ChannelId::new(5)),
+          (None, "You took 31 damage.", MessageKind::Combat, ChannelId::new(1)),
+          (None, "You've given 25 damage.", MessageKind::Combat, ChannelId::new(1)),
+          (None, "You took 61 damage.", MessageKind::Combat, ChannelId::new(1)),
+          (None, "You've given 20 damage.", MessageKind::Combat, ChannelId::new(1)),
+          (Some("Wizz"),
+           "A gender chalks in the vintage coke. When will the murder pocket a wanted symptom? My \
             attitude observes any nuisance into the laughing constant.
         Every candidate \
             offers the railway under the beforehand molecule. The rescue buys his wrath \
             underneath the above garble.",
+           MessageKind::Player,
            ChannelId::new(4)),
-          ("The truth collars the bass into a lower heel. A squashed machinery kisses the \
+          (Some("Thorny"),
+           "The truth collars the bass into a lower heel. A squashed machinery kisses the \
             abandon. Across its horse swims a sheep. Any umbrella damage rants over a sniff.
         \
             How can a theorem chalk the frustrating fraud? Should the world wash an \
             incomprehensible curriculum?",
+           MessageKind::Player,
            ChannelId::new(3)),
-          ("The cap ducks inside the freedom. The mum hammers the apathy above our preserved \
+          (None, "The cap ducks inside the freedom. The mum hammers the apathy above our preserved \
             ozone. Will the peanut nose a review species? His vocabulary beams near the virgin.
         \
             The short supporter blames the hack fudge. The waffle exacts the bankrupt within an \
             infantile attitude.",
+           MessageKind::System,
            ChannelId::new(1)),
-          ("A flesh hazards the sneaking tooth. An analyst steams before an instinct! The muscle \
+          (Some("Kazaghual"),
+           "A flesh hazards the sneaking tooth. An analyst steams before an instinct! The muscle \
             expands within each brother! Why can't the indefinite garbage harden? The feasible \
             cider
         moans in the forest.",
+           MessageKind::Player,
            ChannelId::new(2)),
-          ("Opposite the initiative scratches an inane plant. Why won't the late school \
+          (None, "Opposite the initiative scratches an inane plant. Why won't the late school \
             experiment with a crown? The sneak papers a go dinner without a straw. How can an \
             eating guy camp?
         Around the convinced verdict waffles a scratching shed. The \
             inhabitant escapes before whatever outcry.",
+           MessageKind::System,
            ChannelId::new(1))];
     let init_channels = vec![
         (String::from("General"), [1.0, 1.0, 1.0, 1.0]),
@@ -116,6 +164,7 @@ fn main() {
         (String::from("Whisper"), [0.8, 0.0, 0.7, 1.0]),
         (String::from("Group"), [0.2, 0.4, 0.9, 1.0]),
         (String::from("Guild"), [0.1, 0.8, 0.3, 1.0]),
+        (String::from("System"), [0.9, 0.8, 0.2, 1.0]),
     ];
     let prune = ChatPrune {
         length: 10,
@@ -123,6 +172,8 @@ fn main() {
     };
     let ui_buffers = UiBuffers {
         chat_input_buffer: ImString::with_capacity(chat_buffer_capacity),
+        chat_input_history: InputHistory::with_capacity(DEFAULT_INPUT_HISTORY_LENGTH),
+        completion_candidates: None,
         menu_input_buffer: ImString::with_capacity(menu_input_buffer_capacity),
         menu_int_buffer: Default::default(),
         menu_int_buffer_backup: Default::default(),
@@ -130,27 +181,108 @@ fn main() {
         menu_bool_buffer_backup: Default::default(),
         menu_color_buffer: Default::default(),
         menu_color_buffer_backup: Default::default(),
+        search_input_buffer: ImString::with_capacity(chat_buffer_capacity),
+        search_regex: false,
+        search_sender_buffer: ImString::with_capacity(chat_buffer_capacity),
+        search_minutes: 0,
+        search_channels: vec![],
+        search_cache: None,
+    };
+    let player_name = String::from("Adventurer");
+    let chat_history = {
+        // Pick up where the last session left off, falling back to the default channels and
+        // messages the first time the game is run. A log that is there but can't be read is left
+        // alone, and nothing is logged this session.
+        let mut write_log = true;
+        let mut chat_history = match ChatHistory::load(CHAT_LOG_PATH) {
+            Ok(chat_history) => chat_history,
+            Err(e) => {
+                let not_found = e.downcast_ref::<io::Error>()
+                    .map_or(false, |x| x.kind() == io::ErrorKind::NotFound);
+                if !not_found {
+                    println!("error loading chat log '{}': {}", CHAT_LOG_PATH, e);
+                    write_log = false;
+                }
+                ChatHistory::from_existing(&init_channels, chat_history_text, prune)
+            }
+        };
+        // Channel names and colors, and the prune settings, are kept in the settings file. The
+        // chat log's copies only cover channels the settings don't know yet. They're set directly
+        // so nothing is appended to the log.
+        for record in &settings.channel {
+            if let Some(channel) = chat_history.lookup_channel_mut(ChannelId::new(record.id)) {
+                channel.name = ChatText::new(&record.name);
+                channel.text_color = record.text_color;
+            }
+        }
+        if let Some(ref prune) = settings.prune {
+            chat_history.set_prune(prune.enabled, prune.length);
+            if prune.enabled {
+                chat_history.prune();
+            }
+        }
+        if let Some(id) = chat_history.find_channel("System") {
+            chat_history.set_system_channel(id);
+        }
+        match ChatFilter::load(CHAT_FILTER_PATH) {
+            Ok(filter) => chat_history.set_filter(filter),
+            Err(e) => println!("not loading chat filter '{}': {}", CHAT_FILTER_PATH, e),
+        }
+        for sender in &settings.ignored {
+            chat_history.ignore_sender(sender);
+        }
+        for &id in &settings.muted {
+            chat_history.set_channel_muted(ChannelId::new(id), true);
+        }
+        match RateLimiter::load(CHAT_RATE_LIMIT_PATH) {
+            Ok(rate_limiter) => chat_history.set_rate_limiter(rate_limiter),
+            Err(e) => println!("not loading chat rate limits '{}': {}", CHAT_RATE_LIMIT_PATH, e),
+        }
+        chat_history.set_highlight_rules(HighlightRules {
+            own_name: Some(player_name.clone()),
+            ..HighlightRules::new()
+        });
+        // Start the session from a compacted snapshot, then append to it as messages arrive.
+        if write_log {
+            let opened = chat_history.save(CHAT_LOG_PATH)
+                .and_then(|_| chat_history.open_log(CHAT_LOG_PATH));
+            if let Err(e) = opened {
+                println!("error opening chat log '{}': {}", CHAT_LOG_PATH, e);
+            }
+        }
+        chat_history
+    };
+    let chat_layout = if settings.window.is_empty() {
+        ChatLayout::with_default_tabs(chat_history.channels())
+    } else {
+        ChatLayout::from_records(&settings.window)
     };
     let state = {
         let s = 0.22;
         let c = color::WHITE;
         let ambient_color = [c[0] * s, c[1] * s, c[2] * s, c[3]];
 
-        let diffuse_color = [1.0, 0.0, 1.0, 1.0];
-        let diffuse_color_pos = [0.0, 0.0, 0.0];
-
         State {
             ui_buffers: ui_buffers,
-            chat_history: ChatHistory::from_existing(&init_channels, chat_history_text, prune),
+            chat_history: chat_history,
+            chat_commands: CommandRegistry::with_defaults(),
+            chat_layout: chat_layout,
             chat_button_pressed: ChannelId::new(0),
             chat_window_state: chat_config,
             edit_chat_field: EditingFieldOption::NotEditing,
+            damage_meter: DamageMeter::new(),
+            show_damage_meter: false,
+            item_tooltip: None,
+            fonts: Fonts::new(),
+            saved_settings: None,
+            settings_checked: 0,
             framerate: 0.0,
             window_dimensions: (1920, 1080),
             fullscreen: true,
             quit: false,
 
             player: Player {
+                name: player_name,
                 camera: Camera::from_rot([0.0, 0.0, 0.0]),
                 move_speed: 0.2,
             },
@@ -158,8 +290,8 @@ fn main() {
             // level data
             ambient_color: ambient_color,
 
-            diffuse_color: diffuse_color,
-            diffuse_color_pos: diffuse_color_pos,
+            selected_light: None,
+            new_light: None,
         }
     };
 
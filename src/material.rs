@@ -0,0 +1,155 @@
+use shader::Locals;
+use specs::*;
+use toml;
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// Where the materials entities can be drawn with are defined.
+pub const MATERIALS_PATH: &str = "data/materials.toml";
+
+/// How an entity's surface looks. Every field but the name can be left out of the materials file.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Material {
+    // Has to be unique, meshes are batched by material name.
+    pub name: String,
+
+    // Multiplies the mesh's own colors, or its texture.
+    pub color: [f32; 4],
+
+    // The name of the textured cube drawn instead of the plain one, if any. Only the textured
+    // cubes passed to `Materials::load` can be named.
+    pub textured_cube: Option<String>,
+
+    pub specular_strength: f32,
+    pub shininess: f32,
+
+    // Light given off regardless of any light falling on the surface.
+    pub emissive: [f32; 3],
+}
+
+impl Default for Material {
+    fn default() -> Material {
+        Material {
+            name: String::new(),
+            color: [1.0, 1.0, 1.0, 1.0],
+            textured_cube: None,
+            specular_strength: 1.0,
+            shininess: 32.0,
+            emissive: [0.0, 0.0, 0.0],
+        }
+    }
+}
+
+impl Component for Material {
+    type Storage = VecStorage<Self>;
+}
+
+impl Material {
+    pub fn locals(&self) -> Locals {
+        Locals {
+            albedo: self.color,
+            emissive: self.emissive,
+            specular_strength: self.specular_strength,
+            shininess: self.shininess,
+        }
+    }
+}
+
+/// The materials file as stored on disk.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct MaterialFile {
+    #[serde(default)]
+    pub material: Vec<Material>,
+}
+
+/// The materials loaded from the materials file, in the order they were defined.
+#[derive(Debug)]
+pub struct Materials {
+    materials: Vec<Material>,
+}
+
+impl Materials {
+    /// Just the default material.
+    pub fn new() -> Materials {
+        let material = Material { name: "default".to_owned(), ..Material::default() };
+        Materials { materials: vec![material] }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P, textured_cubes: &[&str]) -> Result<Materials, Box<Error>> {
+        let contents = {
+            let mut file = File::open(path)?;
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            contents
+        };
+        let file: MaterialFile = toml::from_str(&contents)?;
+        Materials::from_file(file, textured_cubes)
+    }
+
+    fn from_file(file: MaterialFile, textured_cubes: &[&str]) -> Result<Materials, Box<Error>> {
+        if file.material.is_empty() {
+            return Err("no materials are defined".into());
+        }
+        let mut names = HashSet::new();
+        for material in &file.material {
+            if material.name.is_empty() {
+                return Err("every material needs a name".into());
+            }
+            if !names.insert(material.name.as_str()) {
+                return Err(format!("material '{}' is defined twice", material.name).into());
+            }
+            if let Some(ref cube) = material.textured_cube {
+                if !textured_cubes.contains(&cube.as_str()) {
+                    let msg = format!("material '{}' uses the unknown textured cube '{}'",
+                                      material.name,
+                                      cube);
+                    return Err(msg.into());
+                }
+            }
+        }
+        Ok(Materials { materials: file.material })
+    }
+
+    pub fn all(&self) -> &[Material] {
+        &self.materials
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn materials(contents: &str) -> Result<Materials, Box<Error>> {
+        Materials::from_file(toml::from_str(contents).unwrap(), &["cube"])
+    }
+
+    #[test]
+    fn left_out_fields_are_defaults() {
+        let materials = materials("[[material]]\nname = \"matte\"\nshininess = 4.0\n").unwrap();
+        let expected = Material {
+            name: "matte".to_owned(),
+            shininess: 4.0,
+            ..Material::default()
+        };
+        assert_eq!(materials.all(), &[expected]);
+    }
+
+    #[test]
+    fn names_are_required_and_unique() {
+        assert!(materials("").is_err());
+        assert!(materials("[[material]]\nshininess = 4.0\n").is_err());
+        assert!(materials("[[material]]\nname = \"a\"\n[[material]]\nname = \"a\"\n").is_err());
+        assert!(materials("[[material]]\nname = \"a\"\n[[material]]\nname = \"b\"\n").is_ok());
+    }
+
+    #[test]
+    fn only_known_textured_cubes() {
+        assert!(materials("[[material]]\nname = \"a\"\ntextured_cube = \"cube\"\n").is_ok());
+        assert!(materials("[[material]]\nname = \"a\"\ntextured_cube = \"crate\"\n").is_err());
+    }
+}
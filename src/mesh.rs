@@ -0,0 +1,276 @@
+use cgmath::Matrix4;
+use gfx;
+use gfx::traits::FactoryExt;
+
+use gpu::InstanceBuffer;
+use light::MAX_LIGHTS;
+use material::Material;
+use shader;
+use shader::{ColorVertex, CubeTextureData, Instance, LightParams, Locals, UvVertex};
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+// Batches not drawn for this many flushes are freed, they're created again when drawn.
+const BATCH_UNUSED_FLUSHES: u64 = 600;
+
+/// A handle to a mesh uploaded to the GPU.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct MeshId(usize);
+
+enum Vertices<R: gfx::Resources> {
+    Color(gfx::handle::Buffer<R, ColorVertex>),
+    Uv(gfx::handle::Buffer<R, UvVertex>, CubeTextureData<R>, gfx::handle::Sampler<R>),
+}
+
+/// Everything drawn with one mesh in one material.
+struct Batch<R: gfx::Resources> {
+    locals: Locals,
+
+    // Everything queued since the last flush.
+    queued: Vec<Instance>,
+
+    // Created on the first flush the batch has anything to draw.
+    buffers: Option<(InstanceBuffer<R>, gfx::handle::Buffer<R, Locals>)>,
+
+    // The last flush that drew this batch.
+    last_drawn: u64,
+}
+
+struct Mesh<R: gfx::Resources> {
+    vertices: Vertices<R>,
+    slice: gfx::Slice<R>,
+
+    // By material name.
+    batches: HashMap<String, Batch<R>>,
+
+    // The last flush that drew this mesh.
+    last_drawn: u64,
+}
+
+/// The pipelines meshes are drawn with, one for each kind of vertex.
+pub struct Pipelines<R: gfx::Resources> {
+    pub color: gfx::PipelineState<R, shader::InstancedColorPipe::Meta>,
+    pub uv: gfx::PipelineState<R, shader::InstancedUvPipe::Meta>,
+}
+
+/// What every mesh drawn in a frame shares.
+#[derive(Debug, Copy, Clone)]
+pub struct DrawParams {
+    pub view_proj: [[f32; 4]; 4],
+    pub viewpos: [f32; 3],
+    pub ambient: [f32; 4],
+
+    // Only the first `num_lights` are used.
+    pub lights: [LightParams; MAX_LIGHTS],
+    pub num_lights: usize,
+}
+
+/// Meshes uploaded once and drawn by handle.
+///
+/// Drawing a mesh only queues its transform, `flush` then draws every mesh once per material,
+/// instanced over everything queued for it. No GPU memory is allocated per frame unless a mesh is
+/// drawn in a new material, or more often than ever before.
+pub struct Meshes<R: gfx::Resources> {
+    meshes: HashMap<MeshId, Mesh<R>>,
+    next_id: usize,
+
+    // How many times the meshes have been flushed.
+    flushes: u64,
+
+    // Shared by every draw, created on the first flush.
+    lights: Option<gfx::handle::Buffer<R, LightParams>>,
+}
+
+impl<R: gfx::Resources> Meshes<R> {
+    pub fn new() -> Meshes<R> {
+        Meshes {
+            meshes: HashMap::new(),
+            next_id: 0,
+            flushes: 0,
+            lights: None,
+        }
+    }
+
+    pub fn add_color<F>(&mut self,
+                        factory: &mut F,
+                        vertices: &[ColorVertex],
+                        indices: &[u16])
+                        -> MeshId
+        where F: gfx::Factory<R>
+    {
+        let (buffer, slice) = factory.create_vertex_buffer_with_slice(vertices, indices);
+        self.add(Vertices::Color(buffer), slice)
+    }
+
+    pub fn add_uv<F>(&mut self,
+                     factory: &mut F,
+                     vertices: &[UvVertex],
+                     indices: &[u16],
+                     texture: CubeTextureData<R>,
+                     sampler: gfx::handle::Sampler<R>)
+                     -> MeshId
+        where F: gfx::Factory<R>
+    {
+        let (buffer, slice) = factory.create_vertex_buffer_with_slice(vertices, indices);
+        self.add(Vertices::Uv(buffer, texture, sampler), slice)
+    }
+
+    fn add(&mut self, vertices: Vertices<R>, slice: gfx::Slice<R>) -> MeshId {
+        let id = MeshId(self.next_id);
+        self.next_id += 1;
+        let mesh = Mesh {
+            vertices: vertices,
+            slice: slice,
+            batches: HashMap::new(),
+            last_drawn: self.flushes,
+        };
+        self.meshes.insert(id, mesh);
+        id
+    }
+
+    /// Free the mesh's buffers. Returns false if there was no such mesh.
+    pub fn unload(&mut self, id: MeshId) -> bool {
+        self.meshes.remove(&id).is_some()
+    }
+
+    /// Free every mesh that hasn't been drawn in the last `flushes` flushes.
+    pub fn unload_unused(&mut self, flushes: u64) {
+        drop_unused(&mut self.meshes, |mesh| mesh.last_drawn, self.flushes, flushes);
+    }
+
+    /// Queue the mesh to be drawn in `material` with `transform` at the next flush, tinted by
+    /// `color`. Unknown meshes are ignored.
+    pub fn draw(&mut self,
+                id: MeshId,
+                material: &Material,
+                transform: Matrix4<f32>,
+                color: [f32; 4]) {
+        let mesh = match self.meshes.get_mut(&id) {
+            Some(mesh) => mesh,
+            None => return,
+        };
+        let instance = Instance::new(transform, color);
+        // Materials can change while the game runs, so the latest one drawn wins.
+        if let Some(batch) = mesh.batches.get_mut(material.name.as_str()) {
+            batch.locals = material.locals();
+            batch.queued.push(instance);
+            return;
+        }
+        let batch = Batch {
+            locals: material.locals(),
+            queued: vec![instance],
+            buffers: None,
+            last_drawn: self.flushes,
+        };
+        mesh.batches.insert(material.name.clone(), batch);
+    }
+
+    /// Draw everything queued since the last flush.
+    pub fn flush<F, C>(&mut self,
+                       factory: &mut F,
+                       encoder: &mut gfx::Encoder<R, C>,
+                       pipelines: &Pipelines<R>,
+                       params: &DrawParams,
+                       out_color: &shader::OutColor<R>,
+                       depth: &shader::OutDepth<R>)
+        where F: gfx::Factory<R>,
+              C: gfx::CommandBuffer<R>
+    {
+        self.flushes += 1;
+        if self.lights.is_none() {
+            self.lights = Some(factory.create_constant_buffer(MAX_LIGHTS));
+        }
+        let lights = self.lights.as_ref().unwrap();
+        encoder.update_buffer(lights, &params.lights, 0).unwrap();
+        let num_lights = min!(params.num_lights, MAX_LIGHTS) as i32;
+
+        for mesh in self.meshes.values_mut() {
+            for batch in mesh.batches.values_mut() {
+                if batch.queued.is_empty() {
+                    continue;
+                }
+                mesh.last_drawn = self.flushes;
+                batch.last_drawn = self.flushes;
+
+                if batch.buffers.is_none() {
+                    let instances = InstanceBuffer::new(factory, batch.queued.len());
+                    batch.buffers = Some((instances, factory.create_constant_buffer(1)));
+                }
+                let (ref mut instances, ref locals) = *batch.buffers.as_mut().unwrap();
+                instances.update(factory, encoder, &batch.queued);
+                batch.queued.clear();
+                encoder.update_constant_buffer(locals, &batch.locals);
+
+                let slice = instances.slice(&mesh.slice);
+                match mesh.vertices {
+                    Vertices::Color(ref vbuf) => {
+                        let data = shader::InstancedColorPipe::Data {
+                            vbuf: vbuf.clone(),
+                            instances: instances.buffer().clone(),
+                            locals: locals.clone(),
+                            view_proj: params.view_proj,
+                            ambient: params.ambient,
+                            lights: lights.clone(),
+                            num_lights: num_lights,
+                            viewpos: params.viewpos,
+                            out: out_color.clone(),
+                            depth: depth.clone(),
+                        };
+                        encoder.draw(&slice, &pipelines.color, &data);
+                    }
+                    Vertices::Uv(ref vbuf, ref texture, ref sampler) => {
+                        let data = shader::InstancedUvPipe::Data {
+                            vbuf: vbuf.clone(),
+                            instances: instances.buffer().clone(),
+                            locals: locals.clone(),
+                            uv_front: (texture.front.clone(), sampler.clone()),
+                            uv_back: (texture.back.clone(), sampler.clone()),
+                            uv_top: (texture.top.clone(), sampler.clone()),
+                            uv_bottom: (texture.bottom.clone(), sampler.clone()),
+                            uv_left: (texture.left.clone(), sampler.clone()),
+                            uv_right: (texture.right.clone(), sampler.clone()),
+                            view_proj: params.view_proj,
+                            ambient: params.ambient,
+                            lights: lights.clone(),
+                            num_lights: num_lights,
+                            viewpos: params.viewpos,
+                            out: out_color.clone(),
+                            depth: depth.clone(),
+                        };
+                        encoder.draw(&slice, &pipelines.uv, &data);
+                    }
+                }
+            }
+            drop_unused(&mut mesh.batches,
+                        |batch| batch.last_drawn,
+                        self.flushes,
+                        BATCH_UNUSED_FLUSHES);
+        }
+    }
+}
+
+// Drop whatever hasn't been drawn in the last `flushes` flushes, `now` being the latest flush.
+fn drop_unused<K, V, F>(items: &mut HashMap<K, V>, last_drawn: F, now: u64, flushes: u64)
+    where K: Eq + Hash,
+          F: Fn(&V) -> u64
+{
+    items.retain(|_, item| now - last_drawn(item) <= flushes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unused_meshes_are_dropped() {
+        let mut last_drawn = HashMap::new();
+        last_drawn.insert(MeshId(0), 10);
+        last_drawn.insert(MeshId(1), 7);
+        last_drawn.insert(MeshId(2), 6);
+        drop_unused(&mut last_drawn, |&flush| flush, 10, 3);
+        assert!(last_drawn.contains_key(&MeshId(0)));
+        assert!(last_drawn.contains_key(&MeshId(1)));
+        assert!(!last_drawn.contains_key(&MeshId(2)));
+    }
+}
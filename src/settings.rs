@@ -0,0 +1,269 @@
+use chat_font::ChatFont;
+use chat_history;
+use chat_history::Timestamp;
+use chat_log::PruneRecord;
+use state::{ChatWindowState, State};
+use toml;
+
+use std::error::Error;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// Where the chat settings are kept.
+pub const CHAT_SETTINGS_PATH: &str = "data/chat_settings.toml";
+
+/// How often, in milliseconds, the chat settings are checked for changes to save.
+pub const CHAT_SETTINGS_CHECK_INTERVAL: Timestamp = 1000;
+
+/// Version of the chat settings file. New fields should be `#[serde(default)]`, so older files
+/// stay loadable.
+pub const CHAT_SETTINGS_VERSION: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ChatTabRecord {
+    pub name: String,
+    #[serde(default)]
+    pub channels: Vec<usize>,
+    #[serde(default)]
+    pub all_channels: bool,
+    pub send_channel: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ChatWindowRecord {
+    pub name: String,
+    #[serde(default)]
+    pub active_tab: usize,
+
+    // Added in version 2. Only kept for windows moved or resized by the user, the others are
+    // placed relative to the screen.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pos: Option<(f32, f32)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<(f32, f32)>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub font: Option<ChatFont>,
+    #[serde(default)]
+    pub tab: Vec<ChatTabRecord>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ChannelSettingsRecord {
+    pub id: usize,
+    pub name: String,
+    pub text_color: [f32; 4],
+}
+
+/// The chat settings file. TOML wants plain values before tables, so the field order matters.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ChatSettings {
+    pub version: u32,
+
+    // Added in version 3. Lowercased players hidden with /ignore, and ids of channels muted with
+    // /mute, both sorted.
+    #[serde(default)]
+    pub ignored: Vec<String>,
+    #[serde(default)]
+    pub muted: Vec<usize>,
+
+    // Added in version 2.
+    #[serde(default)]
+    pub chat_window: ChatWindowState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prune: Option<PruneRecord>,
+    #[serde(default)]
+    pub channel: Vec<ChannelSettingsRecord>,
+
+    #[serde(default)]
+    pub window: Vec<ChatWindowRecord>,
+}
+
+impl Default for ChatSettings {
+    fn default() -> ChatSettings {
+        ChatSettings {
+            version: CHAT_SETTINGS_VERSION,
+            ignored: vec![],
+            muted: vec![],
+            chat_window: ChatWindowState::default(),
+            prune: None,
+            channel: vec![],
+            window: vec![],
+        }
+    }
+}
+
+impl ChatSettings {
+    pub fn new(state: &State) -> ChatSettings {
+        let prune = state.chat_history.get_prune();
+        let channel = state.chat_history
+            .channels()
+            .iter()
+            .map(|x| {
+                ChannelSettingsRecord {
+                    id: x.id.index(),
+                    name: x.name.to_string(),
+                    text_color: x.text_color,
+                }
+            })
+            .collect();
+        let filter = state.chat_history.filter();
+        ChatSettings {
+            version: CHAT_SETTINGS_VERSION,
+            ignored: filter.ignored().cloned().collect(),
+            muted: filter.muted().map(|x| x.index()).collect(),
+            chat_window: state.chat_window_state,
+            prune: Some(PruneRecord {
+                length: prune.length,
+                enabled: prune.enabled,
+            }),
+            channel: channel,
+            window: state.chat_layout.to_records(),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ChatSettings, Box<Error>> {
+        let contents = {
+            let mut file = File::open(path)?;
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            contents
+        };
+        let settings: ChatSettings = toml::from_str(&contents)?;
+        if settings.version > CHAT_SETTINGS_VERSION {
+            let msg = format!("chat settings version {} is newer than the supported version {}",
+                              settings.version,
+                              CHAT_SETTINGS_VERSION);
+            return Err(msg.into());
+        }
+        Ok(settings)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<Error>> {
+        let text = toml::to_string(self)?;
+        let mut file = File::create(path)?;
+        file.write_all(text.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Save the chat settings to `CHAT_SETTINGS_PATH`, reporting any error. Nothing is written while
+/// `save_chat_settings` is off.
+pub fn save_chat_settings(state: &State) {
+    if !state.chat_window_state.save_chat_settings {
+        return;
+    }
+    if let Err(e) = ChatSettings::new(state).save(CHAT_SETTINGS_PATH) {
+        println!("error saving chat settings '{}': {}", CHAT_SETTINGS_PATH, e);
+    }
+}
+
+/// Save the chat settings if they changed since they were last saved. Checking means building
+/// the whole file, so it's done at most once every `CHAT_SETTINGS_CHECK_INTERVAL`.
+pub fn save_changed_settings(state: &mut State) {
+    if !state.chat_window_state.save_chat_settings {
+        return;
+    }
+    let now = chat_history::now();
+    if now.saturating_sub(state.settings_checked) < CHAT_SETTINGS_CHECK_INTERVAL {
+        return;
+    }
+    state.settings_checked = now;
+    let settings = ChatSettings::new(state);
+    if state.saved_settings.as_ref() == Some(&settings) {
+        return;
+    }
+    if let Err(e) = settings.save(CHAT_SETTINGS_PATH) {
+        println!("error saving chat settings '{}': {}", CHAT_SETTINGS_PATH, e);
+    }
+    state.saved_settings = Some(settings);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // A path in the temp directory with nothing there yet.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("softland-chat-settings-{}.toml", name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn settings() -> ChatSettings {
+        let mut chat_window = ChatWindowState::default();
+        chat_window.fade = !chat_window.fade;
+        ChatSettings {
+            ignored: vec!["bob".to_owned()],
+            muted: vec![2],
+            chat_window: chat_window,
+            prune: Some(PruneRecord {
+                length: 50,
+                enabled: true,
+            }),
+            channel: vec![ChannelSettingsRecord {
+                              id: 0,
+                              name: "System".to_owned(),
+                              text_color: [1.0, 0.5, 0.25, 1.0],
+                          }],
+            window: vec![ChatWindowRecord {
+                             name: "Chat".to_owned(),
+                             active_tab: 0,
+                             pos: Some((10.0, 20.0)),
+                             size: None,
+                             font: Some(ChatFont {
+                                 name: "mono".to_owned(),
+                                 size: 14,
+                             }),
+                             tab: vec![ChatTabRecord {
+                                           name: "All".to_owned(),
+                                           channels: vec![0, 1],
+                                           all_channels: false,
+                                           send_channel: 1,
+                                       }],
+                         }],
+            ..ChatSettings::default()
+        }
+    }
+
+    #[test]
+    fn saved_settings_load_back() {
+        let path = temp_path("round-trip");
+        let settings = settings();
+        settings.save(&path).unwrap();
+        assert_eq!(ChatSettings::load(&path).unwrap(), settings);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn old_settings_load_with_defaults() {
+        let path = temp_path("version-1");
+        File::create(&path)
+            .unwrap()
+            .write_all(b"version = 1\n\n[[channel]]\nid = 0\nname = \"System\"\n\
+                         text_color = [1.0, 1.0, 1.0, 1.0]\n")
+            .unwrap();
+        let settings = ChatSettings::load(&path).unwrap();
+        assert_eq!(settings.version, 1);
+        assert!(settings.ignored.is_empty() && settings.window.is_empty());
+        assert_eq!(settings.chat_window, ChatWindowState::default());
+        assert_eq!(settings.channel.len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn newer_settings_are_rejected() {
+        let path = temp_path("newer");
+        let settings = ChatSettings {
+            version: CHAT_SETTINGS_VERSION + 1,
+            ..ChatSettings::default()
+        };
+        settings.save(&path).unwrap();
+        assert!(ChatSettings::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,3 +1,4 @@
+use cgmath::Matrix4;
 use gfx;
 
 pub type ColorFormat = gfx::format::Rgba8;
@@ -46,24 +47,43 @@ gfx_defines!{
         uv: [f32; 2] = "a_uv",
     }
 
+    // Per instance, the model matrix by column and a color the mesh's colors are multiplied by.
+    vertex Instance {
+        model0: [f32; 4] = "a_model0",
+        model1: [f32; 4] = "a_model1",
+        model2: [f32; 4] = "a_model2",
+        model3: [f32; 4] = "a_model3",
+        color: [f32; 4] = "a_instance_color",
+    }
+
+    // The material a mesh is drawn with, laid out as std140 so it can be bound as is.
     constant Locals {
-        model: [[f32; 4]; 4] = "u_model",
-        ambient: [f32; 4] = "u_ambient",
-        lightcolor: [f32; 4] = "u_lightcolor",
+        albedo: [f32; 4] = "u_albedo",
+        emissive: [f32; 3] = "u_emissive",
+        specular_strength: f32 = "u_specular_strength",
+        shininess: f32 = "u_shininess",
+    }
 
-        viewpos: [f32; 3] = "u_viewpos",
-        lightpos: [f32; 3] = "u_lightpos",
+    // One light in the array of lights, see `light::Light::params`. `params` holds the kind of
+    // light, then the cosines of a spot's inner and outer angles.
+    constant LightParams {
+        color: [f32; 4] = "color",
+        position: [f32; 4] = "position",
+        direction: [f32; 4] = "direction",
+        attenuation: [f32; 4] = "attenuation",
+        params: [f32; 4] = "params",
     }
 
-    pipeline ColorPipe {
+    pipeline InstancedColorPipe {
         vbuf: gfx::VertexBuffer<ColorVertex> = (),
+        instances: gfx::InstanceBuffer<Instance> = (),
         locals: gfx::ConstantBuffer<Locals> = "Locals",
-        model: gfx::Global<[[f32; 4]; 4]> = "u_model",
+        view_proj: gfx::Global<[[f32; 4]; 4]> = "u_view_proj",
         ambient: gfx::Global<[f32; 4]> = "u_ambient",
-        lightcolor: gfx::Global<[f32; 4]> = "u_lightcolor",
+        lights: gfx::ConstantBuffer<LightParams> = "Lights",
+        num_lights: gfx::Global<i32> = "u_num_lights",
 
         viewpos: gfx::Global<[f32; 3]> = "u_viewpos",
-        lightpos: gfx::Global<[f32; 3]> = "u_lightpos",
         out: gfx::RenderTarget<ColorFormat> = "target_0",
         depth: gfx::DepthTarget<DepthFormat> = gfx::state::Depth {
             fun: gfx::state::Comparison::Less,
@@ -71,8 +91,9 @@ gfx_defines!{
         },
     }
 
-    pipeline UvPipe {
+    pipeline InstancedUvPipe {
         vbuf: gfx::VertexBuffer<UvVertex> = (),
+        instances: gfx::InstanceBuffer<Instance> = (),
         locals: gfx::ConstantBuffer<Locals> = "Locals",
         uv_front: gfx::TextureSampler<[f32; 4]> = "uv_front",
         uv_back: gfx::TextureSampler<[f32; 4]> = "uv_back",
@@ -81,12 +102,12 @@ gfx_defines!{
         uv_left: gfx::TextureSampler<[f32; 4]> = "uv_left",
         uv_right: gfx::TextureSampler<[f32; 4]> = "uv_right",
 
-        model: gfx::Global<[[f32; 4]; 4]> = "u_model",
+        view_proj: gfx::Global<[[f32; 4]; 4]> = "u_view_proj",
         ambient: gfx::Global<[f32; 4]> = "u_ambient",
-        lightcolor: gfx::Global<[f32; 4]> = "u_lightcolor",
+        lights: gfx::ConstantBuffer<LightParams> = "Lights",
+        num_lights: gfx::Global<i32> = "u_num_lights",
 
         viewpos: gfx::Global<[f32; 3]> = "u_viewpos",
-        lightpos: gfx::Global<[f32; 3]> = "u_lightpos",
         out: gfx::RenderTarget<ColorFormat> = "target_0",
         depth: gfx::DepthTarget<DepthFormat> = gfx::state::Depth {
             fun: gfx::state::Comparison::Less,
@@ -95,8 +116,21 @@ gfx_defines!{
     }
 }
 
-pub const COLOR_CUBE_SHADER_V: &[u8] = include_bytes!("shader/cube_color.glslv");
+impl Instance {
+    pub fn new(model: Matrix4<f32>, color: [f32; 4]) -> Instance {
+        Instance {
+            model0: model.x.into(),
+            model1: model.y.into(),
+            model2: model.z.into(),
+            model3: model.w.into(),
+            color: color,
+        }
+    }
+}
+
+pub const INSTANCED_COLOR_CUBE_SHADER_V: &[u8] =
+    include_bytes!("shader/cube_color_instanced.glslv");
 pub const COLOR_CUBE_SHADER_F: &[u8] = include_bytes!("shader/cube_color.glslf");
 
-pub const UV_CUBE_SHADER_V: &[u8] = include_bytes!("shader/cube_uv.glslv");
+pub const INSTANCED_UV_CUBE_SHADER_V: &[u8] = include_bytes!("shader/cube_uv_instanced.glslv");
 pub const UV_CUBE_SHADER_F: &[u8] = include_bytes!("shader/cube_uv.glslf");
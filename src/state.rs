@@ -1,6 +1,15 @@
 use color;
 use camera::Camera;
+use chat_command::CommandRegistry;
+use chat_font::Fonts;
+use chat_input::InputHistory;
+use chat_layout::{ChatLayout, ChatWindowId};
 use chat_history::*;
+use chat_search::{SearchMatch, SearchQuery};
+use chat_store::MessageSeq;
+use damage_meter::DamageMeter;
+use light::Light;
+use settings::ChatSettings;
 
 use cgmath::*;
 use imgui::*;
@@ -13,8 +22,20 @@ pub struct State {
     pub ui_buffers: UiBuffers,
     pub chat_window_state: ChatWindowState,
     pub chat_history: ChatHistory,
+    pub chat_commands: CommandRegistry,
+    pub chat_layout: ChatLayout,
     pub chat_button_pressed: ChannelId,
     pub edit_chat_field: EditingFieldOption,
+    pub damage_meter: DamageMeter,
+    pub show_damage_meter: bool,
+    // The item whose tooltip was opened by clicking its link in chat.
+    pub item_tooltip: Option<String>,
+    // The fonts loaded from `chat_font::FONTS_PATH`, filled in once imgui is up.
+    pub fonts: Fonts,
+    // The chat settings as last written, so they're only saved again when something changed.
+    pub saved_settings: Option<ChatSettings>,
+    // When the chat settings were last checked for changes.
+    pub settings_checked: Timestamp,
     pub framerate: f64,
     pub fullscreen: bool,
     pub quit: bool,
@@ -25,8 +46,10 @@ pub struct State {
     // level global data
     pub ambient_color: [f32; 4],
 
-    pub diffuse_color: [f32; 4],
-    pub diffuse_color_pos: [f32; 3],
+    // The light the arrow keys move, picked in the Lights menu.
+    pub selected_light: Option<Entity>,
+    // A light added from the Lights menu, waiting to be made into an entity.
+    pub new_light: Option<Light>,
 }
 
 impl Component for State {
@@ -35,6 +58,7 @@ impl Component for State {
 
 #[derive(Debug)]
 pub struct Player {
+    pub name: String,
     pub camera: Camera,
     pub move_speed: f32,
 }
@@ -68,7 +92,10 @@ impl Model {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+/// How chat windows look and behave. Everything but the fields computed or toggled at runtime is
+/// kept in the chat settings file, missing fields fall back to the defaults.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
 pub struct ChatWindowState {
     pub dimensions: (f32, f32),
     pub offset: (f32, f32),
@@ -76,17 +103,57 @@ pub struct ChatWindowState {
     pub window_rounding: f32,
     pub max_length_chat_input_text: usize,
     pub max_length_menu_input_text: usize,
+    // Worked out from the screen size every frame.
+    #[serde(skip_serializing, skip_deserializing)]
     pub pos: (f32, f32),
     pub movable: bool,
     pub resizable: bool,
+    // Let imgui keep the windows' positions and sizes in imgui.ini.
     pub save_settings: bool,
+    // Write changes to the chat settings file.
+    pub save_chat_settings: bool,
+    #[serde(skip_serializing, skip_deserializing)]
     pub view_all: bool,
+    #[serde(skip_serializing, skip_deserializing)]
     pub user_editing: bool,
+
+    // Keep chat windows scrolled to the newest message, unless the user scrolled up.
+    pub auto_scroll: bool,
+    // Fade messages out once a window hasn't been used for `fade_delay` milliseconds.
+    pub fade: bool,
+    pub fade_delay: Timestamp,
+}
+
+impl Default for ChatWindowState {
+    fn default() -> ChatWindowState {
+        ChatWindowState {
+            dimensions: (480.0, 200.0),
+            offset: (10.0, 6.0),
+            button_padding: 20.0,
+            window_rounding: 0.0,
+            max_length_chat_input_text: 128,
+            max_length_menu_input_text: 10,
+            pos: (0.0, 0.0),
+            movable: false,
+            resizable: false,
+            save_settings: false,
+            save_chat_settings: true,
+            view_all: false,
+            user_editing: false,
+            auto_scroll: true,
+            fade: false,
+            fade_delay: 30000,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct UiBuffers {
     pub chat_input_buffer: ImString,
+    pub chat_input_history: InputHistory,
+
+    // The candidates of the last Tab that matched several, and the input they were listed for.
+    pub completion_candidates: Option<(String, Vec<String>)>,
     pub menu_input_buffer: ImString,
     pub menu_int_buffer: i32,
     pub menu_int_buffer_backup: i32,
@@ -94,6 +161,77 @@ pub struct UiBuffers {
     pub menu_bool_buffer_backup: bool,
     pub menu_color_buffer: [f32; 4],
     pub menu_color_buffer_backup: [f32; 4],
+    pub search_input_buffer: ImString,
+    pub search_regex: bool,
+    pub search_sender_buffer: ImString,
+    // Only search messages from the last this many minutes, 0 searches all of them.
+    pub search_minutes: i32,
+    // Only search these channels, all of them when empty.
+    pub search_channels: Vec<ChannelId>,
+    pub search_cache: Option<SearchCache>,
+}
+
+/// Everything the Examine Chat window's search depends on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchInputs {
+    pub text: String,
+    pub regex: bool,
+    pub sender: String,
+    pub minutes: i32,
+    pub channels: Vec<ChannelId>,
+    pub revision: (MessageSeq, MessageSeq, u64),
+}
+
+impl SearchInputs {
+    /// Build the search the inputs describe.
+    pub fn query(&self) -> Result<SearchQuery, String> {
+        let mut query = if self.text.is_empty() {
+            SearchQuery::new()
+        } else if self.regex {
+            SearchQuery::regex(&self.text).map_err(|e| format!("Invalid regex: {}", e))?
+        } else {
+            SearchQuery::substring(&self.text)
+        };
+        if !self.sender.is_empty() {
+            query = query.from_sender(&self.sender);
+        }
+        if !self.channels.is_empty() {
+            query = query.in_channels(&self.channels);
+        }
+        if self.minutes > 0 {
+            // Counted from when the search runs, it isn't redone just because time passes.
+            let since = now().saturating_sub(self.minutes as Timestamp * 60 * 1000);
+            query = query.between(Some(since), None);
+        }
+        Ok(query)
+    }
+}
+
+/// The last search run from the Examine Chat window, redone only when its inputs change.
+#[derive(Debug)]
+pub struct SearchCache {
+    pub inputs: SearchInputs,
+    // None when there is nothing to search for, an error when the query doesn't compile.
+    pub results: Result<Option<Vec<SearchMatch>>, String>,
+}
+
+impl SearchCache {
+    /// Search `history` for what `inputs` describe, unless `cache` holds a search for the same
+    /// inputs already.
+    pub fn update(cache: &mut Option<SearchCache>, inputs: SearchInputs, history: &ChatHistory) {
+        if cache.as_ref().map_or(false, |x| x.inputs == inputs) {
+            return;
+        }
+        let results = inputs.query().map(|query| if query.is_empty() {
+            None
+        } else {
+            Some(history.search(&query))
+        });
+        *cache = Some(SearchCache {
+            inputs: inputs,
+            results: results,
+        });
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -102,6 +240,9 @@ pub enum EditingFieldOption {
     ChatHistoryMaximumLength,
     ChannelName(ChannelId, String),
     ChannelColorText(ChannelId),
+    NewChannel,
+    ChatWindowName(ChatWindowId),
+    ChatTabName(ChatWindowId, usize),
     ChatHistoryViewAll,
 }
 
@@ -132,3 +273,63 @@ pub struct MouseState {
     // atleast once.
     pub cursor_pos: Option<(f32, f32)>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inputs(text: &str, history: &ChatHistory) -> SearchInputs {
+        SearchInputs {
+            text: text.to_owned(),
+            regex: false,
+            sender: String::new(),
+            minutes: 0,
+            channels: vec![],
+            revision: history.revision(),
+        }
+    }
+
+    fn found(cache: &Option<SearchCache>) -> Option<usize> {
+        match cache.as_ref().map(|x| &x.results) {
+            Some(&Ok(Some(ref found))) => Some(found.len()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn search_is_redone_when_the_query_or_history_changes() {
+        let mut history = ChatHistory::new();
+        history.add_channel(DEFAULT_CHANNEL, "System", [1.0, 1.0, 1.0, 1.0]);
+        history.send_message_str(DEFAULT_CHANNEL, "hello");
+        let mut cache = None;
+        SearchCache::update(&mut cache, inputs("hello", &history), &history);
+        assert_eq!(found(&cache), Some(1));
+
+        // Nothing changed, so the (tampered with) results are kept.
+        cache.as_mut().unwrap().results = Ok(Some(vec![]));
+        SearchCache::update(&mut cache, inputs("hello", &history), &history);
+        assert_eq!(found(&cache), Some(0));
+
+        SearchCache::update(&mut cache, inputs("hell", &history), &history);
+        assert_eq!(found(&cache), Some(1));
+
+        history.send_message_str(DEFAULT_CHANNEL, "hello again");
+        SearchCache::update(&mut cache, inputs("hell", &history), &history);
+        assert_eq!(found(&cache), Some(2));
+
+        history.clear();
+        cache.as_mut().unwrap().results = Ok(Some(vec![]));
+        SearchCache::update(&mut cache, inputs("hell", &history), &history);
+        assert_eq!(found(&cache), Some(2));
+    }
+
+    #[test]
+    fn invalid_regex_is_an_error() {
+        let history = ChatHistory::new();
+        let mut inputs = inputs("(", &history);
+        assert!(inputs.query().is_ok());
+        inputs.regex = true;
+        assert!(inputs.query().is_err());
+        assert!(SearchInputs { text: String::new(), ..inputs }.query().unwrap().is_empty());
+    }
+}
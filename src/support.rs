@@ -11,6 +11,7 @@ use glutin::{ElementState, MouseButton, MouseScrollDelta, VirtualKeyCode, TouchP
 use image;
 use imgui::{ImGui, Ui, ImGuiKey};
 use imgui_gfx_renderer::Renderer;
+use std::collections::HashMap;
 use std::time::Instant;
 
 use game_time::{GameClock, FrameCounter, FrameCount};
@@ -20,14 +21,23 @@ use game_time::step;
 use genmesh::generators::*;
 use genmesh::{Vertices, Triangulate};
 
+use chat_font;
+use chat_font::Fonts;
 use color;
 use gpu;
+use mesh;
+use light;
+use light::Light;
+use material;
+use material::{Material, Materials};
+use mesh::{DrawParams, Meshes};
 
 use noise::{Perlin, NoiseModule, Seedable};
 use rand;
 use rand::*;
 use specs::*;
 
+use settings;
 use shape;
 use shader;
 use state;
@@ -65,12 +75,25 @@ impl<'a> System<'a> for UpdateMouseStateSystem {
     }
 }
 
+fn move_selected_light(state: &State, lights: &mut WriteStorage<Light>, offset: [f32; 3]) {
+    let entity = match state.selected_light {
+        Some(entity) => entity,
+        None => return,
+    };
+    if let Some(light) = lights.get_mut(entity) {
+        for i in 0..3 {
+            light.position[i] += offset[i];
+        }
+    }
+}
+
 fn process_event<'a, R>(event: &glutin::WindowEvent,
                         imgui: &mut ImGui,
                         window: &glutin::Window,
                         renderer: &mut Renderer<R>,
                         mouse: &mut state::MouseState,
                         game_state: &mut state::State,
+                        lights: &mut WriteStorage<Light>,
                         main_color: &'a mut shader::OutColor<R>,
                         main_depth: &'a mut shader::OutDepth<R>)
     where R: gfx::Resources + 'a
@@ -114,35 +137,23 @@ fn process_event<'a, R>(event: &glutin::WindowEvent,
                 }
                 Some(VirtualKeyCode::Left) => {
                     imgui.set_key(1, pressed);
-
-                    let x = game_state.diffuse_color_pos[0];
-                    let y = game_state.diffuse_color_pos[1];
-                    let z = game_state.diffuse_color_pos[2];
-                    game_state.diffuse_color_pos = [x + 1.0, y, z];
+                    guard!();
+                    move_selected_light(game_state, lights, [1.0, 0.0, 0.0]);
                 }
                 Some(VirtualKeyCode::Right) => {
                     imgui.set_key(2, pressed);
                     guard!();
-                    let x = game_state.diffuse_color_pos[0];
-                    let y = game_state.diffuse_color_pos[1];
-                    let z = game_state.diffuse_color_pos[2];
-                    game_state.diffuse_color_pos = [x - 1.0, y, z];
+                    move_selected_light(game_state, lights, [-1.0, 0.0, 0.0]);
                 }
                 Some(VirtualKeyCode::Up) => {
                     imgui.set_key(3, pressed);
                     guard!();
-                    let x = game_state.diffuse_color_pos[0];
-                    let y = game_state.diffuse_color_pos[1];
-                    let z = game_state.diffuse_color_pos[2];
-                    game_state.diffuse_color_pos = [x, y + 1.0, z];
+                    move_selected_light(game_state, lights, [0.0, 1.0, 0.0]);
                 }
                 Some(VirtualKeyCode::Down) => {
                     imgui.set_key(4, pressed);
                     guard!();
-                    let x = game_state.diffuse_color_pos[0];
-                    let y = game_state.diffuse_color_pos[1];
-                    let z = game_state.diffuse_color_pos[2];
-                    game_state.diffuse_color_pos = [x, y - 1.0, z];
+                    move_selected_light(game_state, lights, [0.0, -1.0, 0.0]);
                 }
                 Some(VirtualKeyCode::PageUp) => {
                     imgui.set_key(5, pressed);
@@ -316,106 +327,6 @@ fn make_geometry(n: usize) -> (Vec<shader::ColorVertex>, Vec<u32>) {
     (vertexes, indices)
 }
 
-#[inline(always)]
-fn copy_uv_vertices<'a, R, F, C, B>(factory: &mut F,
-                                    encoder: &mut gfx::Encoder<R, C>,
-                                    ambient: [f32; 4],
-                                    light_color: [f32; 4],
-                                    light_pos: [f32; 3],
-                                    out_color: &'a shader::OutColor<R>,
-                                    depth: &shader::OutDepth<R>,
-                                    pso: &gfx::PipelineState<R, shader::UvPipe::Meta>,
-                                    sampler: &gfx::handle::Sampler<R>,
-                                    cube_texture_data: &shader::CubeTextureData<R>,
-                                    model_m: Matrix4<f32>,
-                                    viewpos: [f32; 3],
-                                    vertices: &[shader::UvVertex],
-                                    indices: B)
-    where R: gfx::Resources + PartialEq<R>,
-          F: gfx::Factory<R> + 'a,
-          C: gfx::CommandBuffer<R> + 'a,
-          B: gfx::IntoIndexBuffer<R> + 'a
-{
-    // println!("copy begin");
-    let (vertex_buffer, slice) = factory.create_vertex_buffer_with_slice(&vertices, indices);
-    let cbuf = factory.create_constant_buffer(1);
-    let front_uvmap = (cube_texture_data.front.clone(), sampler.clone());
-    let back_uvmap = (cube_texture_data.back.clone(), sampler.clone());
-    let top_uvmap = (cube_texture_data.top.clone(), sampler.clone());
-    let bottom_uvmap = (cube_texture_data.bottom.clone(), sampler.clone());
-    let left_uvmap = (cube_texture_data.left.clone(), sampler.clone());
-    let right_uvmap = (cube_texture_data.right.clone(), sampler.clone());
-    let data = shader::UvPipe::Data {
-        vbuf: vertex_buffer,
-        locals: cbuf,
-        uv_front: front_uvmap,
-        uv_back: back_uvmap,
-        uv_top: top_uvmap,
-        uv_bottom: bottom_uvmap,
-        uv_left: left_uvmap,
-        uv_right: right_uvmap,
-        model: model_m.into(),
-        viewpos: viewpos.into(),
-        ambient: ambient,
-        lightcolor: light_color,
-        lightpos: light_pos,
-        out: out_color.clone(),
-        depth: depth.clone(),
-    };
-    let locals = shader::Locals {
-        model: data.model,
-        viewpos: data.viewpos,
-        ambient: data.ambient,
-        lightcolor: data.lightcolor,
-        lightpos: data.lightpos,
-    };
-    encoder.update_buffer(&data.locals, &[locals], 0).unwrap();
-    encoder.draw(&slice, &pso, &data);
-    // println!("copy end");
-}
-
-#[inline(always)]
-fn copy_color_vertices<'a, R, F, C, B>(factory: &mut F,
-                                       encoder: &mut gfx::Encoder<R, C>,
-                                       ambient: [f32; 4],
-                                       light_color: [f32; 4],
-                                       light_pos: [f32; 3],
-                                       out_color: &'a shader::OutColor<R>,
-                                       depth: &shader::OutDepth<R>,
-                                       pso: &gfx::PipelineState<R, shader::ColorPipe::Meta>,
-                                       model_m: Matrix4<f32>,
-                                       viewpos: [f32; 3],
-                                       vertices: &[shader::ColorVertex],
-                                       indices: B)
-    where R: gfx::Resources + PartialEq<R>,
-          F: gfx::Factory<R> + 'a,
-          C: gfx::CommandBuffer<R> + 'a,
-          B: gfx::IntoIndexBuffer<R> + 'a
-{
-    let (vertex_buffer, slice) = factory.create_vertex_buffer_with_slice(&vertices, indices);
-    let cbuf = factory.create_constant_buffer(1);
-    let data = shader::ColorPipe::Data {
-        vbuf: vertex_buffer,
-        locals: cbuf,
-        model: model_m.into(),
-        viewpos: viewpos.into(),
-        ambient: ambient,
-        lightcolor: light_color,
-        lightpos: light_pos,
-        out: out_color.clone(),
-        depth: depth.clone(),
-    };
-    let locals = shader::Locals {
-        model: data.model,
-        viewpos: data.viewpos,
-        ambient: data.ambient,
-        lightcolor: data.lightcolor,
-        lightpos: data.lightpos,
-    };
-    encoder.update_buffer(&data.locals, &[locals], 0).unwrap();
-    encoder.draw(&slice, &pso, &data);
-}
-
 fn load_texture<R, F>(factory: &mut F,
                       data: &[u8])
                       -> Result<gfx::handle::ShaderResourceView<R, [f32; 4]>, Box<Error>>
@@ -431,13 +342,19 @@ fn load_texture<R, F>(factory: &mut F,
     Ok(view)
 }
 
-pub fn run_game<F: FnMut(&Ui, &mut State)>(title: &str,
-                                           clear_color: [f32; 4],
-                                           mut state: State,
-                                           file_contents: &str,
-                                           mut build_ui: F)
-                                           -> Result<(), Box<Error>> {
+pub fn run_game<F>(title: &str,
+                   clear_color: [f32; 4],
+                   mut state: State,
+                   file_contents: &str,
+                   mut build_ui: F)
+                   -> Result<(), Box<Error>>
+    where F: FnMut(&Ui, &mut State, &EntitiesRes, &mut WriteStorage<Light>)
+{
     let mut imgui = ImGui::init();
+    match Fonts::load(chat_font::FONTS_PATH) {
+        Ok(fonts) => state.fonts = fonts,
+        Err(e) => println!("error loading fonts '{}': {}", chat_font::FONTS_PATH, e),
+    }
 
     let (w, h) = state.window_dimensions;
     let events_loop = glutin::EventsLoop::new();
@@ -460,22 +377,39 @@ pub fn run_game<F: FnMut(&Ui, &mut State)>(title: &str,
     // let (plane_vertices, plane_indices) = make_geometry(90000);
     // println!("done!");
 
-    let (cube_colors_pso, cube_uvs_pso) = {
+    let pipelines = {
         // let (/*triangle_pso, */cube_uv_pso, /*generated_pso*/) = {
         let mut pso_factory = gpu::PsoFactory::new(&mut factory);
         // let triangle_pso = pso_factory.triangle_list_uv();
-        let cube_colors_pso = pso_factory.triangle_list_colors();
-        let cube_uvs_pso = pso_factory.triangle_list_uv();
         // let generated_pso = pso_factory.triangle_list_uv();
         // (triangle_pso, cube_pso, generated_pso)
-        (cube_colors_pso, cube_uvs_pso)
-
+        mesh::Pipelines {
+            color: pso_factory.triangle_list_colors_instanced(),
+            uv: pso_factory.triangle_list_uv_instanced(),
+        }
     };
 
     let mut world = World::new();
     world.register::<state::Model>();
+    world.register::<Material>();
+    world.register::<Light>();
     world.register::<State>();
 
+    let diffuse_light = Light::point("Diffuse", [0.0, 0.0, 0.0], [1.0, 0.0, 1.0, 1.0]);
+    let diffuse_light = world.create_entity().with(diffuse_light).build();
+    let sun = Light {
+        enabled: false,
+        ..Light::directional("Sun", [-0.3, -1.0, -0.5], [0.5, 0.5, 0.45, 1.0])
+    };
+    world.create_entity().with(sun).build();
+    let spotlight = Light {
+        enabled: false,
+        attenuation: [1.0, 0.02, 0.001],
+        ..Light::spot("Spotlight", [10.0, 10.0, 20.0], [0.0, 0.0, -1.0], color::WHITE)
+    };
+    world.create_entity().with(spotlight).build();
+    state.selected_light = Some(diffuse_light);
+
     state.player.camera.move_forward(10.0);
     state.player.camera.look_at(&[0.0, 0.0, -1.0].into(), &[0.0, 1.0, 0.0].into());
     world.add_resource(state);
@@ -518,6 +452,43 @@ pub fn run_game<F: FnMut(&Ui, &mut State)>(title: &str,
     let sampler = factory.create_sampler_linear();
     println!("post load");
 
+    // Every cube is drawn from the same two meshes, moved and tinted by its model. The color cube
+    // is white so the model's color comes through as is. Materials naming a textured cube are
+    // drawn with it instead.
+    let mut meshes = Meshes::new();
+    let uv_cube = {
+        let (vertices, indices) = shape::construct_uv_cube();
+        let texture = shader::CubeTextureData {
+            front: front,
+            back: back,
+            top: top,
+            bottom: bottom,
+            left: left,
+            right: right,
+        };
+        meshes.add_uv(&mut factory, &vertices, indices, texture, sampler)
+    };
+    let color_cube = {
+        let colors = [color::WHITE; 6];
+        let (vertices, indices) = shape::construct_color_cube(&colors);
+        meshes.add_color(&mut factory, &vertices, indices)
+    };
+    let mut textured_cubes = HashMap::new();
+    textured_cubes.insert("cube".to_owned(), uv_cube);
+
+    let materials = {
+        let names: Vec<&str> = textured_cubes.keys().map(|x| x.as_str()).collect();
+        Materials::load(material::MATERIALS_PATH, &names)
+    };
+    let materials = match materials {
+        Ok(materials) => materials,
+        Err(e) => {
+            println!("not loading materials '{}': {}", material::MATERIALS_PATH, e);
+            Materials::new()
+        }
+    };
+    let mut rng = rand::thread_rng();
+
     let (xr, yr, zr) = (20, 20, 10);
     let num_divisions = 4;
     for x in 0..xr {
@@ -537,7 +508,8 @@ pub fn run_game<F: FnMut(&Ui, &mut State)>(title: &str,
                     // if i as i32 % 3 == 0 {
                     // model.color = color::YELLOW;
                     // }
-                    world.create_entity().with(model).build();
+                    let material = rng.choose(materials.all()).unwrap().clone();
+                    world.create_entity().with(model).with(material).build();
                 }
             }
         }
@@ -555,16 +527,28 @@ pub fn run_game<F: FnMut(&Ui, &mut State)>(title: &str,
     let mut counter = FrameCounter::new(60.0, RunningAverageSampler::with_max_samples(120));
     let mut sim_time;
 
-    let mut rng = rand::thread_rng();
-
     loop {
         dispatcher.dispatch(&mut world.res);
+        // Delete the entities removed last frame, along with their components.
+        world.maintain();
+        let new_light = world.write_resource::<State>().new_light.take();
+        if let Some(light) = new_light {
+            let light = world.create_entity().with(light).build();
+            world.write_resource::<State>().selected_light = Some(light);
+        }
+
         let mut state = &mut *world.write_resource::<State>();
+        let entities = world.entities();
+        let mut lights = world.write::<Light>();
         {
             sim_time = clock.tick(&step::FixedStep::new(&counter));
             counter.tick(&sim_time);
             state.framerate = sim_time.instantaneous_frame_rate();
 
+            for event in state.chat_history.take_combat_events() {
+                state.damage_meter.record(&event);
+            }
+
             events_loop.poll_events(|glutin::Event::WindowEvent { event, .. }| {
                 process_event(&event,
                               &mut imgui,
@@ -572,6 +556,7 @@ pub fn run_game<F: FnMut(&Ui, &mut State)>(title: &str,
                               &mut renderer,
                               &mut mouse,
                               &mut state,
+                              &mut lights,
                               &mut main_color,
                               &mut main_depth);
             });
@@ -600,7 +585,10 @@ pub fn run_game<F: FnMut(&Ui, &mut State)>(title: &str,
                 cgmath::perspective(fovy, aspect_ratio as f32, near, far)
             };
 
-            for model in world.read::<state::Model>().join() {
+            let view = state.player.camera.compute_view();
+            let models = world.read::<state::Model>();
+            let materials = world.read::<Material>();
+            for (model, material) in (&models, &materials).join() {
                 let tmatrix = Matrix4::from_translation(model.translation);
                 let rmatrix: Matrix4<f32> = model.rotation.into();
                 let smatrix = {
@@ -608,53 +596,22 @@ pub fn run_game<F: FnMut(&Ui, &mut State)>(title: &str,
                     Matrix4::from_nonuniform_scale(x, y, z)
                 };
 
-                let mmatrix = tmatrix * rmatrix * smatrix;
-                let view = state.player.camera.compute_view();
-                let uv_matrix = projection * view * mmatrix;
-
-                let c = model.color;
-                let colors = [c, c, c, c, c, c];
-                let viewpos = model.translation.into();
-                let (uv_vertices, uv_indices) = shape::construct_uv_cube();
-                let (color_vertices, color_indices) = shape::construct_color_cube(&colors);
-                let cube_texture_data = shader::CubeTextureData {
-                    front: front.clone(),
-                    back: back.clone(),
-                    top:top.clone(),
-                    bottom: bottom.clone(),
-                    left:left.clone(),
-                    right:right.clone()
-                };
-                if rng.gen() {
-                    copy_uv_vertices(&mut factory,
-                                &mut encoder,
-                                state.ambient_color,
-                                state.diffuse_color,
-                                state.diffuse_color_pos,
-                                &main_color,
-                                &main_depth,
-                                &cube_uvs_pso,
-                                &sampler,
-                                &cube_texture_data,
-                                uv_matrix,
-                                viewpos,
-                                &uv_vertices,
-                                uv_indices);
-                } else {
-                    copy_color_vertices(&mut factory,
-                                  &mut encoder,
-                                  state.ambient_color,
-                                  state.diffuse_color,
-                                  state.diffuse_color_pos,
-                                  &main_color,
-                                  &main_depth,
-                                  &cube_colors_pso,
-                                  uv_matrix,
-                                  viewpos,
-                                  &color_vertices,
-                                  color_indices);
-                }
+                let mesh = material.textured_cube
+                    .as_ref()
+                    .and_then(|cube| textured_cubes.get(cube).cloned())
+                    .unwrap_or(color_cube);
+                meshes.draw(mesh, material, tmatrix * rmatrix * smatrix, model.color);
             }
+
+            let (light_params, num_lights) = light::light_params((&lights).join());
+            let params = DrawParams {
+                view_proj: (projection * view).into(),
+                viewpos: state.player.camera.position().into(),
+                ambient: state.ambient_color,
+                lights: light_params,
+                num_lights: num_lights,
+            };
+            meshes.flush(&mut factory, &mut encoder, &pipelines, &params, &main_color, &main_depth);
             /*
             let tmatrix = Matrix4::from_translation(Vector3::new(0.0, 0.0, 0.0));
             let rmatrix: Matrix4<f32> = Quaternion::from_angle_x(cgmath::Deg(0.0)).into();
@@ -685,7 +642,7 @@ pub fn run_game<F: FnMut(&Ui, &mut State)>(title: &str,
         let size_points = window.get_inner_size_points().unwrap();
         let size_pixels = window.get_inner_size_pixels().unwrap();
         let ui = imgui.frame(size_points, size_pixels, delta_s);
-        build_ui(&ui, &mut state);
+        build_ui(&ui, &mut state, &entities, &mut lights);
 
         // 4. Draw our scene (both UI and geometry submitted via encoder).
         renderer.render(ui, &mut factory, &mut encoder).expect("Rendering failed");
@@ -696,6 +653,10 @@ pub fn run_game<F: FnMut(&Ui, &mut State)>(title: &str,
         device.cleanup();
 
         if state.quit {
+            if let Err(e) = state.chat_history.sync_log() {
+                println!("error saving chat log: {}", e);
+            }
+            settings::save_chat_settings(&state);
             break;
         }
     }
@@ -1,19 +1,73 @@
 use cgmath::*;
 use imgui::*;
 use imgui_sys;
+use imgui_sys::ImGuiTextEditCallbackData;
 
 use color;
-use chat_history::{ChannelId, ChatHistory};
+use chat_command::CommandRegistry;
+use chat_export;
+use chat_export::{ExportFormat, ExportScope};
+use chat_font;
+use chat_font::{ChatFont, FontIndex, Fonts};
+use chat_history::{Channel, ChannelId, ChatHistory, ChatMessage, DEFAULT_CHANNEL, MessageKind};
+use chat_history::Timestamp;
+use chat_history;
+use chat_input;
+use chat_input::{Completion, InputHistory};
+use chat_layout::{ChatLayout, ChatTab, ChatWindow, ChatWindowId};
+use chat_markup;
+use chat_markup::{Link, Span};
+use chat_notify::Unread;
+use chat_search::SearchMatch;
+use chat_text::ChatText;
+use combat::DamageDirection;
+use damage_meter::DamageMeter;
+use light::{Light, LightKind};
+use settings;
+use specs::{EntitiesRes, Entity, Join, WriteStorage};
 use state::*;
 
-pub fn render_ui<'a>(ui: &Ui<'a>, state: &mut State) {
-    show_main_menu(ui, state);
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::slice;
+
+pub fn render_ui<'a>(ui: &Ui<'a>,
+                     state: &mut State,
+                     entities: &EntitiesRes,
+                     lights: &mut WriteStorage<Light>) {
+    // Any click closes an item tooltip, unless it lands on another item link below.
+    if unsafe { imgui_sys::igIsMouseClicked(0, false) } {
+        state.item_tooltip = None;
+    }
+    show_main_menu(ui, state, entities, lights);
     set_chat_window_pos(state);
-    show_chat_window(ui, state);
+    show_chat_windows(ui, state);
+    if let Some(ref item) = state.item_tooltip {
+        show_item_tooltip(ui, item);
+    }
+    if state.show_damage_meter {
+        show_damage_meter(ui, &mut state.damage_meter, &mut state.show_damage_meter);
+    }
+    // Dragging or resizing a window changes it every frame, wait until it's let go.
+    if !unsafe { imgui_sys::igIsMouseDown(0) } {
+        settings::save_changed_settings(state);
+    }
 
     let chat_history = &mut state.chat_history;
+    let chat_layout = &mut state.chat_layout;
     let ui_buffers = &mut state.ui_buffers;
     let edit_chat_field = &mut state.edit_chat_field;
+    // A channel removed while one of its dialogs is open, by `/leave` or the Remove menu item,
+    // closes the dialog.
+    let channel_removed = match *edit_chat_field {
+        EditingFieldOption::ChannelName(id, _) |
+        EditingFieldOption::ChannelColorText(id) => chat_history.lookup_channel(id).is_none(),
+        _ => false,
+    };
+    if channel_removed {
+        *edit_chat_field = EditingFieldOption::NotEditing;
+        ui_buffers.menu_input_buffer.clear();
+    }
     match edit_chat_field.clone() {
         EditingFieldOption::ChannelName(id, name) => {
             create_rename_chat_channel(&ui, id, &name, edit_chat_field, chat_history, ui_buffers);
@@ -24,51 +78,345 @@ pub fn render_ui<'a>(ui: &Ui<'a>, state: &mut State) {
         EditingFieldOption::ChatHistoryMaximumLength => {
             create_set_maximum_chat_history(&ui, edit_chat_field, chat_history, ui_buffers);
         }
+        EditingFieldOption::NewChannel => {
+            create_new_chat_channel(&ui, edit_chat_field, chat_history, ui_buffers);
+        }
+        EditingFieldOption::ChatWindowName(id) => {
+            create_rename_chat_layout(&ui, id, None, edit_chat_field, chat_layout, ui_buffers);
+        }
+        EditingFieldOption::ChatTabName(id, tab) => {
+            create_rename_chat_layout(&ui, id, Some(tab), edit_chat_field, chat_layout, ui_buffers);
+        }
         EditingFieldOption::ChatHistoryViewAll => {
-            create_view_all_chat_history(&ui, edit_chat_field, chat_history);
+            create_view_all_chat_history(&ui, edit_chat_field, chat_history, ui_buffers);
         }
         EditingFieldOption::NotEditing => {}
     };
 }
 
-fn print_chat_msg<'a>(ui: &Ui<'a>, text_color: [f32; 4], msg_bytes: Vec<u8>) {
-    let msg_string: ImString = unsafe { ImString::from_vec_unchecked(msg_bytes) };
-    ui.with_color_var(ImGuiCol::Text, ImVec4::from(text_color), || {
-        ui.text_wrapped(&msg_string);
-    });
+// Every string handed to imgui goes through ChatText, so none of them can hold a NUL.
+fn im_string<T: Into<ChatText>>(text: T) -> ImString {
+    text.into().into_im_string()
+}
+
+// Decorate a message according to who sent it and why.
+fn chat_msg_prefix(msg: &ChatMessage) -> String {
+    match (msg.kind, msg.sender.as_ref()) {
+        (MessageKind::Whisper, _) if msg.recipient.is_some() => {
+            format!("To {}: ", msg.recipient.as_ref().unwrap())
+        }
+        (MessageKind::Player, Some(sender)) => format!("{}: ", sender),
+        (MessageKind::Whisper, Some(sender)) => format!("{} whispers: ", sender),
+        (MessageKind::System, _) => String::from("[System] "),
+        _ => String::new(),
+    }
+}
+
+// The spans shown for a message: who sent it, with their name as a link, followed by the
+// message's own markup.
+fn chat_msg_spans(msg: &ChatMessage) -> Vec<Span> {
+    let player = |name: &str| Span::link(name, Link::Player(name.to_owned())).with_bold();
+    let mut spans = match (msg.kind, msg.sender.as_ref(), msg.recipient.as_ref()) {
+        (MessageKind::Whisper, _, Some(recipient)) => {
+            vec![Span::plain("To "), player(recipient), Span::plain(": ")]
+        }
+        (MessageKind::Player, Some(sender), _) => vec![player(sender), Span::plain(": ")],
+        (MessageKind::Whisper, Some(sender), _) => {
+            vec![player(sender), Span::plain(" whispers: ")]
+        }
+        _ => vec![Span::plain(&chat_msg_prefix(msg))],
+    };
+    spans.extend(chat_markup::parse(&msg.text));
+    spans
+}
+
+// Split text into words, each keeping the whitespace after it, so wrapped lines never start with
+// a space.
+fn split_words(text: &str) -> Vec<&str> {
+    let mut words = vec![];
+    let mut start = 0;
+    let mut after_space = false;
+    for (pos, c) in text.char_indices() {
+        if c.is_whitespace() {
+            after_space = true;
+        } else if after_space {
+            words.push(&text[start..pos]);
+            start = pos;
+            after_space = false;
+        }
+    }
+    if start < text.len() {
+        words.push(&text[start..]);
+    }
+    words
+}
+
+// There is no bold font, text drawn twice a pixel apart stands in for it.
+fn print_text<'a>(ui: &Ui<'a>, color: [f32; 4], bold: bool, text: &ImStr) {
+    if !bold {
+        ui.text_colored(color, text);
+        return;
+    }
+    let mut pos = ImVec2::new(0.0, 0.0);
+    unsafe { imgui_sys::igGetCursorPos(&mut pos) };
+    ui.text_colored(color, text);
+    unsafe { imgui_sys::igSetCursorPos(ImVec2::new(pos.x + 1.0, pos.y)) };
+    ui.text_colored(color, text);
+}
+
+fn show_link_tooltip<'a>(ui: &Ui<'a>, link: &Link) {
+    let text = match *link {
+        Link::Player(ref name) => format!("Whisper {}", name),
+        Link::Item(ref name) => format!("Inspect {}", name),
+        Link::Location(pos) => format!("Look at {}, {}, {}", pos[0], pos[1], pos[2]),
+    };
+    let text = im_string(text);
+    unsafe { imgui_sys::igBeginTooltip() };
+    ui.text(&text);
+    unsafe { imgui_sys::igEndTooltip() };
+}
+
+fn show_item_tooltip<'a>(ui: &Ui<'a>, item: &str) {
+    let name = im_string(item);
+    unsafe { imgui_sys::igBeginTooltip() };
+    print_text(ui, color::GOLD, true, &name);
+    ui.text_colored(color::GRAY, im_str!("Nothing more is known about this item."));
+    unsafe { imgui_sys::igEndTooltip() };
+}
+
+// Draw spans word by word, wrapping at the edge of the window. Every color is made `alpha` times
+// as opaque. Returns the link that was clicked, if any.
+fn print_spans<'a>(ui: &Ui<'a>,
+                   text_color: [f32; 4],
+                   alpha: f32,
+                   spans: &[Span])
+                   -> Option<Link> {
+    const LINK_COLOR: [f32; 4] = color::LIGHT_SKY_BLUE;
+    let wrap_width = unsafe { imgui_sys::igGetContentRegionAvailWidth() };
+    let dont_wrap = -1.0;
+    let mut line_width = 0.0;
+    let mut first = true;
+    let mut clicked = None;
+    for span in spans {
+        let mut color = match (span.link.as_ref(), span.color) {
+            (Some(_), _) => LINK_COLOR,
+            (None, Some(color)) => color,
+            (None, None) => text_color,
+        };
+        color[3] *= alpha;
+        for word in split_words(&span.text) {
+            let text = im_string(word);
+            let width = ui.calc_text_size(&text, false, dont_wrap).x;
+            if !first && line_width + width <= wrap_width {
+                ui.same_line_spacing(0.0, 0.0);
+            } else {
+                line_width = 0.0;
+            }
+            first = false;
+            print_text(ui, color, span.bold, &text);
+            line_width += width;
+
+            if let Some(ref link) = span.link {
+                if unsafe { imgui_sys::igIsItemHovered() } {
+                    show_link_tooltip(ui, link);
+                }
+                if unsafe { imgui_sys::igIsItemClicked(0) } {
+                    clicked = Some(link.clone());
+                }
+            }
+        }
+    }
+    clicked
 }
 
-fn print_chat_messages<'a>(ui: &Ui<'a>, channel_id: ChannelId, history: &ChatHistory) {
-    // If looking at channel 0, show all results.
-    // Otherwise only yield results for the channel.
-    for msg in history.iter_history()
-        .filter(|&msg| channel_id == ChannelId::new(0) || msg.channel_id == channel_id) {
+// How long a message takes to fade out, once it starts to.
+const FADE_DURATION: Timestamp = 2000;
+
+// When messages fade out. A message starts to fade `delay` milliseconds after it arrived or its
+// window was last used, whichever came later.
+#[derive(Debug, Copy, Clone)]
+struct Fade {
+    now: Timestamp,
+    last_active: Timestamp,
+    delay: Timestamp,
+}
+
+impl Fade {
+    fn alpha(&self, msg: &ChatMessage) -> f32 {
+        let start = max!(msg.timestamp, self.last_active) + self.delay;
+        let fading = min!(self.now.saturating_sub(start), FADE_DURATION);
+        1.0 - fading as f32 / FADE_DURATION as f32
+    }
+}
+
+fn print_chat_msg<'a>(ui: &Ui<'a>,
+                      text_color: [f32; 4],
+                      font: Option<FontIndex>,
+                      fade: Option<Fade>,
+                      msg: &ChatMessage)
+                      -> Option<Link> {
+    let alpha = fade.map_or(1.0, |x| x.alpha(msg));
+    chat_font::with_font(font, || print_spans(ui, text_color, alpha, &chat_msg_spans(msg)))
+}
+
+fn channel_font(fonts: &Fonts, channel: &Channel) -> Option<FontIndex> {
+    channel.font.as_ref().and_then(|x| fonts.find(x))
+}
+
+// Returns the link that was clicked, if any.
+fn print_chat_messages<'a>(ui: &Ui<'a>,
+                           channels: &[ChannelId],
+                           history: &ChatHistory,
+                           fonts: &Fonts,
+                           fade: Option<Fade>)
+                           -> Option<Link> {
+    let mut clicked = None;
+    let rows: usize = channels.iter().map(|&id| history.channel_len(id)).sum();
+    let (start, end) = clip_rows(ui, rows);
+    for msg in history.iter_channel_rows(channels).skip(start).take(end - start) {
+        if history.is_hidden(msg) {
+            continue;
+        }
         if let Some(channel) = history.lookup_channel(msg.channel_id) {
-            print_chat_msg(&ui, channel.text_color, msg.to_owned());
+            let font = channel_font(fonts, channel);
+            clicked = print_chat_msg(&ui, channel.text_color, font, fade, msg).or(clicked);
+        }
+    }
+    skip_rows(ui, rows - end);
+    clicked
+}
+
+// Which of `rows` rows are in view, skipping over the ones before. This is what imgui's list
+// clipper does, which imgui-sys doesn't expose. Every row is taken to be one line of text high,
+// rows that wrap or are hidden only shift the rest while they are in view.
+fn clip_rows<'a>(ui: &Ui<'a>, rows: usize) -> (usize, usize) {
+    let (height, cursor_y, scroll_y, window_height) = unsafe {
+        (imgui_sys::igGetTextLineHeightWithSpacing(),
+         imgui_sys::igGetCursorPosY(),
+         imgui_sys::igGetScrollY(),
+         imgui_sys::igGetWindowHeight())
+    };
+    let start = ((scroll_y - cursor_y) / height).max(0.0) as usize;
+    let end = ((scroll_y + window_height - cursor_y) / height).ceil().max(0.0) as usize;
+    let (start, end) = (min!(start, rows), min!(end, rows));
+    skip_rows(ui, start);
+    (start, max!(start, end))
+}
+
+// Leave the space `rows` lines of text would take.
+fn skip_rows<'a>(ui: &Ui<'a>, rows: usize) {
+    if rows == 0 {
+        return;
+    }
+    // Like any other item the dummy is followed by the item spacing, which is part of a line.
+    let height = unsafe { imgui_sys::igGetTextLineHeightWithSpacing() } * rows as f32 -
+                 ui.imgui().style().item_spacing.y;
+    unsafe { imgui_sys::igDummy(&ImVec2::new(0.0, height)) };
+}
+
+// Returns the link that was clicked, if any.
+fn print_conversation<'a>(ui: &Ui<'a>,
+                          partner: &str,
+                          history: &ChatHistory,
+                          fonts: &Fonts,
+                          fade: Option<Fade>)
+                          -> Option<Link> {
+    let mut clicked = None;
+    for msg in history.iter_conversation(partner) {
+        let channel = history.lookup_any_channel(msg.channel_id);
+        let color = channel.map(|x| x.text_color).unwrap_or(color::WHITE);
+        let font = channel.and_then(|x| channel_font(fonts, x));
+        clicked = print_chat_msg(&ui, color, font, fade, msg).or(clicked);
+    }
+    clicked
+}
+
+// What clicking a link in a chat message does.
+fn follow_link(state: &mut State, link: Link) {
+    match link {
+        Link::Player(name) => {
+            let whisper = format!("/w {} ", name);
+            state.ui_buffers.chat_input_buffer.clear();
+            state.ui_buffers.chat_input_buffer.push_str(&whisper);
+            state.chat_window_state.user_editing = true;
         }
+        Link::Item(name) => state.item_tooltip = Some(name),
+        Link::Location(pos) => state.player.camera.point_at(pos.into()),
     }
 }
 
+// Links aren't followed from here, the full history is only for reading.
 fn print_all_chat_message<'a>(ui: &Ui<'a>, history: &ChatHistory) {
     for msg in history.iter_backup() {
-        if let Some(channel) = history.lookup_channel(msg.channel_id) {
-            print_chat_msg(&ui, channel.text_color, msg.to_owned());
+        if let Some(channel) = history.lookup_any_channel(msg.channel_id) {
+            print_chat_msg(&ui, channel.text_color, None, None, msg);
         }
     }
     for msg in history.iter_history() {
         if let Some(channel) = history.lookup_channel(msg.channel_id) {
-            print_chat_msg(&ui, channel.text_color, msg.to_owned());
+            print_chat_msg(&ui, channel.text_color, None, None, msg);
+        }
+    }
+}
+
+// Print a message with the parts matched by a search drawn in a highlight color. Matches from the
+// history backup are faded.
+fn print_search_match<'a>(ui: &Ui<'a>,
+                          text_color: [f32; 4],
+                          msg: &ChatMessage,
+                          found: &SearchMatch) {
+    const HIGHLIGHT_COLOR: [f32; 4] = color::YELLOW;
+    const BACKUP_ALPHA: f32 = 0.5;
+    let fade = |color: [f32; 4]| if found.in_backup {
+        [color[0], color[1], color[2], color[3] * BACKUP_ALPHA]
+    } else {
+        color
+    };
+    let (text_color, highlight_color) = (fade(text_color), fade(HIGHLIGHT_COLOR));
+    let prefix = chat_msg_prefix(msg);
+    let text = msg.text.as_str();
+    let mut segments = vec![(prefix.as_str(), text_color)];
+    let mut pos = 0;
+    for &(start, end) in &found.ranges {
+        segments.push((&text[pos..start], text_color));
+        segments.push((&text[start..end], highlight_color));
+        pos = end;
+    }
+    segments.push((&text[pos..], text_color));
+
+    let segments = segments.iter().filter(|&&(text, _)| !text.is_empty());
+    for (idx, &(text, color)) in segments.enumerate() {
+        if idx > 0 {
+            ui.same_line_spacing(0.0, 0.0);
         }
+        let text = im_string(text);
+        ui.text_colored(color, &text);
     }
 }
 
-fn add_chat_button<'a>(text: &ImStr,
+fn add_chat_button<'a>(name: &str,
                        button_color: [f32; 4],
                        text_padding: (f32, f32),
+                       unread: Unread,
                        ui: &Ui<'a>)
                        -> bool {
+    // Show the unread count as a badge after the name. Everything after "###" only goes into the
+    // button's id, which keeps the id stable while the count changes.
+    let text = if unread.is_empty() {
+        format!("{}###{}", name, name)
+    } else {
+        format!("{} ({})###{}", name, unread.count, name)
+    };
+    let text = im_string(text);
     let dont_wrap = -1.0;
-    let text_size = ui.calc_text_size(text, false, dont_wrap);
+    let hide_text_after_double_hash = true;
+    let text_size = ui.calc_text_size(&text, hide_text_after_double_hash, dont_wrap);
+
+    // Flash the buttons of channels with unread highlighted messages.
+    const FLASH_PERIOD_MS: u64 = 500;
+    const FLASH_COLOR: [f32; 4] = color::ORANGE;
+    let flash_on = unread.highlights > 0 && (chat_history::now() / FLASH_PERIOD_MS) % 2 == 0;
+    let button_color = if flash_on { FLASH_COLOR } else { button_color };
 
     const COLOR_FACTOR: f32 = 4.0;
     let (r, g, b, a) = (button_color[0], button_color[1], button_color[2], button_color[3]);
@@ -79,7 +427,7 @@ fn add_chat_button<'a>(text: &ImStr,
 
     let mut pressed = false;
     ui.with_color_var(ImGuiCol::Button, button_color, || {
-        pressed = ui.button(text, button_size);
+        pressed = ui.button(&text, button_size);
     });
 
     // setting the POS_X to 0.0 tells imgui to place the next item immediately after the last item,
@@ -112,13 +460,13 @@ fn create_rename_chat_channel<'a>(ui: &Ui<'a>,
                 ui_buffers.menu_input_buffer.push_str(channel_name);
             }
             let text = "Rename channel: ".to_owned();
-            let mut text = unsafe { ImString::from_string_unchecked(text) };
+            let mut text = im_string(text);
             ui.text(&text);
 
             ui.same_line(0.0);
             if let Some(channel) = chat_history.lookup_channel(id) {
                 let text = channel_name.to_owned();
-                let text = unsafe { ImString::from_string_unchecked(text) };
+                let text = im_string(text);
                 ui.text_colored(channel.text_color, &text);
             };
             text.clear();
@@ -136,7 +484,119 @@ fn create_rename_chat_channel<'a>(ui: &Ui<'a>,
                 button_was_pressed = true;
                 let renamed = chat_history.rename_channel(id, &ui_buffers.menu_input_buffer);
                 if !renamed {
-                    panic!("error renaming channel!");
+                    let msg = format!("Channel {} can't be renamed.", channel_name);
+                    chat_history.send_system_message(&msg);
+                }
+            }
+
+            if button_was_pressed {
+                *edit_chat_field_option = EditingFieldOption::NotEditing;
+                ui_buffers.menu_input_buffer.clear();
+            }
+        });
+}
+
+// Rename a chat window, or one of its tabs if `tab` is given.
+fn create_rename_chat_layout<'a>(ui: &Ui<'a>,
+                                 id: ChatWindowId,
+                                 tab: Option<usize>,
+                                 edit_chat_field_option: &mut EditingFieldOption,
+                                 chat_layout: &mut ChatLayout,
+                                 ui_buffers: &mut UiBuffers) {
+    let current_name = match (chat_layout.window(id), tab) {
+        (Some(window), None) => Some(window.name.clone()),
+        (Some(window), Some(tab)) => window.tabs.get(tab).map(|x| x.name.clone()),
+        (None, _) => None,
+    };
+    let current_name = match current_name {
+        Some(name) => name,
+        None => {
+            // The window or tab went away while the dialog was open.
+            *edit_chat_field_option = EditingFieldOption::NotEditing;
+            ui_buffers.menu_input_buffer.clear();
+            return;
+        }
+    };
+    let title = if tab.is_some() {
+        im_str!("Rename Tab")
+    } else {
+        im_str!("Rename Window")
+    };
+    ui.window(title)
+        .position((100.0, 100.0), ImGuiSetCond_FirstUseEver)
+        .title_bar(true)
+        .movable(true)
+        .resizable(false)
+        .save_settings(false)
+        .inputs(true)  // interacting with buttons.
+        .collapsible(false)
+        .scroll_bar(false)
+        .always_auto_resize(true)
+        .build(|| {
+            if ui_buffers.menu_input_buffer.is_empty() {
+                ui_buffers.menu_input_buffer.push_str(&current_name);
+            }
+            ui.text(im_str!("Name: "));
+            ui.same_line(0.0);
+            ui.input_text(im_str!(""), &mut ui_buffers.menu_input_buffer)
+                .auto_select_all(true)
+                .build();
+            ui.new_line();
+
+            let button_size = (100.0, 20.0);
+            let mut button_was_pressed = ui.button(im_str!("Cancel"), button_size);
+            ui.same_line(0.0);
+            if ui.button(im_str!("Ok"), button_size) {
+                button_was_pressed = true;
+                let name = ui_buffers.menu_input_buffer.trim().to_owned();
+                if !name.is_empty() {
+                    if let Some(window) = chat_layout.window_mut(id) {
+                        match tab {
+                            Some(tab) => window.tabs[tab].name = name,
+                            None => window.name = name,
+                        }
+                    }
+                }
+            }
+
+            if button_was_pressed {
+                *edit_chat_field_option = EditingFieldOption::NotEditing;
+                ui_buffers.menu_input_buffer.clear();
+            }
+        });
+}
+
+fn create_new_chat_channel<'a>(ui: &Ui<'a>,
+                               edit_chat_field_option: &mut EditingFieldOption,
+                               chat_history: &mut ChatHistory,
+                               ui_buffers: &mut UiBuffers) {
+    ui.window(im_str!("New Channel"))
+        .position((100.0, 100.0), ImGuiSetCond_FirstUseEver)
+        .title_bar(true)
+        .movable(true)
+        .resizable(false)
+        .save_settings(false)
+        .inputs(true)  // interacting with buttons.
+        .collapsible(false)
+        .scroll_bar(false)
+        .always_auto_resize(true)
+        .build(|| {
+            ui.text(im_str!("Channel name: "));
+            ui.same_line(0.0);
+            ui.input_text(im_str!(""), &mut ui_buffers.menu_input_buffer)
+                .auto_select_all(true)
+                .chars_noblank(true)
+                .build();
+            ui.new_line();
+
+            let button_size = (100.0, 20.0);
+            let mut button_was_pressed = ui.button(im_str!("Cancel"), button_size);
+            ui.same_line(0.0);
+            if ui.button(im_str!("Ok"), button_size) {
+                button_was_pressed = true;
+                let name = ui_buffers.menu_input_buffer.trim().to_owned();
+                if !name.is_empty() && chat_history.find_channel(&name).is_none() {
+                    chat_history.create_channel(&name, color::WHITE);
                 }
             }
 
@@ -167,7 +627,7 @@ fn create_set_channel_text_color<'a>(ui: &Ui<'a>,
             if let Some(channel) = chat_history.lookup_channel_mut(id) {
                 let color = (0.4, 0.4, 0.4, 1.0);
                 ui.text_colored(color, im_str!("Edit text color channel "));
-                let channel_name = unsafe { ImString::from_string_unchecked(channel.name.clone()) };
+                let channel_name = im_string(channel.name.clone());
                 ui.text_colored(channel.text_color.clone(), &channel_name);
                 ui.new_line();
 
@@ -253,7 +713,8 @@ fn create_set_maximum_chat_history<'a>(ui: &Ui<'a>,
 
 fn create_view_all_chat_history<'a>(ui: &Ui<'a>,
                                     edit_chat_field_option: &mut EditingFieldOption,
-                                    chat_history: &mut ChatHistory) {
+                                    chat_history: &mut ChatHistory,
+                                    ui_buffers: &mut UiBuffers) {
     ui.window(im_str!("Examine Chat"))
             .position((100.0, 100.0), ImGuiSetCond_FirstUseEver)
             .size((600.0, 400.0), ImGuiSetCond_FirstUseEver)
@@ -265,9 +726,61 @@ fn create_view_all_chat_history<'a>(ui: &Ui<'a>,
             .collapsible(false)
             .scroll_bar(false)
             .build(|| {
+                ui.input_text(im_str!("Search"), &mut ui_buffers.search_input_buffer)
+                    .auto_select_all(true)
+                    .build();
+                ui.same_line(0.0);
+                ui.checkbox(im_str!("Regex"), &mut ui_buffers.search_regex);
+                ui.input_text(im_str!("From"), &mut ui_buffers.search_sender_buffer)
+                    .auto_select_all(true)
+                    .build();
+                ui.input_int(im_str!("Last minutes"), &mut ui_buffers.search_minutes).build();
+                ui_buffers.search_minutes = max!(0, ui_buffers.search_minutes);
+                ui.text(im_str!("Channels:"));
+                for channel in chat_history.channels() {
+                    ui.same_line(0.0);
+                    let mut checked = ui_buffers.search_channels.contains(&channel.id);
+                    let label = im_string(format!("{}##search", channel.name));
+                    if ui.checkbox(&label, &mut checked) {
+                        if checked {
+                            ui_buffers.search_channels.push(channel.id);
+                        } else {
+                            ui_buffers.search_channels.retain(|&x| x != channel.id);
+                        }
+                    }
+                }
+
+                let inputs = SearchInputs {
+                    text: ui_buffers.search_input_buffer.trim().to_owned(),
+                    regex: ui_buffers.search_regex,
+                    sender: ui_buffers.search_sender_buffer.trim().to_owned(),
+                    minutes: ui_buffers.search_minutes,
+                    channels: ui_buffers.search_channels.clone(),
+                    revision: chat_history.revision(),
+                };
+                SearchCache::update(&mut ui_buffers.search_cache, inputs, chat_history);
+                let results = ui_buffers.search_cache.as_ref().map(|x| &x.results);
                 ui.child_frame(im_str!(""), (0.0, -25.0))
                     .build(|| {
-                        print_all_chat_message(&ui, chat_history);
+                        match results {
+                            Some(&Ok(Some(ref found))) => {
+                                for found in found {
+                                    let msg = match chat_history.message(found.message) {
+                                        Some(msg) => msg,
+                                        None => continue,
+                                    };
+                                    if let Some(channel) =
+                                           chat_history.lookup_any_channel(msg.channel_id) {
+                                        print_search_match(&ui, channel.text_color, msg, found);
+                                    }
+                                }
+                            }
+                            Some(&Ok(None)) | None => print_all_chat_message(&ui, chat_history),
+                            Some(&Err(ref e)) => {
+                                let error = im_string(e.clone());
+                                ui.text_colored(color::RED, &error);
+                            }
+                        }
                     });
                 let button_size = (100.0, 20.0);
                 if ui.button(im_str!("Done"), button_size) {
@@ -276,7 +789,141 @@ fn create_view_all_chat_history<'a>(ui: &Ui<'a>,
             });
 }
 
-fn show_main_menu<'a>(ui: &Ui<'a>, state: &mut State) {
+fn export_chat(chat_history: &mut ChatHistory, scope: &ExportScope, format: ExportFormat) {
+    const EXPORT_DIR: &str = "data/exports";
+    let result = chat_export::export_to_dir(chat_history, scope, format, EXPORT_DIR);
+    let notice = match result {
+        Ok(path) => format!("Chat exported to {}.", path.display()),
+        Err(e) => format!("Error exporting chat: {}", e),
+    };
+    chat_history.send_system_message(&notice);
+}
+
+// Pick one of the loaded fonts, or imgui's default one. Returns true if the pick changed.
+fn show_font_menu<'a>(ui: &Ui<'a>, fonts: &Fonts, font: &mut Option<ChatFont>) -> bool {
+    let mut changed = false;
+    let mut default = font.is_none();
+    if ui.menu_item(im_str!("Default")).selected(&mut default).build() {
+        *font = None;
+        changed = true;
+    }
+    let names = fonts.names();
+    if names.is_empty() {
+        let label = im_string(format!("No fonts in {}", chat_font::FONTS_PATH));
+        ui.menu_item(&label).enabled(false).build();
+    }
+    for name in names {
+        let label = im_string(name);
+        ui.menu(&label).build(|| {
+            for &size in chat_font::FONT_SIZES {
+                let pick = ChatFont {
+                    name: name.to_owned(),
+                    size: size,
+                };
+                let mut selected = font.as_ref() == Some(&pick);
+                let label = im_string(format!("{} px", size));
+                if ui.menu_item(&label).selected(&mut selected).build() {
+                    *font = Some(pick);
+                    changed = true;
+                }
+            }
+        });
+    }
+    changed
+}
+
+// The Windows submenu of the Chat menu.
+fn show_chat_layout_menu<'a>(ui: &Ui<'a>,
+                             chat_layout: &mut ChatLayout,
+                             chat_history: &ChatHistory,
+                             fonts: &Fonts,
+                             edit_chat_field: &mut EditingFieldOption) {
+    let windows = chat_layout.windows().to_vec();
+    let removable_window = windows.len() > 1;
+    for window in &windows {
+        let label = format!("{}###ChatWindowMenu{}", window.name, window.id);
+        let label = im_string(label);
+        ui.menu(&label).build(|| {
+            let removable_tab = window.tabs.len() > 1;
+            for (idx, tab) in window.tabs.iter().enumerate() {
+                let label = format!("{}###ChatTabMenu{}", tab.name, idx);
+                let label = im_string(label);
+                ui.menu(&label).build(|| {
+                    let mut tab = tab.clone();
+                    if show_chat_tab_menu(ui, &mut tab, chat_history) {
+                        if let Some(window) = chat_layout.window_mut(window.id) {
+                            window.tabs[idx] = tab;
+                        }
+                    }
+                    if ui.menu_item(im_str!("Rename")).build() {
+                        *edit_chat_field = EditingFieldOption::ChatTabName(window.id, idx);
+                    }
+                    if ui.menu_item(im_str!("Remove")).enabled(removable_tab).build() {
+                        chat_layout.remove_tab(window.id, idx);
+                    }
+                });
+            }
+            if ui.menu_item(im_str!("New Tab")).build() {
+                let name = format!("Tab {}", window.tabs.len() + 1);
+                chat_layout.add_tab(window.id, ChatTab::all(&name));
+            }
+            if ui.menu_item(im_str!("Rename")).build() {
+                *edit_chat_field = EditingFieldOption::ChatWindowName(window.id);
+            }
+            ui.menu(im_str!("Font")).build(|| {
+                let mut font = window.font.clone();
+                if show_font_menu(ui, fonts, &mut font) {
+                    if let Some(window) = chat_layout.window_mut(window.id) {
+                        window.font = font;
+                    }
+                }
+            });
+            if ui.menu_item(im_str!("Close")).enabled(removable_window).build() {
+                chat_layout.remove_window(window.id);
+            }
+        });
+    }
+    if ui.menu_item(im_str!("New Window")).build() {
+        let name = format!("Window {}", windows.len() + 1);
+        chat_layout.add_window(&name, vec![]);
+    }
+}
+
+// The channels a tab subscribes to, and where it sends. Returns true if the tab changed.
+fn show_chat_tab_menu<'a>(ui: &Ui<'a>, tab: &mut ChatTab, chat_history: &ChatHistory) -> bool {
+    let mut changed = false;
+    let mut all_channels = tab.all_channels;
+    if ui.menu_item(im_str!("All Channels")).selected(&mut all_channels).build() {
+        tab.all_channels = all_channels;
+        changed = true;
+    }
+    ui.separator();
+    for channel in chat_history.channels() {
+        let name = im_string(channel.name.clone());
+        let mut subscribed = tab.is_subscribed(channel.id);
+        if ui.menu_item(&name).selected(&mut subscribed).build() {
+            tab.set_subscribed(channel.id, subscribed, chat_history.channels());
+            changed = true;
+        }
+    }
+    ui.separator();
+    ui.menu(im_str!("Send To")).build(|| {
+        for channel in chat_history.channels() {
+            let name = im_string(channel.name.clone());
+            let mut selected = tab.send_channel == channel.id;
+            if ui.menu_item(&name).selected(&mut selected).build() {
+                tab.send_channel = channel.id;
+                changed = true;
+            }
+        }
+    });
+    changed
+}
+
+fn show_main_menu<'a>(ui: &Ui<'a>,
+                      state: &mut State,
+                      entities: &EntitiesRes,
+                      lights: &mut WriteStorage<Light>) {
     ui.main_menu_bar(|| {
         ui.menu(im_str!("Menu")).build(|| {
             ui.menu_item(im_str!("Exit")).selected(&mut state.quit).build();
@@ -289,34 +936,111 @@ fn show_main_menu<'a>(ui: &Ui<'a>, state: &mut State) {
             if ui.menu_item(im_str!("View All")).build() {
                 *edit_chat_field = EditingFieldOption::ChatHistoryViewAll;
             }
+            ui.menu_item(im_str!("Damage Meter"))
+                .selected(&mut state.show_damage_meter)
+                .build();
+            let mut reveal_hidden = state.chat_history.reveal_hidden();
+            if ui.menu_item(im_str!("Show Hidden")).selected(&mut reveal_hidden).build() {
+                state.chat_history.set_reveal_hidden(reveal_hidden);
+            }
+            ui.menu_item(im_str!("Auto-Scroll"))
+                .selected(&mut state.chat_window_state.auto_scroll)
+                .build();
+            ui.menu(im_str!("Fade Messages")).build(|| {
+                let chat_window_state = &mut state.chat_window_state;
+                ui.menu_item(im_str!("Enabled")).selected(&mut chat_window_state.fade).build();
+                ui.separator();
+                for &seconds in &[10, 30, 60, 120, 300] {
+                    let delay = seconds * 1000;
+                    let mut selected = chat_window_state.fade_delay == delay;
+                    let label = im_string(format!("After {} seconds", seconds));
+                    if ui.menu_item(&label)
+                        .selected(&mut selected)
+                        .enabled(chat_window_state.fade)
+                        .build() {
+                        chat_window_state.fade_delay = delay;
+                    }
+                }
+            });
             let chat_history = &mut state.chat_history;
             let ui_buffers = &mut state.ui_buffers;
-            for (idx, &(ref channel_name, _)) in chat_history.channel_names().iter().enumerate() {
-                let cn = unsafe { ImString::from_string_unchecked(channel_name.clone()) };
+            let fonts = &state.fonts;
+            let channels: Vec<(ChannelId, String)> = chat_history.channels()
+                .iter()
+                .map(|x| (x.id, x.name.to_string()))
+                .collect();
+            let last_idx = channels.len().saturating_sub(1);
+            for (idx, &(channel_id, ref channel_name)) in channels.iter().enumerate() {
+                let cn = im_string(channel_name.clone());
                 ui.menu(&cn).build(|| {
-                    let channel_id = ChannelId::new(idx);
                     if ui.menu_item(im_str!("Name")).build() {
                         *edit_chat_field = EditingFieldOption::ChannelName(channel_id,
                                                                            channel_name.to_owned());
                     }
                     if ui.menu_item(im_str!("Color")).build() {
-                        if let Some(channel) =
-                               chat_history.lookup_channel_mut(ChannelId::new(idx)) {
+                        if let Some(channel) = chat_history.lookup_channel_mut(channel_id) {
                             ui_buffers.menu_color_buffer = channel.text_color;
                             // Store the color currently in the buffer for later.
                             ui_buffers.menu_color_buffer_backup = ui_buffers.menu_color_buffer;
                             *edit_chat_field = EditingFieldOption::ChannelColorText(channel_id);
                         };
                     }
-                    ui.menu_item(im_str!("Font")).build();
+                    ui.menu(im_str!("Font")).build(|| {
+                        let mut font = chat_history.lookup_channel(channel_id)
+                            .and_then(|x| x.font.clone());
+                        if show_font_menu(ui, fonts, &mut font) {
+                            chat_history.set_channel_font(channel_id, font);
+                        }
+                    });
+                    let mut muted = chat_history.is_channel_muted(channel_id);
+                    let mutable = Some(channel_id) != chat_history.system_channel();
+                    if ui.menu_item(im_str!("Mute")).selected(&mut muted).enabled(mutable).build() {
+                        chat_history.set_channel_muted(channel_id, muted);
+                    }
+                    if ui.menu_item(im_str!("Move Up")).enabled(idx > 0).build() {
+                        chat_history.move_channel(channel_id, idx - 1);
+                    }
+                    if ui.menu_item(im_str!("Move Down")).enabled(idx < last_idx).build() {
+                        chat_history.move_channel(channel_id, idx + 1);
+                    }
+                    let removable = channel_id != DEFAULT_CHANNEL;
+                    if ui.menu_item(im_str!("Remove")).enabled(removable).build() {
+                        chat_history.remove_channel(channel_id);
+                    }
                 });
             }
+            if ui.menu_item(im_str!("New Channel")).build() {
+                *edit_chat_field = EditingFieldOption::NewChannel;
+            }
             if ui.menu_item(im_str!("Max Length")).build() {
                 let prune = chat_history.get_prune();
                 ui_buffers.menu_int_buffer_backup = prune.length;
                 ui_buffers.menu_bool_buffer_backup = prune.enabled;
                 *edit_chat_field = EditingFieldOption::ChatHistoryMaximumLength;
             }
+            let chat_layout = &mut state.chat_layout;
+            ui.menu(im_str!("Windows")).build(|| {
+                show_chat_layout_menu(ui, chat_layout, chat_history, fonts, edit_chat_field);
+            });
+            let visible_channels = chat_layout.input_tab()
+                .map(|x| x.channel_ids(chat_history.channels()))
+                .unwrap_or_default();
+            ui.menu(im_str!("Export")).build(|| {
+                let formats = [(ExportFormat::Text, "Text"),
+                               (ExportFormat::JsonLines, "JSON Lines"),
+                               (ExportFormat::Html, "HTML")];
+                let scopes = [(ExportScope::Visible(visible_channels), "Visible"),
+                              (ExportScope::Full, "Full History")];
+                for &(ref scope, scope_name) in &scopes {
+                    for &(format, format_name) in &formats {
+                        let label = format!("{} as {}", scope_name, format_name);
+                        let label = im_string(label);
+                        if ui.menu_item(&label).build() {
+                            export_chat(chat_history, scope, format);
+                        }
+                    }
+                }
+            });
             let chat_window_state = &mut state.chat_window_state;
             ui.menu_item(im_str!("Movable"))
                 .selected(&mut chat_window_state.movable)
@@ -327,32 +1051,23 @@ fn show_main_menu<'a>(ui: &Ui<'a>, state: &mut State) {
             ui.menu_item(im_str!("Save Settings"))
                 .selected(&mut chat_window_state.save_settings)
                 .build();
+            ui.menu_item(im_str!("Save Chat Settings"))
+                .selected(&mut chat_window_state.save_chat_settings)
+                .build();
         });
 
         let position = state.player.camera.position();
         let rotation = state.player.camera.rotation();
-        ui.menu(im_str!("Diffuse Light")).build(|| {
-            {
-                let pos = format!("Position: [{}, {}, {}]",
-                                  state.diffuse_color_pos[0],
-                                  state.diffuse_color_pos[1],
-                                  state.diffuse_color_pos[2]);
-                let position = unsafe { ImString::from_string_unchecked(pos) };
-                ui.menu_item(&position).build();
-            }
-            {
-                let c = format!("Color: [{}, {}, {}, {}]",
-                                state.diffuse_color[0],
-                                state.diffuse_color[1],
-                                state.diffuse_color[2],
-                                state.diffuse_color[3]);
-                let color = unsafe { ImString::from_string_unchecked(c) };
-                ui.menu_item(&color).build();
-            }
-        });
+        {
+            let selected_light = &mut state.selected_light;
+            let new_light = &mut state.new_light;
+            ui.menu(im_str!("Lights")).build(|| {
+                show_lights_menu(ui, entities, lights, selected_light, new_light);
+            });
+        }
         {
             let pos = format!("Position: [{}, {}, {}]", position.x, position.y, position.z);
-            let pos = unsafe { ImString::from_string_unchecked(pos) };
+            let pos = im_string(pos);
             ui.with_color_var(ImGuiCol::TextDisabled, color::BLACK, || {
                 ui.menu(&pos).enabled(false).build(|| {});
             });
@@ -362,7 +1077,7 @@ fn show_main_menu<'a>(ui: &Ui<'a>, state: &mut State) {
             let rot = (Deg::from(rot.x), Deg::from(rot.y), Deg::from(rot.z));
             let (x, y, z) = rot;
             let fmt = format!("Rotation: [{}, {}, {}]", x.0, y.0, z.0);
-            let rotation = unsafe { ImString::from_string_unchecked(fmt) };
+            let rotation = im_string(fmt);
             ui.with_color_var(ImGuiCol::TextDisabled, color::PINK, || {
                 ui.menu(&rotation).enabled(false).build(|| {});
             });
@@ -370,7 +1085,7 @@ fn show_main_menu<'a>(ui: &Ui<'a>, state: &mut State) {
         {
             let framerate = state.framerate;
             let fps = "Framerate: ".to_string() + &framerate.to_string();
-            let fps = unsafe { ImString::from_string_unchecked(fps) };
+            let fps = im_string(fps);
             ui.with_color_var(ImGuiCol::TextDisabled, color::GREEN_YELLOW, || {
                 ui.menu(&fps).enabled(false).build(|| {});
             });
@@ -378,7 +1093,283 @@ fn show_main_menu<'a>(ui: &Ui<'a>, state: &mut State) {
     });
 }
 
-fn show_chat_window<'a>(ui: &Ui<'a>, state: &mut State) {
+fn show_lights_menu<'a>(ui: &Ui<'a>,
+                        entities: &EntitiesRes,
+                        lights: &mut WriteStorage<Light>,
+                        selected_light: &mut Option<Entity>,
+                        new_light: &mut Option<Light>) {
+    let kinds = [LightKind::Point, LightKind::Directional, LightKind::Spot];
+    let mut removed = None;
+    for (entity, light) in (entities, &mut *lights).join() {
+        let label = format!("{} ({})##light{}", light.name, light.kind.name(), entity.id());
+        ui.menu(&im_string(label)).build(|| {
+            ui.menu_item(im_str!("Enabled")).selected(&mut light.enabled).build();
+            let mut selected = *selected_light == Some(entity);
+            if ui.menu_item(im_str!("Move With Arrow Keys")).selected(&mut selected).build() {
+                *selected_light = if selected { Some(entity) } else { None };
+            }
+            ui.menu(im_str!("Kind")).build(|| {
+                for &kind in &kinds {
+                    let mut current = light.kind == kind;
+                    if ui.menu_item(&im_string(kind.name())).selected(&mut current).build() {
+                        light.kind = kind;
+                    }
+                }
+            });
+            ui.separator();
+            ui.color_edit4(im_str!("Color"), &mut light.color).build();
+            if light.kind != LightKind::Directional {
+                ui.input_float3(im_str!("Position"), &mut light.position).build();
+                ui.input_float3(im_str!("Attenuation"), &mut light.attenuation).build();
+            }
+            if light.kind != LightKind::Point {
+                ui.input_float3(im_str!("Direction"), &mut light.direction).build();
+            }
+            if light.kind == LightKind::Spot {
+                // The light fades out between the two angles, so the inner one can't pass the
+                // outer one.
+                let outer = light.outer_angle;
+                ui.slider_float(im_str!("Inner Angle"), &mut light.inner_angle, 0.0, outer).build();
+                light.inner_angle = light.inner_angle.max(0.0).min(light.outer_angle);
+                let inner = light.inner_angle;
+                ui.slider_float(im_str!("Outer Angle"), &mut light.outer_angle, inner, 90.0)
+                    .build();
+                light.outer_angle = light.outer_angle.max(light.inner_angle).min(90.0);
+            }
+            ui.separator();
+            if ui.menu_item(im_str!("Remove")).build() {
+                removed = Some(entity);
+            }
+        });
+    }
+    if let Some(entity) = removed {
+        entities.delete(entity);
+        if *selected_light == Some(entity) {
+            *selected_light = None;
+        }
+    }
+
+    ui.separator();
+    ui.menu(im_str!("Add")).build(|| {
+        let position = [0.0, 0.0, 0.0];
+        let down = [0.0, -1.0, 0.0];
+        if ui.menu_item(im_str!("Point Light")).build() {
+            *new_light = Some(Light::point("Point", position, color::WHITE));
+        }
+        if ui.menu_item(im_str!("Directional Light")).build() {
+            *new_light = Some(Light::directional("Directional", down, color::WHITE));
+        }
+        if ui.menu_item(im_str!("Spot Light")).build() {
+            *new_light = Some(Light::spot("Spot", position, down, color::WHITE));
+        }
+    });
+}
+
+fn show_damage_meter<'a>(ui: &Ui<'a>, damage_meter: &mut DamageMeter, opened: &mut bool) {
+    let now = chat_history::now();
+    let mut reset_pressed = false;
+    ui.window(im_str!("Damage Meter"))
+        .position((100.0, 100.0), ImGuiSetCond_FirstUseEver)
+        .size((320.0, 240.0), ImGuiSetCond_FirstUseEver)
+        .opened(opened)
+        .title_bar(true)
+        .movable(true)
+        .resizable(true)
+        .save_settings(false)
+        .inputs(true)  // interacting with buttons.
+        .collapsible(true)
+        .build(|| {
+            let window_secs = damage_meter.dps_window() / 1000;
+            let dps = format!("DPS (last {}s): {:.1} dealt, {:.1} taken",
+                              window_secs,
+                              damage_meter.dps(DamageDirection::Dealt, now),
+                              damage_meter.dps(DamageDirection::Taken, now));
+            let dps = im_string(dps);
+            ui.text(&dps);
+
+            let current = match damage_meter.current_encounter(now) {
+                Some(encounter) => {
+                    format!("Current encounter: {} dealt, {} taken over {:.0}s",
+                            encounter.dealt,
+                            encounter.taken,
+                            encounter.duration_secs())
+                }
+                None => String::from("Out of combat"),
+            };
+            let current = im_string(current);
+            ui.text_colored(color::GRAY, &current);
+            ui.separator();
+
+            ui.columns(4, im_str!("encounters"), true);
+            for header in &[im_str!("#"), im_str!("Dealt"), im_str!("Taken"), im_str!("DPS")] {
+                ui.text(header);
+                ui.next_column();
+            }
+            ui.separator();
+            // Most recent encounter first.
+            for (idx, encounter) in damage_meter.encounters().iter().enumerate().rev() {
+                let cells = [format!("{}", idx + 1),
+                             format!("{}", encounter.dealt),
+                             format!("{}", encounter.taken),
+                             format!("{:.1}", encounter.dps(DamageDirection::Dealt))];
+                for cell in cells.iter() {
+                    let cell = im_string(cell.clone());
+                    ui.text(&cell);
+                    ui.next_column();
+                }
+            }
+            ui.columns(1, im_str!(""), false);
+            ui.separator();
+
+            let totals = format!("Total: {} dealt, {} taken",
+                                 damage_meter.total(DamageDirection::Dealt),
+                                 damage_meter.total(DamageDirection::Taken));
+            let totals = im_string(totals);
+            ui.text(&totals);
+            reset_pressed = ui.button(im_str!("Reset"), (100.0, 20.0));
+        });
+    if reset_pressed {
+        damage_meter.reset();
+    }
+}
+
+// What the chat input callback needs, passed to imgui as user data.
+struct ChatInputCallbackData<'a> {
+    input_history: &'a mut InputHistory,
+    commands: &'a CommandRegistry,
+    chat_history: &'a ChatHistory,
+
+    // Set when Tab was pressed, so the caller can list the candidates.
+    completion: Option<Completion>,
+}
+
+// Replace the contents of the input being edited, placing the cursor at byte offset `cursor`.
+unsafe fn replace_input_text(data: &mut ImGuiTextEditCallbackData, text: &str, cursor: usize) {
+    let mut len = min!(text.len(), max!(data.buf_size - 1, 0) as usize);
+    while !text.is_char_boundary(len) {
+        len -= 1;
+    }
+    ptr::copy_nonoverlapping(text.as_ptr(), data.buf as *mut u8, len);
+    *data.buf.offset(len as isize) = 0;
+    let cursor = min!(cursor, len) as c_int;
+    data.buf_text_len = len as c_int;
+    data.cursor_pos = cursor;
+    data.selection_start = cursor;
+    data.selection_end = cursor;
+    data.buf_dirty = true;
+}
+
+extern "C" fn chat_input_callback(data: *mut ImGuiTextEditCallbackData) -> c_int {
+    let data = unsafe { &mut *data };
+    let callback_data = unsafe { &mut *(data.user_data as *mut ChatInputCallbackData) };
+    let text = {
+        let len = max!(data.buf_text_len, 0) as usize;
+        let bytes = unsafe { slice::from_raw_parts(data.buf as *const u8, len) };
+        String::from_utf8_lossy(bytes).into_owned()
+    };
+
+    if data.event_flag.contains(ImGuiInputTextFlags_CallbackHistory) {
+        let recalled = match data.event_key {
+            ImGuiKey::UpArrow => callback_data.input_history.previous(&text).map(|x| x.to_owned()),
+            ImGuiKey::DownArrow => callback_data.input_history.next().map(|x| x.to_owned()),
+            _ => None,
+        };
+        if let Some(recalled) = recalled {
+            unsafe { replace_input_text(data, &recalled, recalled.len()) };
+        }
+    } else if data.event_flag.contains(ImGuiInputTextFlags_CallbackCompletion) {
+        let cursor = min!(max!(data.cursor_pos, 0) as usize, text.len());
+        let commands = callback_data.commands.names();
+        let mut words: Vec<String> = callback_data.chat_history
+            .channels()
+            .iter()
+            .map(|x| x.name.to_string())
+            .collect();
+        words.extend(callback_data.chat_history.known_senders());
+
+        if let Some(completion) = chat_input::complete(&text, cursor, &commands, &words) {
+            let mut completed = String::from(&text[..completion.start]);
+            completed.push_str(&completion.text);
+            let new_cursor = completed.len();
+            completed.push_str(&text[cursor..]);
+            unsafe { replace_input_text(data, &completed, new_cursor) };
+            callback_data.completion = Some(completion);
+        }
+    }
+    0
+}
+
+// The chat input line. Unlike `Ui::input_text` this hooks up imgui's history and completion
+// callbacks, so Up/Down recall earlier input and Tab completes the word in front of the cursor.
+fn chat_input_text(buf: &mut ImString, callback_data: &mut ChatInputCallbackData) -> bool {
+    let flags = ImGuiInputTextFlags_EnterReturnsTrue | ImGuiInputTextFlags_CallbackHistory |
+                ImGuiInputTextFlags_CallbackCompletion;
+    unsafe {
+        let entered = imgui_sys::igInputText(im_str!("").as_ptr(),
+                                             buf.as_mut_ptr(),
+                                             buf.capacity_with_nul(),
+                                             flags,
+                                             Some(chat_input_callback),
+                                             callback_data as *mut ChatInputCallbackData as
+                                             *mut c_void);
+        buf.refresh_len();
+        entered
+    }
+}
+
+// Everything the tab's channels received since they were last viewed.
+fn tab_unread(history: &ChatHistory, channels: &[ChannelId]) -> Unread {
+    let mut total = Unread::default();
+    for &id in channels {
+        let unread = history.unread(id);
+        total.count += unread.count;
+        total.highlights += unread.highlights;
+        total.last_highlight = max!(total.last_highlight, unread.last_highlight);
+    }
+    total
+}
+
+// A tab for every open whisper conversation. Right-clicking a tab closes it.
+fn show_conversation_tabs<'a>(ui: &Ui<'a>, state: &mut State, window_id: ChatWindowId) {
+    let color = state.chat_history
+        .find_channel("Whisper")
+        .and_then(|x| state.chat_history.lookup_channel(x))
+        .map(|x| x.text_color)
+        .unwrap_or(color::WHITE);
+    let conversations: Vec<(String, Unread)> = state.chat_history
+        .conversations()
+        .filter(|x| x.open)
+        .map(|x| (x.partner.clone(), x.unread))
+        .collect();
+    for (partner, unread) in conversations {
+        let name = format!("@{}", partner);
+        if add_chat_button(&name, color, (10.0, 7.0), unread, &ui) {
+            state.chat_layout.select_conversation(window_id, &partner);
+        }
+        if unsafe { imgui_sys::igIsItemHovered() } {
+            unsafe { imgui_sys::igBeginTooltip() };
+            ui.text(im_str!("Right-click to close"));
+            unsafe { imgui_sys::igEndTooltip() };
+        }
+        if unsafe { imgui_sys::igIsItemClicked(1) } {
+            state.chat_history.close_conversation(&partner);
+            state.chat_layout.close_conversation(&partner);
+        }
+    }
+}
+
+fn show_chat_windows<'a>(ui: &Ui<'a>, state: &mut State) {
+    // The channel we were sending to may have been removed since the last frame.
+    if state.chat_history.lookup_channel(state.chat_button_pressed).is_none() {
+        state.chat_button_pressed = DEFAULT_CHANNEL;
+    }
+    let windows = state.chat_layout.windows().to_vec();
+    for (idx, window) in windows.iter().enumerate() {
+        show_chat_window(ui, state, window, idx);
+    }
+}
+
+fn show_chat_window<'a>(ui: &Ui<'a>, state: &mut State, window: &ChatWindow, index: usize) {
     let styles = {
         let padding = StyleVar::WindowPadding(ImVec2::new(5.0, 0.0));
         let rounding = StyleVar::WindowRounding(state.chat_window_state.window_rounding);
@@ -389,77 +1380,239 @@ fn show_chat_window<'a>(ui: &Ui<'a>, state: &mut State) {
     };
     let (chat_w, chat_h) = state.chat_window_state.dimensions;
     let (chat_w, chat_h) = (chat_w as f32, chat_h as f32);
-    let window_pos = state.chat_window_state.pos;
+    let window_pos = window.pos.unwrap_or_else(|| {
+        // Stack every window after the first above the previous one.
+        let (x, y) = state.chat_window_state.pos;
+        (x, y - index as f32 * (chat_h + 10.0))
+    });
+    let window_size = window.size.unwrap_or((chat_w, chat_h));
+    // Everything after "###" is the window's id, so renaming a window keeps its position.
+    let title = format!("{}###ChatWindow{}", window.name, window.id);
+    let title = im_string(title);
+    let has_input = window.id == state.chat_layout.input_window();
+    let window_font = window.font.as_ref().and_then(|x| state.fonts.find(x));
+    let mut clicked = None;
 
     ui.with_style_vars(styles, || {
-        ui.window(im_str!("ChatWindow"))
-            .position(window_pos, ImGuiSetCond_FirstUseEver)
-            .size((chat_w, chat_h), ImGuiSetCond_FirstUseEver)
-            .title_bar(false)
-            .movable(state.chat_window_state.movable)
-            .resizable(state.chat_window_state.resizable)
-            .save_settings(state.chat_window_state.save_settings)
-            .inputs(true)  // interacting with buttons.
-            .no_bring_to_front_on_focus(true)
-            .show_borders(false)
-            .always_use_window_padding(false)
-            .scroll_bar(false)
-            .scrollable(false)
-            .build(|| {
-                for (count, channels) in state.chat_history.channel_names().iter().enumerate() {
-                    let &(ref name, color) = channels;
-                    let id = ChannelId::new(count);
-
-                    // 1) Add the channel to the chat_history
-                    state.chat_history.add_channel(id, &name, color);
-
-                    // 2) Draw the button for the chat channel.
-                    let name = unsafe { ImString::from_string_unchecked(name.clone()) };
-                    let pressed = add_chat_button(&name, color, (10.0, 7.0), &ui);
-                    if pressed {
-                        state.chat_button_pressed = id;
+        // Pushed before the window begins, so its title and tabs use the font too.
+        chat_font::with_font(window_font, || {
+            ui.window(&title)
+                .position(window_pos, ImGuiSetCond_FirstUseEver)
+                .size(window_size, ImGuiSetCond_FirstUseEver)
+                .title_bar(false)
+                .movable(state.chat_window_state.movable)
+                .resizable(state.chat_window_state.resizable)
+                .save_settings(state.chat_window_state.save_settings)
+                .inputs(true)  // interacting with buttons.
+                .no_bring_to_front_on_focus(true)
+                .show_borders(false)
+                .always_use_window_padding(false)
+                .scroll_bar(false)
+                .scrollable(false)
+                .build(|| {
+                    for (idx, tab) in window.tabs.iter().enumerate() {
+                        let channels = tab.channel_ids(state.chat_history.channels());
+                        let unread = tab_unread(&state.chat_history, &channels);
+                        let color = state.chat_history
+                            .lookup_channel(tab.send_channel)
+                            .map(|x| x.text_color)
+                            .unwrap_or(color::WHITE);
+                        if add_chat_button(&tab.name, color, (10.0, 7.0), unread, &ui) {
+                            state.chat_layout.select_tab(window.id, idx);
+                            state.chat_button_pressed = tab.send_channel;
+                        }
+                    }
+                    // Open whisper conversations get temporary tabs in the first window.
+                    if index == 0 {
+                        show_conversation_tabs(ui, state, window.id);
+                    }
+                    // A conversation closed since the window selected it falls back to the tab.
+                    let partner = window.conversation
+                        .as_ref()
+                        .and_then(|x| state.chat_history.conversation(x))
+                        .and_then(|x| if x.open { Some(x.partner.clone()) } else { None });
+                    let channels = match partner {
+                        Some(_) => vec![],
+                        None => {
+                            window.active_tab()
+                                .map(|x| x.channel_ids(state.chat_history.channels()))
+                                .unwrap_or_default()
+                        }
+                    };
+                    // Whatever the window shows counts as read.
+                    match partner {
+                        Some(ref partner) => state.chat_history.mark_conversation_read(partner),
+                        None => {
+                            for &id in &channels {
+                                state.chat_history.mark_read(id);
+                            }
+                        }
                     }
-                }
 
-                ui.new_line();
-                let child_height = if state.chat_window_state.user_editing {
-                    -30.0
-                } else {
-                    -5.0
-                };
-                ui.child_frame(im_str!(""), ImVec2::new(-5.0, child_height))
-                    .always_resizable(false)
-                    .input_allow(true) // interacting with internal scrollbar.
-                    .scrollbar_horizontal(false)
-                    .always_show_horizontal_scroll_bar(false)
-                    .show_scrollbar(true)
-                    .build(|| {
-                        print_chat_messages(&ui, state.chat_button_pressed, &state.chat_history);
-                    });
-                if state.chat_window_state.user_editing {
-                    let chat_entered_by_user = ui.input_text(im_str!(""), &mut state.ui_buffers.chat_input_buffer)
-                        .enter_returns_true(true)
-                        .build();
-                    // This next function call makes the input widget within the chat bar have keyboard focus.
-                    // Without this call, the user has to click on the input widget.
-                    unsafe { imgui_sys::igSetKeyboardFocusHere(-1); }
-
-                    if chat_entered_by_user {
-                        if !state.ui_buffers.chat_input_buffer.trim().is_empty() {
-                            let prefix = b"You: ";
-                            let mut msg = state.ui_buffers.chat_input_buffer.as_bytes().to_owned();
-                            for (pos, byte) in prefix.iter().enumerate() {
-                                msg.insert(pos, *byte);
+                    ui.new_line();
+                    let child_height = if has_input && state.chat_window_state.user_editing {
+                        -30.0
+                    } else {
+                        -5.0
+                    };
+                    // Using a window brings back its faded messages.
+                    let mut active = has_input && state.chat_window_state.user_editing;
+                    active = active || unsafe { imgui_sys::igIsRootWindowOrAnyChildFocused() };
+                    active = active || unsafe { imgui_sys::igIsWindowHovered() };
+                    let fade = if state.chat_window_state.fade {
+                        Some(Fade {
+                            now: chat_history::now(),
+                            last_active: window.last_active,
+                            delay: state.chat_window_state.fade_delay,
+                        })
+                    } else {
+                        None
+                    };
+                    let auto_scroll = state.chat_window_state.auto_scroll;
+                    ui.child_frame(im_str!(""), ImVec2::new(-5.0, child_height))
+                        .always_resizable(false)
+                        .input_allow(true) // interacting with internal scrollbar.
+                        .scrollbar_horizontal(false)
+                        .always_show_horizontal_scroll_bar(false)
+                        .show_scrollbar(true)
+                        .build(|| {
+                            // Where the scrollbar was left last frame, before any new messages.
+                            let scroll_y = unsafe { imgui_sys::igGetScrollY() };
+                            let scroll_max_y = unsafe { imgui_sys::igGetScrollMaxY() };
+                            let at_bottom = scroll_y >= scroll_max_y - 1.0;
+                            active = active || unsafe { imgui_sys::igIsWindowHovered() };
+
+                            let (history, fonts) = (&state.chat_history, &state.fonts);
+                            clicked = match partner {
+                                Some(ref partner) => {
+                                    print_conversation(&ui, partner, history, fonts, fade)
+                                }
+                                None => print_chat_messages(&ui, &channels, history, fonts, fade),
+                            };
+                            let end_y = unsafe { imgui_sys::igGetCursorPosY() };
+
+                            let mut scroll_to_end = auto_scroll && at_bottom;
+                            if !at_bottom {
+                                // Keep the button at the bottom of the view, above the messages.
+                                let height = unsafe { imgui_sys::igGetWindowHeight() };
+                                unsafe { imgui_sys::igSetCursorPosY(scroll_y + height - 25.0) };
+                                if ui.small_button(im_str!("Jump to latest")) {
+                                    scroll_to_end = true;
+                                }
+                            }
+                            if scroll_to_end {
+                                // Too far is fine, imgui stops at the end.
+                                unsafe { imgui_sys::igSetScrollY(end_y) };
                             }
-                            state.chat_history.send_message_u8(state.chat_button_pressed, &msg);
-                            state.ui_buffers.chat_input_buffer.clear();
+                        });
+                    // Remember where the user put the window, if they can move or resize it.
+                    let (mut pos, mut size) = (ImVec2::new(0.0, 0.0), ImVec2::new(0.0, 0.0));
+                    unsafe {
+                        imgui_sys::igGetWindowPos(&mut pos);
+                        imgui_sys::igGetWindowSize(&mut size);
+                    }
+                    let movable = state.chat_window_state.movable;
+                    let resizable = state.chat_window_state.resizable;
+                    if let Some(window) = state.chat_layout.window_mut(window.id) {
+                        if active {
+                            window.last_active = chat_history::now();
+                        }
+                        if movable {
+                            window.pos = Some((pos.x, pos.y));
+                        }
+                        if resizable {
+                            window.size = Some((size.x, size.y));
                         }
                     }
-                }
-                //let mouse_pos = ui.imgui().mouse_pos();
-                //ui.text(im_str!("Mouse Position: ({:.1},{:.1})", mouse_pos.0, mouse_pos.1));
-            });
+                    if has_input && state.chat_window_state.user_editing {
+                        show_chat_input(ui, state);
+                    }
+                    //let mouse_pos = ui.imgui().mouse_pos();
+                    //ui.text(im_str!("Mouse Position: ({:.1},{:.1})", mouse_pos.0, mouse_pos.1));
+                });
+        });
     });
+    if let Some(link) = clicked {
+        follow_link(state, link);
+    }
+}
+
+fn show_chat_input<'a>(ui: &Ui<'a>, state: &mut State) {
+    let (chat_entered_by_user, completion) = {
+        let mut callback_data = ChatInputCallbackData {
+            input_history: &mut state.ui_buffers.chat_input_history,
+            commands: &state.chat_commands,
+            chat_history: &state.chat_history,
+            completion: None,
+        };
+        let entered = chat_input_text(&mut state.ui_buffers.chat_input_buffer, &mut callback_data);
+        (entered, callback_data.completion)
+    };
+    let mut input_pos = ImVec2::new(0.0, 0.0);
+    unsafe { imgui_sys::igGetItemRectMin(&mut input_pos) };
+    // This next function call makes the input widget within the chat bar have keyboard focus.
+    // Without this call, the user has to click on the input widget.
+    unsafe { imgui_sys::igSetKeyboardFocusHere(-1); }
+
+    if let Some(completion) = completion {
+        // Tab couldn't pick between several candidates, so list them until the input changes.
+        state.ui_buffers.completion_candidates = if completion.candidates.len() > 1 {
+            let input = state.ui_buffers.chat_input_buffer.to_str().to_owned();
+            Some((input, completion.candidates))
+        } else {
+            None
+        };
+    }
+    let input_changed = match state.ui_buffers.completion_candidates {
+        Some((ref input, _)) => {
+            chat_entered_by_user || input.as_str() != state.ui_buffers.chat_input_buffer.to_str()
+        }
+        None => false,
+    };
+    if input_changed {
+        state.ui_buffers.completion_candidates = None;
+    }
+    if let Some((_, ref candidates)) = state.ui_buffers.completion_candidates {
+        // Just above the input box.
+        let height = ui.imgui().style().window_padding.y * 2.0 +
+                     unsafe { imgui_sys::igGetTextLineHeightWithSpacing() };
+        let pos = ImVec2::new(input_pos.x, input_pos.y - height);
+        unsafe {
+            imgui_sys::igSetNextWindowPos(pos, ImGuiSetCond_Always);
+            imgui_sys::igBeginTooltip();
+        }
+        ui.text(&im_string(candidates.join(", ")));
+        unsafe { imgui_sys::igEndTooltip() };
+    }
+
+    if chat_entered_by_user {
+        if !state.ui_buffers.chat_input_buffer.trim().is_empty() {
+            {
+                let text = &state.ui_buffers.chat_input_buffer;
+                state.ui_buffers.chat_input_history.push(text);
+                let is_command = state.chat_commands.execute(&mut state.chat_history,
+                                                             &mut state.chat_button_pressed,
+                                                             &state.player.name,
+                                                             text);
+                if !is_command {
+                    let player_name = &state.player.name;
+                    match state.chat_layout.input_conversation() {
+                        Some(partner) => {
+                            let channel_id = state.chat_history
+                                .find_channel("Whisper")
+                                .unwrap_or(DEFAULT_CHANNEL);
+                            state.chat_history.send_whisper(channel_id, player_name, partner, text);
+                        }
+                        None => {
+                            let channel_id = state.chat_button_pressed;
+                            state.chat_history.send_player_message(channel_id, player_name, text);
+                        }
+                    }
+                }
+            }
+            state.ui_buffers.chat_input_buffer.clear();
+        }
+    }
 }
 
 fn set_chat_window_pos<'a>(state: &mut State) {